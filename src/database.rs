@@ -1,6 +1,7 @@
-use std::{collections::BTreeMap, path::Path, sync::LazyLock};
+use std::{collections::BTreeMap, ffi::CString, io, mem::MaybeUninit, path::Path, sync::LazyLock};
 
-use crate::errors::Result;
+use crate::errors::{Error, Result};
+use crate::queries;
 use anyhow::Context;
 use rusqlite::params;
 
@@ -8,11 +9,25 @@ static MIGRATIONS: LazyLock<BTreeMap<u32, &'static str>> = LazyLock::new(|| {
     let mut m = BTreeMap::new();
     m.insert(1, include_str!("migrations/001_initial_tables.sql"));
     m.insert(2, include_str!("migrations/002_block_compression.sql"));
+    m.insert(3, include_str!("migrations/003_symlinks.sql"));
+    m.insert(4, include_str!("migrations/004_xattr.sql"));
+    m.insert(5, include_str!("migrations/005_dir_entry_ino_idx.sql"));
+    m.insert(6, include_str!("migrations/006_unique_dir_entry.sql"));
+    m.insert(7, include_str!("migrations/007_directory_nlink.sql"));
+    m.insert(8, include_str!("migrations/008_stored_blocks.sql"));
+    m.insert(9, include_str!("migrations/009_shared_blocks.sql"));
+    m.insert(10, include_str!("migrations/010_block_hash.sql"));
+    m.insert(11, include_str!("migrations/011_snapshots.sql"));
+    m.insert(12, include_str!("migrations/012_file_versions.sql"));
+    m.insert(13, include_str!("migrations/013_deleted_file_versions.sql"));
+    m.insert(14, include_str!("migrations/014_shared_snapshot_rows.sql"));
     m
 });
 
 pub struct DatabaseOps {
     pub(crate) db: rusqlite::Connection,
+    /// The connection shows a snapshot and rejects any change.
+    read_only: bool,
 }
 
 impl DatabaseOps {
@@ -20,14 +35,14 @@ impl DatabaseOps {
         let mut db = rusqlite::Connection::open(path).context("open")?;
         set_cipher_key(&db, key)?;
         migrate_database(&mut db)?;
-        Ok(DatabaseOps { db })
+        Ok(DatabaseOps { db, read_only: false })
     }
 
     #[cfg(test)]
     pub fn open_in_memory() -> anyhow::Result<Self> {
         let mut db = rusqlite::Connection::open_in_memory().context("open")?;
         migrate_database(&mut db)?;
-        Ok(DatabaseOps { db })
+        Ok(DatabaseOps { db, read_only: false })
     }
 
     pub fn with_read_tx<T, F>(&mut self, scope: F) -> Result<T>
@@ -42,19 +57,100 @@ impl DatabaseOps {
     where
         F: FnOnce(&mut rusqlite::Transaction) -> Result<T>,
     {
-        let mut tx = self
-            .db
-            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        // A read-only connection can't take the write lock, the changes fail when they are attempted instead.
+        let behavior = if self.read_only {
+            rusqlite::TransactionBehavior::Deferred
+        } else {
+            rusqlite::TransactionBehavior::Immediate
+        };
+        let mut tx = self.db.transaction_with_behavior(behavior)?;
         let val = scope(&mut tx)?;
         tx.commit()?;
         Ok(val)
     }
+
+    /// Show the content of the snapshot instead of the filesystem, the connection becomes read-only.
+    pub fn use_snapshot(&mut self, name: &str) -> Result<()> {
+        let snapshot = self.with_read_tx(|tx| queries::snapshot::lookup(tx, name))?;
+        let views = include_str!("snapshot_views.sql").replace("{snapshot_id}", &snapshot.id.to_string());
+        self.db.execute_batch(&views)?;
+        self.db.pragma_update(None, "query_only", true)?;
+        self.db.flush_prepared_statement_cache();
+        self.read_only = true;
+        Ok(())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Space used by the database file and space available to it on the host filesystem.
+    pub fn usage(&self) -> Result<DatabaseUsage> {
+        let page_size: u64 = self.db.pragma_query_value(None, "page_size", |row| row.get(0))?;
+        let page_count: u64 = self.db.pragma_query_value(None, "page_count", |row| row.get(0))?;
+        let freelist_count: u64 = self.db.pragma_query_value(None, "freelist_count", |row| row.get(0))?;
+
+        // In-memory databases do not have a path.
+        let (host_free, host_avail) = match self.db.path() {
+            Some(path) if !path.is_empty() => {
+                host_free_space(Path::new(path)).map_err(|e| Error::Other(e.to_string()))?
+            }
+            _ => (0, 0),
+        };
+
+        Ok(DatabaseUsage {
+            page_size,
+            page_count,
+            freelist_count,
+            host_free,
+            host_avail,
+        })
+    }
+
+    /// Make the committed transactions durable. The WAL is only synced during checkpoints with
+    /// `synchronous = NORMAL`, so a full checkpoint is forced.
+    pub fn sync(&mut self) -> Result<()> {
+        let busy: i64 = self
+            .db
+            .query_row("PRAGMA wal_checkpoint(FULL)", params![], |row| row.get(0))?;
+        if busy != 0 {
+            return Err(Error::Other("wal checkpoint could not complete".to_string()));
+        }
+        Ok(())
+    }
+
     pub fn vacuum(&mut self) -> anyhow::Result<()> {
         self.db.execute("VACUUM;", params![])?;
         Ok(())
     }
 }
 
+pub struct DatabaseUsage {
+    pub page_size: u64,
+    /// Number of pages in the database file.
+    pub page_count: u64,
+    /// Number of unused pages in the database file.
+    pub freelist_count: u64,
+    /// Free bytes on the filesystem holding the database file.
+    pub host_free: u64,
+    /// Free bytes available to unprivileged users on the filesystem holding the database file.
+    pub host_avail: u64,
+}
+
+fn host_free_space(path: &Path) -> io::Result<(u64, u64)> {
+    let path = CString::new(path.as_os_str().as_encoded_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: path is a valid C string and stat is only read if statvfs succeeds.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    let frsize = stat.f_frsize as u64;
+    Ok((stat.f_bfree as u64 * frsize, stat.f_bavail as u64 * frsize))
+}
+
 fn set_cipher_key(db: &rusqlite::Connection, key: String) -> anyhow::Result<()> {
     db.pragma_update(None, "key", key).context("pragma")?;
     match db
@@ -70,16 +166,16 @@ fn set_cipher_key(db: &rusqlite::Connection, key: String) -> anyhow::Result<()>
 }
 
 pub(crate) fn migrate_database(db: &mut rusqlite::Connection) -> anyhow::Result<()> {
-    migrate_database_inner(db).context("Migration error: rolled back all changes")
+    migrate_database_inner(db, u32::MAX).context("Migration error: rolled back all changes")
 }
 
-fn migrate_database_inner(db: &mut rusqlite::Connection) -> anyhow::Result<()> {
+fn migrate_database_inner(db: &mut rusqlite::Connection, target_version: u32) -> anyhow::Result<()> {
     db.execute_batch(include_str!("pragmas.sql"))?;
 
     let tx = db.transaction()?;
     let current_version: u32 = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
     let mut last_version = current_version;
-    for (&version, &migration) in &*MIGRATIONS {
+    for (&version, &migration) in MIGRATIONS.range(..=target_version) {
         if version > current_version {
             log::info!(
                 "Running migration #{} because current_version is #{}",
@@ -105,3 +201,262 @@ fn migrate_database_inner(db: &mut rusqlite::Connection) -> anyhow::Result<()> {
     tx.commit()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::migrate_database_inner;
+    use crate::errors::Error;
+    use rusqlite::params;
+
+    #[test]
+    fn test_unique_dir_entry_migration() -> anyhow::Result<()> {
+        let mut db = rusqlite::Connection::open_in_memory()?;
+        migrate_database_inner(&mut db, 5)?;
+
+        db.execute_batch(
+            "INSERT INTO inode VALUES (1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 493, 2, 0, 0, 0, 512, 0);
+            INSERT INTO inode VALUES (2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 420, 1, 0, 0, 0, 512, 0);
+            INSERT INTO inode VALUES (3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 420, 1, 0, 0, 0, 512, 0);",
+        )?;
+        for ino in [2, 3] {
+            db.execute(
+                "INSERT INTO dir_entry (parent_ino, name, ino) VALUES (1, ?, ?)",
+                params![b"foo".as_slice(), ino],
+            )?;
+        }
+
+        migrate_database_inner(&mut db, u32::MAX)?;
+
+        let mut stmt = db.prepare("SELECT name, ino FROM dir_entry ORDER BY ino")?;
+        let entries = stmt
+            .query_map(params![], |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, u64>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(entries, vec![(b"foo".to_vec(), 2), (b"foo.dup-2".to_vec(), 3)]);
+
+        let res = db.execute(
+            "INSERT INTO dir_entry (parent_ino, name, ino) VALUES (1, ?, 3)",
+            params![b"foo".as_slice()],
+        );
+        assert_eq!(res.map_err(Error::from), Err(Error::AlreadyExists));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unique_dir_entry_migration_conflicts() -> anyhow::Result<()> {
+        let mut db = rusqlite::Connection::open_in_memory()?;
+        migrate_database_inner(&mut db, 5)?;
+
+        db.execute_batch("INSERT INTO inode VALUES (1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 493, 6, 0, 0, 0, 512, 0);")?;
+        // The new name of the duplicate is taken, and a name of NAME_MAX bytes is duplicated.
+        let long = vec![b'x'; 255];
+        let entries: [(&[u8], u64); 5] = [(b"foo", 2), (b"foo", 3), (b"foo.dup-2", 4), (&long, 5), (&long, 6)];
+        for (name, ino) in entries {
+            db.execute(
+                "INSERT INTO inode VALUES (?, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 420, 1, 0, 0, 0, 512, 0)",
+                params![ino],
+            )?;
+            db.execute(
+                "INSERT INTO dir_entry (parent_ino, name, ino) VALUES (1, ?, ?)",
+                params![name, ino],
+            )?;
+        }
+
+        migrate_database_inner(&mut db, u32::MAX)?;
+
+        let mut stmt = db.prepare("SELECT name, ino FROM dir_entry ORDER BY ino")?;
+        let entries = stmt
+            .query_map(params![], |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, u64>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut truncated = vec![b'x'; 249];
+        truncated.extend_from_slice(b".dup-5");
+        assert_eq!(
+            entries,
+            vec![
+                (b"foo".to_vec(), 2),
+                (b"foo.dup-2-1".to_vec(), 3),
+                (b"foo.dup-2".to_vec(), 4),
+                (long, 5),
+                (truncated, 6),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_nlink_migration() -> anyhow::Result<()> {
+        let mut db = rusqlite::Connection::open_in_memory()?;
+        migrate_database_inner(&mut db, 6)?;
+
+        // A root directory with two subdirectories and a file, all with the wrong link count.
+        db.execute_batch(
+            "INSERT INTO inode VALUES (1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 493, 2, 0, 0, 0, 512, 0);
+            INSERT INTO inode VALUES (2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 493, 2, 0, 0, 0, 512, 0);
+            INSERT INTO inode VALUES (3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 493, 7, 0, 0, 0, 512, 0);
+            INSERT INTO inode VALUES (4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 420, 1, 0, 0, 0, 512, 0);
+            INSERT INTO dir_entry (parent_ino, name, ino) VALUES (1, 'a', 2);
+            INSERT INTO dir_entry (parent_ino, name, ino) VALUES (1, 'b', 3);
+            INSERT INTO dir_entry (parent_ino, name, ino) VALUES (1, 'c', 4);",
+        )?;
+
+        migrate_database_inner(&mut db, u32::MAX)?;
+
+        let mut stmt = db.prepare("SELECT nlink FROM inode ORDER BY ino")?;
+        let nlinks = stmt
+            .query_map(params![], |row| row.get::<_, u32>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(nlinks, vec![4, 2, 2, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stored_blocks_migration() -> anyhow::Result<()> {
+        let mut db = rusqlite::Connection::open_in_memory()?;
+        migrate_database_inner(&mut db, 7)?;
+
+        // A file with two blocks counted in uncompressed 512 byte blocks and an empty file.
+        db.execute_batch(
+            "INSERT INTO inode VALUES (2, 262144, 512, 0, 0, 0, 0, 0, 0, 0, 0, 1, 420, 1, 0, 0, 0, 512, 0);
+            INSERT INTO inode VALUES (3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 420, 1, 0, 0, 0, 512, 0);",
+        )?;
+        for (bno, len) in [(0, 1000), (1, 30)] {
+            db.execute(
+                "INSERT INTO block (ino, bno, data, compression) VALUES (2, ?, ?, 2)",
+                params![bno, vec![0u8; len]],
+            )?;
+        }
+
+        migrate_database_inner(&mut db, u32::MAX)?;
+
+        let mut stmt = db.prepare("SELECT blocks, blksize FROM inode ORDER BY ino")?;
+        let attrs = stmt
+            .query_map(params![], |row| Ok((row.get::<_, u64>(0)?, row.get::<_, u32>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(attrs, vec![(3, 131072), (0, 131072)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_blocks_migration() -> anyhow::Result<()> {
+        let mut db = rusqlite::Connection::open_in_memory()?;
+        migrate_database_inner(&mut db, 8)?;
+
+        db.execute_batch(
+            "INSERT INTO inode VALUES (2, 262144, 3, 0, 0, 0, 0, 0, 0, 0, 0, 1, 420, 1, 0, 0, 0, 131072, 0);",
+        )?;
+        for (bno, len) in [(0, 1000), (1, 30)] {
+            db.execute(
+                "INSERT INTO block (ino, bno, data, compression) VALUES (2, ?, ?, 2)",
+                params![bno, vec![bno as u8; len]],
+            )?;
+        }
+
+        migrate_database_inner(&mut db, u32::MAX)?;
+
+        let mut stmt = db.prepare(
+            "SELECT b.bno, length(d.data), d.compression FROM block b JOIN block_data d ON d.id = b.data_id
+            ORDER BY b.bno",
+        )?;
+        let blocks = stmt
+            .query_map(params![], |row| {
+                Ok((row.get::<_, u64>(0)?, row.get::<_, u64>(1)?, row.get::<_, u8>(2)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(blocks, vec![(0, 1000, 2), (1, 30, 2)]);
+
+        // The data of the blocks is deleted with the inode.
+        db.execute("PRAGMA foreign_keys = ON", params![])?;
+        db.execute("DELETE FROM inode WHERE ino = 2", params![])?;
+        let count: u64 = db.query_row("SELECT count(*) FROM block_data", params![], |row| row.get(0))?;
+        assert_eq!(count, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deleted_file_versions_migration() -> anyhow::Result<()> {
+        let mut db = rusqlite::Connection::open_in_memory()?;
+        migrate_database_inner(&mut db, 12)?;
+
+        // A file with a version sharing the data of its only block.
+        db.execute_batch(
+            "INSERT INTO inode VALUES (2, 100, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 416, 1, 1000, 100, 0, 131072, 0);
+            INSERT INTO block_data (id, data, compression) VALUES (1, x'00', 0);
+            INSERT INTO block (ino, bno, data_id) VALUES (2, 0, 1);
+            INSERT INTO file_version (id, ino, created_secs, created_nanos, size, mtime_secs, mtime_nanos)
+                VALUES (1, 2, 0, 0, 100, 0, 0);
+            INSERT INTO file_version_block (version_id, bno, data_id) VALUES (1, 0, 1);",
+        )?;
+
+        migrate_database_inner(&mut db, u32::MAX)?;
+
+        let version = db.query_row(
+            "SELECT v.ino, v.perm, v.uid, v.gid, b.data_id FROM file_version v
+            JOIN file_version_block b ON b.version_id = v.id",
+            params![],
+            |row| {
+                Ok((
+                    row.get::<_, u64>(0)?,
+                    row.get::<_, u16>(1)?,
+                    row.get::<_, u32>(2)?,
+                    row.get::<_, u32>(3)?,
+                    row.get::<_, u64>(4)?,
+                ))
+            },
+        )?;
+        assert_eq!(version, (2, 416, 1000, 100, 1));
+
+        // The version and its data outlive the inode.
+        db.execute("PRAGMA foreign_keys = ON", params![])?;
+        db.execute("DELETE FROM inode WHERE ino = 2", params![])?;
+        let ino: Option<u64> = db.query_row("SELECT ino FROM file_version", params![], |row| row.get(0))?;
+        assert_eq!(ino, None);
+        let count: u64 = db.query_row("SELECT count(*) FROM block_data", params![], |row| row.get(0))?;
+        assert_eq!(count, 1);
+
+        // The data is deleted with the last version.
+        db.execute("DELETE FROM file_version", params![])?;
+        let count: u64 = db.query_row("SELECT count(*) FROM block_data", params![], |row| row.get(0))?;
+        assert_eq!(count, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_snapshot_rows_migration() -> anyhow::Result<()> {
+        let mut db = rusqlite::Connection::open_in_memory()?;
+        migrate_database_inner(&mut db, 13)?;
+
+        // Two snapshots of the same file.
+        db.execute_batch(
+            "INSERT INTO block_data (id, data, compression) VALUES (1, x'00', 0);
+            INSERT INTO snapshot (id, name, created_secs, created_nanos) VALUES (1, 's1', 0, 0), (2, 's2', 0, 0);
+            INSERT INTO snapshot_dir_entry (snapshot_id, parent_ino, name, ino) VALUES (1, 1, 'a', 2), (2, 1, 'a', 2);
+            INSERT INTO snapshot_block (snapshot_id, ino, bno, data_id) VALUES (1, 2, 0, 1), (2, 2, 0, 1);",
+        )?;
+
+        migrate_database_inner(&mut db, u32::MAX)?;
+
+        let mut stmt = db.prepare("SELECT first_snapshot, last_snapshot FROM snapshot_block ORDER BY rowid")?;
+        let ranges = stmt
+            .query_map(params![], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        assert_eq!(ranges, vec![(1, 1), (2, 2)]);
+        let count: u64 = db.query_row(
+            "SELECT count(*) FROM snapshot_dir_entry WHERE first_snapshot = last_snapshot",
+            params![],
+            |row| row.get(0),
+        )?;
+        assert_eq!(count, 2);
+
+        // The data is deleted with the last snapshot block.
+        db.execute("DELETE FROM snapshot_block", params![])?;
+        let count: u64 = db.query_row("SELECT count(*) FROM block_data", params![], |row| row.get(0))?;
+        assert_eq!(count, 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::ffi::OsStr;
+
+use fuser::FileAttr;
+
+use crate::driver::acl::{Acl, ACL_ACCESS, ACL_DEFAULT};
+use crate::driver::RequestInfo;
+use crate::errors::{Error, Result};
+use crate::queries;
+
+/// Check if the requester is allowed to access the inode with the given access(2) mask (R_OK, W_OK, X_OK). When the
+/// inode has an access ACL, it takes precedence over the permission bits.
+pub fn check_access(attr: &FileAttr, acl: Option<&Acl>, req: RequestInfo, mask: i32) -> Result<()> {
+    let want = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+    if want == 0 {
+        return Ok(());
+    }
+
+    let allowed = if req.uid == 0 {
+        // root bypasses permission checks, except it cannot execute a file that no one can execute.
+        want & libc::X_OK as u16 == 0 || attr.kind == fuser::FileType::Directory || attr.perm & 0o111 != 0
+    } else if let Some(acl) = acl {
+        acl.permits(attr.uid, attr.gid, req, want)
+    } else {
+        let perm = if req.uid == attr.uid {
+            attr.perm >> 6
+        } else if req.gid == attr.gid {
+            attr.perm >> 3
+        } else {
+            attr.perm
+        };
+        perm & want == want
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(Error::PermissionDenied)
+    }
+}
+
+/// Load an ACL stored in the given extended attribute of an inode.
+pub fn load_acl(tx: &mut rusqlite::Transaction, ino: u64, name: &str) -> Result<Option<Acl>> {
+    match queries::xattr::get(tx, ino, OsStr::new(name)) {
+        Ok(data) => Acl::parse(&data).map(Some),
+        Err(Error::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Check access to an inode, taking its access ACL into account.
+pub fn check_inode_access(tx: &mut rusqlite::Transaction, attr: &FileAttr, req: RequestInfo, mask: i32) -> Result<()> {
+    let acl = load_acl(tx, attr.ino, ACL_ACCESS)?;
+    check_access(attr, acl.as_ref(), req, mask)
+}
+
+/// Check access to an extended attribute. ACLs can be read by anyone and changed by the owner, user attributes
+/// follow the permissions of the inode and the other namespaces are reserved to root.
+pub fn check_xattr_access(
+    tx: &mut rusqlite::Transaction,
+    attr: &FileAttr,
+    req: RequestInfo,
+    name: &OsStr,
+    mask: i32,
+) -> Result<()> {
+    let name = name.as_encoded_bytes();
+    if name == ACL_ACCESS.as_bytes() || name == ACL_DEFAULT.as_bytes() {
+        if mask & libc::W_OK != 0 && req.uid != 0 && req.uid != attr.uid {
+            return Err(Error::NotPermitted);
+        }
+        Ok(())
+    } else if name.starts_with(b"user.") {
+        check_inode_access(tx, attr, req, mask)
+    } else if req.uid == 0 {
+        Ok(())
+    } else {
+        Err(Error::NotPermitted)
+    }
+}
+
+/// Only the owner of an inode (or root) can change its metadata.
+pub fn check_owner(attr: &FileAttr, req: RequestInfo) -> Result<()> {
+    if req.uid == 0 || req.uid == attr.uid {
+        Ok(())
+    } else {
+        Err(Error::NotPermitted)
+    }
+}
+
+/// Permission bits once the set-user-ID bit is dropped, along with the set-group-ID bit if the group can execute the
+/// file. This happens when a file is modified or given away, so that it does not keep running with privileges that
+/// were granted to its previous content or owner. Directories keep their bits.
+pub fn drop_setid(kind: fuser::FileType, perm: u16) -> u16 {
+    if kind == fuser::FileType::Directory {
+        return perm;
+    }
+    let mut perm = perm & !(libc::S_ISUID as u16);
+    if perm & libc::S_IXGRP as u16 != 0 {
+        perm &= !(libc::S_ISGID as u16);
+    }
+    perm
+}
+
+/// In a directory with the sticky bit set, entries can only be removed or renamed by the owner of the entry, the
+/// owner of the directory or root.
+pub fn check_sticky(dir: &FileAttr, attr: &FileAttr, req: RequestInfo) -> Result<()> {
+    if dir.perm & libc::S_ISVTX as u16 == 0 || req.uid == 0 || req.uid == dir.uid || req.uid == attr.uid {
+        Ok(())
+    } else {
+        Err(Error::NotPermitted)
+    }
+}
@@ -0,0 +1,282 @@
+//! POSIX ACLs in the binary format the Linux kernel uses for the `system.posix_acl_access` and
+//! `system.posix_acl_default` extended attributes.
+//!
+//! https://man7.org/linux/man-pages/man5/acl.5.html
+
+use crate::driver::RequestInfo;
+use crate::errors::{Error, Result};
+
+pub const ACL_ACCESS: &str = "system.posix_acl_access";
+pub const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+const ACL_VERSION: u32 = 2;
+const HEADER_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 8;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: u16,
+    pub perm: u16,
+    pub id: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    pub fn parse(data: &[u8]) -> Result<Acl> {
+        if data.len() < HEADER_SIZE || !(data.len() - HEADER_SIZE).is_multiple_of(ENTRY_SIZE) {
+            return Err(Error::InvalidArgument);
+        }
+        let version = u32::from_le_bytes(data[..4].try_into().unwrap());
+        if version != ACL_VERSION {
+            return Err(Error::InvalidArgument);
+        }
+
+        let entries = data[HEADER_SIZE..]
+            .chunks_exact(ENTRY_SIZE)
+            .map(|e| AclEntry {
+                tag: u16::from_le_bytes([e[0], e[1]]),
+                perm: u16::from_le_bytes([e[2], e[3]]),
+                id: u32::from_le_bytes([e[4], e[5], e[6], e[7]]),
+            })
+            .collect();
+        let acl = Acl { entries };
+        acl.validate()?;
+        Ok(acl)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + self.entries.len() * ENTRY_SIZE);
+        data.extend_from_slice(&ACL_VERSION.to_le_bytes());
+        for entry in &self.entries {
+            data.extend_from_slice(&entry.tag.to_le_bytes());
+            data.extend_from_slice(&entry.perm.to_le_bytes());
+            data.extend_from_slice(&entry.id.to_le_bytes());
+        }
+        data
+    }
+
+    fn validate(&self) -> Result<()> {
+        let count = |tag| self.entries.iter().filter(|e| e.tag == tag).count();
+        let valid_tags = self.entries.iter().all(|e| {
+            matches!(
+                e.tag,
+                ACL_USER_OBJ | ACL_USER | ACL_GROUP_OBJ | ACL_GROUP | ACL_MASK | ACL_OTHER
+            )
+        });
+        let valid_perms = self.entries.iter().all(|e| e.perm & !0o7 == 0);
+        let named = count(ACL_USER) + count(ACL_GROUP);
+        // A mask is required as soon as there are named user or group entries.
+        let valid_mask = if named > 0 {
+            count(ACL_MASK) == 1
+        } else {
+            count(ACL_MASK) <= 1
+        };
+        if valid_tags
+            && valid_perms
+            && valid_mask
+            && count(ACL_USER_OBJ) == 1
+            && count(ACL_GROUP_OBJ) == 1
+            && count(ACL_OTHER) == 1
+        {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgument)
+        }
+    }
+
+    fn entry_mut(&mut self, tag: u16) -> Option<&mut AclEntry> {
+        self.entries.iter_mut().find(|e| e.tag == tag)
+    }
+
+    fn perm(&self, tag: u16) -> Option<u16> {
+        self.entries.iter().find(|e| e.tag == tag).map(|e| e.perm)
+    }
+
+    /// Returns true if the ACL carries no more information than the permission bits.
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    /// Permission bits equivalent to this ACL. When a mask is present, it is reported as the group bits.
+    pub fn mode(&self) -> u16 {
+        let user = self.perm(ACL_USER_OBJ).unwrap_or(0);
+        let group = self.perm(ACL_MASK).or(self.perm(ACL_GROUP_OBJ)).unwrap_or(0);
+        let other = self.perm(ACL_OTHER).unwrap_or(0);
+        (user << 6) | (group << 3) | other
+    }
+
+    /// Update the ACL after a chmod, the permission bits replace the owner, mask (or owning group) and other entries.
+    pub fn chmod(&mut self, mode: u16) {
+        if let Some(e) = self.entry_mut(ACL_USER_OBJ) {
+            e.perm = (mode >> 6) & 0o7;
+        }
+        let group_tag = if self.perm(ACL_MASK).is_some() {
+            ACL_MASK
+        } else {
+            ACL_GROUP_OBJ
+        };
+        if let Some(e) = self.entry_mut(group_tag) {
+            e.perm = (mode >> 3) & 0o7;
+        }
+        if let Some(e) = self.entry_mut(ACL_OTHER) {
+            e.perm = mode & 0o7;
+        }
+    }
+
+    /// Turn a default ACL inherited from the parent directory into the access ACL of a new inode created with the
+    /// given mode. Returns the permission bits of the new inode.
+    pub fn inherit(&mut self, mode: u16) -> u16 {
+        let has_mask = self.perm(ACL_MASK).is_some();
+        for e in &mut self.entries {
+            match e.tag {
+                ACL_USER_OBJ => e.perm &= (mode >> 6) & 0o7,
+                ACL_MASK => e.perm &= (mode >> 3) & 0o7,
+                ACL_GROUP_OBJ if !has_mask => e.perm &= (mode >> 3) & 0o7,
+                ACL_OTHER => e.perm &= mode & 0o7,
+                _ => {}
+            }
+        }
+        (mode & !0o777) | self.mode()
+    }
+
+    /// Check if the requester is granted all the `want` permission bits (rwx) by this ACL.
+    pub fn permits(&self, uid: u32, gid: u32, req: RequestInfo, want: u16) -> bool {
+        let mask = self.perm(ACL_MASK).unwrap_or(0o7);
+        let granted = |perm: u16| perm & want == want;
+
+        if req.uid == uid {
+            return self.perm(ACL_USER_OBJ).is_some_and(granted);
+        }
+        if let Some(e) = self.entries.iter().find(|e| e.tag == ACL_USER && e.id == req.uid) {
+            return granted(e.perm & mask);
+        }
+
+        // Only the primary group of the requester is known, FUSE does not give us the supplementary groups.
+        let mut group_matched = false;
+        for e in &self.entries {
+            let matches = match e.tag {
+                ACL_GROUP_OBJ => req.gid == gid,
+                ACL_GROUP => e.id != ACL_UNDEFINED_ID && req.gid == e.id,
+                _ => false,
+            };
+            if matches {
+                if granted(e.perm & mask) {
+                    return true;
+                }
+                group_matched = true;
+            }
+        }
+        if group_matched {
+            return false;
+        }
+
+        self.perm(ACL_OTHER).is_some_and(granted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Acl, AclEntry, ACL_GROUP, ACL_GROUP_OBJ, ACL_MASK, ACL_OTHER, ACL_USER, ACL_USER_OBJ};
+    use crate::driver::RequestInfo;
+    use crate::errors::Error;
+
+    fn entry(tag: u16, perm: u16, id: u32) -> AclEntry {
+        AclEntry { tag, perm, id }
+    }
+
+    fn req(uid: u32, gid: u32) -> RequestInfo {
+        RequestInfo { uid, gid, pid: 0 }
+    }
+
+    #[test]
+    fn test_acl_parse_roundtrip() {
+        let acl = Acl {
+            entries: vec![
+                entry(ACL_USER_OBJ, 0o6, u32::MAX),
+                entry(ACL_USER, 0o6, 1001),
+                entry(ACL_GROUP_OBJ, 0o4, u32::MAX),
+                entry(ACL_MASK, 0o6, u32::MAX),
+                entry(ACL_OTHER, 0o0, u32::MAX),
+            ],
+        };
+        let data = acl.to_bytes();
+        assert_eq!(data.len(), 4 + 5 * 8);
+        assert_eq!(Acl::parse(&data), Ok(acl.clone()));
+        assert_eq!(acl.mode(), 0o660);
+
+        // Named entries require a mask.
+        let acl = Acl {
+            entries: vec![
+                entry(ACL_USER_OBJ, 0o6, u32::MAX),
+                entry(ACL_GROUP, 0o6, 1001),
+                entry(ACL_GROUP_OBJ, 0o4, u32::MAX),
+                entry(ACL_OTHER, 0o0, u32::MAX),
+            ],
+        };
+        assert_eq!(Acl::parse(&acl.to_bytes()), Err(Error::InvalidArgument));
+        assert_eq!(Acl::parse(&[1, 2, 3]), Err(Error::InvalidArgument));
+    }
+
+    #[test]
+    fn test_acl_permits() {
+        let acl = Acl {
+            entries: vec![
+                entry(ACL_USER_OBJ, 0o6, u32::MAX),
+                entry(ACL_USER, 0o7, 1001),
+                entry(ACL_GROUP_OBJ, 0o4, u32::MAX),
+                entry(ACL_GROUP, 0o2, 2002),
+                entry(ACL_MASK, 0o6, u32::MAX),
+                entry(ACL_OTHER, 0o0, u32::MAX),
+            ],
+        };
+        // Owner
+        assert!(acl.permits(1000, 1000, req(1000, 1000), 0o6));
+        assert!(!acl.permits(1000, 1000, req(1000, 1000), 0o1));
+        // Named user, execute is masked out
+        assert!(acl.permits(1000, 1000, req(1001, 5000), 0o6));
+        assert!(!acl.permits(1000, 1000, req(1001, 5000), 0o1));
+        // Owning group
+        assert!(acl.permits(1000, 1000, req(3000, 1000), 0o4));
+        assert!(!acl.permits(1000, 1000, req(3000, 1000), 0o2));
+        // Named group
+        assert!(acl.permits(1000, 1000, req(3000, 2002), 0o2));
+        assert!(!acl.permits(1000, 1000, req(3000, 2002), 0o4));
+        // Other
+        assert!(!acl.permits(1000, 1000, req(3000, 3000), 0o4));
+    }
+
+    #[test]
+    fn test_acl_inherit_and_chmod() {
+        let mut acl = Acl {
+            entries: vec![
+                entry(ACL_USER_OBJ, 0o7, u32::MAX),
+                entry(ACL_USER, 0o7, 1001),
+                entry(ACL_GROUP_OBJ, 0o5, u32::MAX),
+                entry(ACL_MASK, 0o7, u32::MAX),
+                entry(ACL_OTHER, 0o5, u32::MAX),
+            ],
+        };
+        assert_eq!(acl.inherit(0o644), 0o644);
+        assert_eq!(acl.perm(ACL_MASK), Some(0o4));
+        assert_eq!(acl.perm(ACL_GROUP_OBJ), Some(0o5));
+
+        acl.chmod(0o750);
+        assert_eq!(acl.mode(), 0o750);
+        assert_eq!(acl.perm(ACL_USER_OBJ), Some(0o7));
+        assert_eq!(acl.perm(ACL_MASK), Some(0o5));
+        assert_eq!(acl.perm(ACL_OTHER), Some(0o0));
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::{Duration, SystemTime};
+
+use fuser::FileAttr;
+
+/// With relatime, an access time older than this is updated even if the file did not change since.
+const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// When reading a file or listing a directory updates its access time.
+#[derive(Clone, Copy, Debug, PartialEq, Default, clap::ValueEnum)]
+pub enum AtimePolicy {
+    /// Never update the access time.
+    Noatime,
+    /// Update the access time if it is older than the modification or change time, or more than a day old.
+    #[default]
+    Relatime,
+    /// Update the access time on every access.
+    Strictatime,
+}
+
+impl AtimePolicy {
+    pub fn needs_update(self, attr: &FileAttr, now: SystemTime) -> bool {
+        match self {
+            AtimePolicy::Noatime => false,
+            AtimePolicy::Relatime => {
+                attr.atime <= attr.mtime
+                    || attr.atime <= attr.ctime
+                    || now.duration_since(attr.atime).is_ok_and(|age| age >= RELATIME_INTERVAL)
+            }
+            AtimePolicy::Strictatime => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::AtimePolicy;
+    use crate::driver::attr::FileAttrBuilder;
+    use crate::types::FileType;
+
+    #[test]
+    fn test_atime_policy() {
+        let now = SystemTime::now();
+        let mut attr = FileAttrBuilder::new_node(FileType::RegularFile).build();
+        attr.mtime = now - Duration::from_secs(60);
+        attr.ctime = attr.mtime;
+
+        // Read after the last modification.
+        attr.atime = now - Duration::from_secs(30);
+        assert!(!AtimePolicy::Noatime.needs_update(&attr, now));
+        assert!(!AtimePolicy::Relatime.needs_update(&attr, now));
+        assert!(AtimePolicy::Strictatime.needs_update(&attr, now));
+
+        // Modified since the last read.
+        attr.atime = now - Duration::from_secs(90);
+        assert!(!AtimePolicy::Noatime.needs_update(&attr, now));
+        assert!(AtimePolicy::Relatime.needs_update(&attr, now));
+
+        // Read after the last modification, but more than a day ago.
+        attr.mtime = now - Duration::from_secs(3 * 24 * 60 * 60);
+        attr.ctime = attr.mtime;
+        attr.atime = now - Duration::from_secs(2 * 24 * 60 * 60);
+        assert!(AtimePolicy::Relatime.needs_update(&attr, now));
+    }
+}
@@ -1,10 +1,9 @@
 use std::time::SystemTime;
 
+use crate::queries::block::BLOCK_SIZE;
 use crate::types::FileType;
 use fuser::FileAttr;
 
-const POSIX_BLOCK_SIZE: u32 = 512;
-
 pub struct FileAttrBuilder {
     attr: FileAttr,
 }
@@ -27,7 +26,7 @@ impl FileAttrBuilder {
                 uid: 0,
                 gid: 0,
                 rdev: 0,
-                blksize: POSIX_BLOCK_SIZE,
+                blksize: BLOCK_SIZE as u32,
                 flags: 0,
             },
         }
@@ -50,7 +49,7 @@ impl FileAttrBuilder {
                 uid: 0,
                 gid: 0,
                 rdev: 0,
-                blksize: POSIX_BLOCK_SIZE,
+                blksize: BLOCK_SIZE as u32,
                 flags: 0,
             },
         }
@@ -72,6 +71,11 @@ impl FileAttrBuilder {
         self
     }
 
+    pub fn with_size(mut self, size: u64) -> FileAttrBuilder {
+        self.attr.size = size;
+        self
+    }
+
     pub fn with_rdev(mut self, rdev: u32) -> FileAttrBuilder {
         self.attr.rdev = rdev;
         self
@@ -0,0 +1,251 @@
+use std::cmp;
+
+use crate::errors::{Error, Result};
+use crate::queries;
+use crate::queries::block::{Block, Storage};
+use crate::time::TimeSpec;
+
+const BUFFER_SIZE: usize = 2 * 1024 * 1024;
+
+/// Write-back cache of an inode, shared by all the handles open on it.
+#[derive(Debug)]
+pub struct WriteBuffer {
+    pub ino: u64,
+    /// Stores the write position where buf must be written.
+    write_offset: u64,
+    /// Write data buffer used to optimize writes.
+    buf: Vec<u8>,
+    storage: Storage,
+    /// Time of the last write into the buffer, it becomes the modification time of the inode when flushed.
+    modified: TimeSpec,
+}
+
+impl WriteBuffer {
+    pub fn new(ino: u64, storage: Storage) -> Self {
+        WriteBuffer {
+            ino,
+            write_offset: 0,
+            buf: Vec::with_capacity(BUFFER_SIZE),
+            storage,
+            modified: TimeSpec::now(),
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.capacity() - self.buf.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub fn write_offset(&self) -> u64 {
+        self.write_offset + self.buf.len() as u64
+    }
+
+    pub fn seek_to(&mut self, offset: u64) {
+        assert_eq!(self.buf.len(), 0);
+        self.write_offset = offset;
+    }
+
+    pub fn modified(&self) -> TimeSpec {
+        self.modified
+    }
+
+    pub fn consume_input(&mut self, buf: &[u8]) -> usize {
+        let write = cmp::min(buf.len(), self.remaining());
+        self.buf.extend_from_slice(&buf[..write]);
+        self.modified = TimeSpec::now();
+        write
+    }
+
+    pub fn flush(&mut self, tx: &mut rusqlite::Transaction) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        log::debug!(
+            "Flush called, buf.len()={} buf.capacity()={}",
+            self.buf.len(),
+            self.buf.capacity()
+        );
+
+        let mut attr = queries::inode::lookup(tx, self.ino)?;
+        let mut new_offset = self.write_offset;
+        let mut data = &self.buf[..];
+
+        while !data.is_empty() {
+            let bno = Block::offset_to_bno(new_offset);
+            let written = match queries::block::get_block(tx, self.ino, bno) {
+                // Update the block if the offset overrides an existing block.
+                Ok(mut block) => {
+                    let (written, diff) = block.write_at(new_offset, data);
+                    log::debug!(
+                        "Update block {} at offset={}, written={}, diff={}",
+                        block.bno,
+                        new_offset,
+                        written,
+                        diff
+                    );
+                    queries::block::update(tx, &block, self.storage)?;
+                    written
+                }
+                // Write the data in a new block if the offset is in a hole.
+                Err(Error::NotFound) => {
+                    let written = queries::block::create(tx, self.ino, new_offset, data, self.storage)?;
+                    log::debug!("Create block {} at offset={}, written={}", bno, new_offset, written);
+                    written
+                }
+                Err(e) => return Err(e),
+            };
+            data = &data[written as usize..];
+            new_offset += written;
+        }
+
+        // The file only grows when data is written past its end, it might already cover the range when
+        // writing into a hole.
+        attr.size = cmp::max(attr.size, new_offset);
+        queries::inode::set_attr(tx, self.ino, "size", attr.size)?;
+        queries::inode::update_blocks(tx, self.ino)?;
+        queries::inode::set_time(tx, self.ino, "mtime", self.modified)?;
+        queries::inode::set_time(tx, self.ino, "ctime", self.modified)?;
+
+        self.buf.clear();
+        self.write_offset = new_offset;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::attr::FileAttrBuilder;
+    use crate::driver::WriteBuffer;
+    use crate::queries;
+    use crate::queries::block::{Compression, BLOCK_SIZE};
+    use crate::time::TimeSpec;
+    use test_log::test;
+
+    #[test]
+    fn test_write_buffer_buffer_remaining() {
+        let fh = WriteBuffer {
+            ino: 1,
+            write_offset: 0,
+            buf: Vec::with_capacity(37),
+            storage: Compression::None.into(),
+            modified: TimeSpec::now(),
+        };
+        assert_eq!(fh.remaining(), 37);
+    }
+
+    #[test]
+    fn test_write_buffer_buffer_full() {
+        let mut fh = WriteBuffer {
+            ino: 1,
+            write_offset: 0,
+            buf: vec![0; 37],
+            storage: Compression::None.into(),
+            modified: TimeSpec::now(),
+        };
+        assert!(fh.is_full());
+        fh.buf.reserve(10);
+        assert!(!fh.is_full());
+    }
+
+    #[test]
+    fn test_write_buffer_seek_to() {
+        let mut fh = WriteBuffer {
+            ino: 1,
+            write_offset: 0,
+            buf: Vec::with_capacity(1000),
+            storage: Compression::None.into(),
+            modified: TimeSpec::now(),
+        };
+        fh.seek_to(500);
+        assert_eq!(fh.write_offset(), 500);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_write_buffer_seek_to_buffer_not_flushed() {
+        let mut fh = WriteBuffer {
+            ino: 1,
+            write_offset: 0,
+            buf: vec![0; 37],
+            storage: Compression::None.into(),
+            modified: TimeSpec::now(),
+        };
+        fh.seek_to(0);
+    }
+
+    #[test]
+    fn test_write_buffer_consume() {
+        let mut fh = WriteBuffer {
+            ino: 1,
+            write_offset: 1000,
+            buf: Vec::with_capacity(64),
+            storage: Compression::None.into(),
+            modified: TimeSpec::now(),
+        };
+        assert_eq!(5, fh.consume_input(&[5; 5]));
+        assert_eq!(59, fh.consume_input(&[5; 100]));
+        assert_eq!(1064, fh.write_offset());
+    }
+
+    #[test]
+    fn test_write_buffer_flush() -> anyhow::Result<()> {
+        let mut cx = rusqlite::Connection::open_in_memory()?;
+        crate::database::migrate_database(&mut cx)?;
+        let mut tx = cx.transaction()?;
+
+        let mut attr = FileAttrBuilder::new_node(crate::types::FileType::RegularFile).build();
+        queries::inode::create(&mut tx, &mut attr)?;
+        let mut fh = WriteBuffer::new(attr.ino, Compression::None.into());
+
+        //
+        // Simple consecutive write...
+        //
+        fh.consume_input(&[1u8; (BLOCK_SIZE + 100) as usize]);
+        fh.flush(&mut tx)?;
+
+        let mut total_size = 0;
+        let mut block_num = 0;
+
+        queries::block::iter_blocks_from(&mut tx, attr.ino, 0, |block| {
+            block_num += 1;
+            total_size += block.data.len();
+            Ok(true)
+        })?;
+
+        assert_eq!(total_size, (BLOCK_SIZE + 100) as usize);
+        assert_eq!(block_num, 2);
+
+        //
+        // Seek and overwrite
+        //
+        fh.seek_to(BLOCK_SIZE / 2);
+        fh.consume_input(&[2u8; (BLOCK_SIZE * 2) as usize]);
+        fh.flush(&mut tx)?;
+
+        let mut total_size = 0;
+        let mut block_num = 0;
+
+        queries::block::iter_blocks_from(&mut tx, attr.ino, 0, |block| {
+            block_num += 1;
+            total_size += block.data.len();
+            Ok(true)
+        })?;
+
+        assert_eq!(total_size, (BLOCK_SIZE * 2 + (BLOCK_SIZE / 2)) as usize);
+        assert_eq!(block_num, 3);
+
+        Ok(())
+    }
+}
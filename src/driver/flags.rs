@@ -6,6 +6,7 @@ pub struct OpenFlags {
     pub read: bool,
     pub write: bool,
     pub create: bool,
+    pub exclusive: bool,
     pub append: bool,
     pub truncate: bool,
     pub sync: bool,
@@ -16,6 +17,7 @@ impl From<i32> for OpenFlags {
         let read = flags & libc::O_WRONLY == libc::O_RDONLY || flags & libc::O_RDWR == libc::O_RDWR;
         let write = flags & libc::O_WRONLY != 0 || flags & libc::O_RDWR == libc::O_RDWR;
         let create = flags & libc::O_CREAT == libc::O_CREAT;
+        let exclusive = flags & libc::O_EXCL == libc::O_EXCL;
         let append = flags & libc::O_APPEND == libc::O_APPEND;
         let truncate = flags & libc::O_TRUNC == libc::O_TRUNC;
         let sync = flags & libc::O_SYNC == libc::O_SYNC;
@@ -24,6 +26,7 @@ impl From<i32> for OpenFlags {
             read,
             write,
             create,
+            exclusive,
             append,
             truncate,
             sync,
@@ -101,5 +104,11 @@ mod tests {
             ),
             (true, true, false, false, true, true)
         );
+
+        let flags = OpenFlags::from(libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL);
+        assert_eq!(
+            (flags.write, flags.create, flags.exclusive, flags.truncate),
+            (true, true, true, false)
+        );
     }
 }
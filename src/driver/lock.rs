@@ -0,0 +1,330 @@
+use std::{
+    cmp,
+    collections::{HashMap, HashSet},
+};
+
+use crate::errors::{Error, Result};
+
+/// Advisory byte range lock held or requested by a lock owner. The range `start..=end` is inclusive, a lock up to the
+/// end of the file ends at `OFFSET_MAX`. The kernel sends flock locks as whole-file locks whose owner is the open file
+/// description, so they are handled like POSIX locks. The two kinds are therefore not independent like on a local
+/// filesystem, a flock lock conflicts with an overlapping POSIX lock of another owner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lock {
+    pub owner: u64,
+    pub start: u64,
+    pub end: u64,
+    /// `F_RDLCK`, `F_WRLCK` or `F_UNLCK`.
+    pub typ: i32,
+    pub pid: u32,
+}
+
+impl Lock {
+    fn overlaps(&self, other: &Lock) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    /// Locks of the same owner never conflict, other locks conflict if they overlap and one of them is exclusive.
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.owner != other.owner && self.overlaps(other) && (self.typ == libc::F_WRLCK || other.typ == libc::F_WRLCK)
+    }
+}
+
+/// Request waiting for a lock, `waiter` is what is needed to answer it once the lock is granted.
+struct Waiter<W> {
+    ino: u64,
+    lock: Lock,
+    waiter: W,
+}
+
+/// Locks held on each inode and the requests waiting for them.
+pub struct LockManager<W> {
+    locks: HashMap<u64, Vec<Lock>>,
+    waiters: Vec<Waiter<W>>,
+}
+
+impl<W> LockManager<W> {
+    pub fn new() -> Self {
+        LockManager {
+            locks: HashMap::new(),
+            waiters: Vec::new(),
+        }
+    }
+
+    /// First lock of another owner conflicting with `lock`.
+    pub fn conflict(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        self.locks.get(&ino)?.iter().find(|l| l.conflicts(lock)).copied()
+    }
+
+    /// Acquire, convert or release (with `F_UNLCK`) the range of `lock` for its owner. Fails with `WouldBlock` if
+    /// another owner holds a conflicting lock. Returns the waiters that were granted their lock by the change.
+    pub fn set(&mut self, ino: u64, lock: Lock) -> Result<Vec<W>> {
+        if lock.typ != libc::F_UNLCK && self.conflict(ino, &lock).is_some() {
+            return Err(Error::WouldBlock);
+        }
+        self.apply(ino, lock);
+        Ok(self.wake(ino))
+    }
+
+    /// Queue a request conflicting with the current locks, it is granted once the conflicting locks are released.
+    /// The waiter is given back if waiting would deadlock.
+    pub fn wait(&mut self, ino: u64, lock: Lock, waiter: W) -> std::result::Result<(), W> {
+        if self.would_deadlock(ino, &lock) {
+            return Err(waiter);
+        }
+        self.waiters.push(Waiter { ino, lock, waiter });
+        Ok(())
+    }
+
+    /// Release all the locks of the owner on the inode and cancel its pending requests on it. Returns the waiters that
+    /// were granted their lock, and the cancelled waiters of the owner.
+    pub fn release_owner(&mut self, ino: u64, owner: u64) -> (Vec<W>, Vec<W>) {
+        let mut cancelled = Vec::new();
+        let mut i = 0;
+        while i < self.waiters.len() {
+            if self.waiters[i].ino == ino && self.waiters[i].lock.owner == owner {
+                cancelled.push(self.waiters.remove(i).waiter);
+            } else {
+                i += 1;
+            }
+        }
+
+        if let Some(locks) = self.locks.get_mut(&ino) {
+            locks.retain(|l| l.owner != owner);
+            if locks.is_empty() {
+                self.locks.remove(&ino);
+            }
+        }
+        (self.wake(ino), cancelled)
+    }
+
+    /// Replace the locks of the owner over the range of `lock`, the parts of them outside of the range are kept.
+    /// Adjacent locks of the same type are merged.
+    fn apply(&mut self, ino: u64, lock: Lock) {
+        let locks = self.locks.entry(ino).or_default();
+        let mut kept = Vec::with_capacity(locks.len() + 2);
+        for l in locks.drain(..) {
+            if l.owner != lock.owner || !l.overlaps(&lock) {
+                kept.push(l);
+                continue;
+            }
+            if l.start < lock.start {
+                kept.push(Lock {
+                    end: lock.start - 1,
+                    ..l
+                });
+            }
+            if l.end > lock.end {
+                kept.push(Lock {
+                    start: lock.end + 1,
+                    ..l
+                });
+            }
+        }
+
+        if lock.typ != libc::F_UNLCK {
+            let mut merged = lock;
+            kept.retain(|l| {
+                let adjacent = l.owner == merged.owner
+                    && l.typ == merged.typ
+                    && l.start <= merged.end.saturating_add(1)
+                    && merged.start <= l.end.saturating_add(1);
+                if adjacent {
+                    merged.start = cmp::min(merged.start, l.start);
+                    merged.end = cmp::max(merged.end, l.end);
+                }
+                !adjacent
+            });
+            kept.push(merged);
+        }
+
+        if kept.is_empty() {
+            self.locks.remove(&ino);
+        } else {
+            *locks = kept;
+        }
+    }
+
+    /// Grant the waiting requests on the inode that no longer conflict, in the order they were made.
+    fn wake(&mut self, ino: u64) -> Vec<W> {
+        let mut granted = Vec::new();
+        let mut i = 0;
+        while i < self.waiters.len() {
+            let w = &self.waiters[i];
+            if w.ino == ino && self.conflict(ino, &w.lock).is_none() {
+                let w = self.waiters.remove(i);
+                self.apply(ino, w.lock);
+                granted.push(w.waiter);
+                // A granted lock can downgrade a lock of its owner, the earlier requests are checked again.
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+        granted
+    }
+
+    /// Owners of the locks conflicting with `lock`.
+    fn blockers(&self, ino: u64, lock: &Lock) -> Vec<u64> {
+        self.locks
+            .get(&ino)
+            .into_iter()
+            .flatten()
+            .filter(|l| l.conflicts(lock))
+            .map(|l| l.owner)
+            .collect()
+    }
+
+    /// Waiting for `lock` deadlocks if one of the owners it waits for is itself waiting, directly or through other
+    /// owners, for the owner of `lock`.
+    fn would_deadlock(&self, ino: u64, lock: &Lock) -> bool {
+        let mut pending = self.blockers(ino, lock);
+        let mut seen = HashSet::new();
+        while let Some(owner) = pending.pop() {
+            if owner == lock.owner {
+                return true;
+            }
+            if !seen.insert(owner) {
+                continue;
+            }
+            for w in self.waiters.iter().filter(|w| w.lock.owner == owner) {
+                pending.extend(self.blockers(w.ino, &w.lock));
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lock, LockManager};
+    use crate::errors::Error;
+
+    const END: u64 = i64::MAX as u64;
+
+    fn lock(owner: u64, start: u64, end: u64, typ: i32) -> Lock {
+        Lock {
+            owner,
+            start,
+            end,
+            typ,
+            pid: owner as u32,
+        }
+    }
+
+    fn held(locks: &LockManager<u32>, ino: u64) -> Vec<(u64, u64, u64, i32)> {
+        let mut held: Vec<_> = locks
+            .locks
+            .get(&ino)
+            .into_iter()
+            .flatten()
+            .map(|l| (l.owner, l.start, l.end, l.typ))
+            .collect();
+        held.sort();
+        held
+    }
+
+    #[test]
+    fn test_lock_conflicts() -> anyhow::Result<()> {
+        let mut locks = LockManager::<u32>::new();
+
+        // Shared locks are compatible, an exclusive lock conflicts with any overlapping lock of another owner.
+        locks.set(1, lock(1, 0, 99, libc::F_RDLCK))?;
+        locks.set(1, lock(2, 50, 149, libc::F_RDLCK))?;
+        assert_eq!(locks.set(1, lock(3, 90, 90, libc::F_WRLCK)), Err(Error::WouldBlock));
+        assert_eq!(
+            locks.conflict(1, &lock(3, 90, 90, libc::F_WRLCK)),
+            Some(lock(1, 0, 99, libc::F_RDLCK))
+        );
+        locks.set(1, lock(3, 150, END, libc::F_WRLCK))?;
+        assert_eq!(locks.set(1, lock(1, 100, 200, libc::F_RDLCK)), Err(Error::WouldBlock));
+
+        // Other inodes are independent.
+        locks.set(2, lock(3, 0, END, libc::F_WRLCK))?;
+
+        // The owner can not upgrade while another owner shares the range, it can once the range is free.
+        assert_eq!(locks.set(1, lock(1, 0, 99, libc::F_WRLCK)), Err(Error::WouldBlock));
+        locks.release_owner(1, 2);
+        locks.set(1, lock(1, 0, 99, libc::F_WRLCK))?;
+        assert_eq!(
+            held(&locks, 1),
+            vec![(1, 0, 99, libc::F_WRLCK), (3, 150, END, libc::F_WRLCK)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_split_merge() -> anyhow::Result<()> {
+        let mut locks = LockManager::<u32>::new();
+
+        locks.set(1, lock(1, 0, 99, libc::F_WRLCK))?;
+        // Converting the middle of a lock splits it.
+        locks.set(1, lock(1, 40, 59, libc::F_RDLCK))?;
+        assert_eq!(
+            held(&locks, 1),
+            vec![
+                (1, 0, 39, libc::F_WRLCK),
+                (1, 40, 59, libc::F_RDLCK),
+                (1, 60, 99, libc::F_WRLCK)
+            ]
+        );
+        // Converting it back merges the adjacent locks.
+        locks.set(1, lock(1, 40, 59, libc::F_WRLCK))?;
+        assert_eq!(held(&locks, 1), vec![(1, 0, 99, libc::F_WRLCK)]);
+
+        // Unlocking a part of the range, then the rest.
+        locks.set(1, lock(1, 0, 9, libc::F_UNLCK))?;
+        assert_eq!(held(&locks, 1), vec![(1, 10, 99, libc::F_WRLCK)]);
+        locks.set(1, lock(1, 0, END, libc::F_UNLCK))?;
+        assert_eq!(held(&locks, 1), vec![]);
+        assert!(locks.locks.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_waiters() -> anyhow::Result<()> {
+        let mut locks = LockManager::<u32>::new();
+
+        locks.set(1, lock(1, 0, END, libc::F_WRLCK))?;
+        // Waiters are granted in order once the conflicting lock is released.
+        assert!(locks.wait(1, lock(2, 0, 9, libc::F_WRLCK), 2).is_ok());
+        assert!(locks.wait(1, lock(3, 0, 9, libc::F_RDLCK), 3).is_ok());
+        assert!(locks.wait(1, lock(4, 10, 19, libc::F_RDLCK), 4).is_ok());
+        assert_eq!(locks.set(1, lock(1, 0, 9, libc::F_UNLCK))?, vec![2]);
+        assert_eq!(locks.release_owner(1, 1), (vec![4], vec![]));
+        assert_eq!(locks.release_owner(1, 2), (vec![3], vec![]));
+        assert_eq!(
+            held(&locks, 1),
+            vec![(3, 0, 9, libc::F_RDLCK), (4, 10, 19, libc::F_RDLCK)]
+        );
+
+        // The pending requests of an owner are cancelled with its locks.
+        assert!(locks.wait(1, lock(5, 0, END, libc::F_WRLCK), 5).is_ok());
+        assert!(locks.wait(1, lock(4, 0, END, libc::F_WRLCK), 6).is_ok());
+        assert_eq!(locks.release_owner(1, 4), (vec![], vec![6]));
+        assert_eq!(locks.release_owner(1, 3), (vec![5], vec![]));
+        assert_eq!(held(&locks, 1), vec![(5, 0, END, libc::F_WRLCK)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_deadlock() -> anyhow::Result<()> {
+        let mut locks = LockManager::<u32>::new();
+
+        locks.set(1, lock(1, 0, 0, libc::F_WRLCK))?;
+        locks.set(1, lock(2, 1, 1, libc::F_WRLCK))?;
+        locks.set(2, lock(3, 0, END, libc::F_WRLCK))?;
+
+        // 1 waits for 2 and 2 waits for 3, so 3 can not wait for 1.
+        assert!(locks.wait(1, lock(1, 1, 1, libc::F_WRLCK), 1).is_ok());
+        assert!(locks.wait(2, lock(2, 0, END, libc::F_RDLCK), 2).is_ok());
+        assert_eq!(locks.wait(1, lock(3, 0, 0, libc::F_RDLCK), 3), Err(3));
+        // Nor can 2 wait for 1.
+        assert_eq!(locks.wait(1, lock(2, 0, 0, libc::F_WRLCK), 4), Err(4));
+
+        Ok(())
+    }
+}
@@ -1,64 +1,166 @@
 #![allow(clippy::too_many_arguments)]
 
+mod access;
+mod acl;
+mod atime;
 mod attr;
+mod buffer;
 mod flags;
 mod handle;
+mod lock;
 mod request_info;
+mod statfs;
+mod xattr;
 
 use std::{
     cmp,
+    collections::{hash_map::Entry, HashMap},
     ffi::OsStr,
     fs,
-    os::unix::fs::MetadataExt,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
     path::Path,
     time::{Duration, SystemTime},
 };
 
+use access::{check_inode_access, check_owner, check_sticky, check_xattr_access, drop_setid, load_acl};
+use acl::{Acl, ACL_ACCESS, ACL_DEFAULT};
 use attr::FileAttrBuilder;
-use fuser::FileAttr;
+use fuser::{FileAttr, TimeOrNow};
+use lock::{Lock, LockManager};
 use slab::Slab;
 
-use crate::queries::{self, block::Compression, dir_entry::ListDirEntry};
+use crate::queries::{
+    self,
+    block::{Storage, BLOCK_SIZE},
+    dir_entry::ListDirEntry,
+    inode::POSIX_BLOCK_SIZE,
+};
 use crate::types::FileType;
 use crate::{database::DatabaseOps, time::TimeSpec};
 use crate::{
     errors::{Error, Result},
     queries::block::Block,
 };
+pub use atime::AtimePolicy;
+pub use buffer::WriteBuffer;
 pub use flags::OpenFlags;
 pub use handle::FileHandle;
 pub use request_info::RequestInfo;
+pub use statfs::StatFs;
+pub use xattr::XattrReply;
 
 const DURATION: Duration = Duration::from_secs(0);
+/// Largest length copied by a single copy_file_range, the reply holds the copied length in 32 bits.
+const MAX_COPY: u64 = u32::MAX as u64 / BLOCK_SIZE * BLOCK_SIZE;
+/// Size of the chunks of data copied when blocks can't be shared.
+const COPY_CHUNK: u64 = 1024 * 1024;
+
+/// Answer to a lock request waiting for its lock, called once with the outcome of the request.
+type LockReply = Box<dyn FnOnce(Result<()>) + Send>;
 
 pub struct FuseDriver {
     pub db: DatabaseOps,
-    compression: Compression,
+    storage: Storage,
     handles: Slab<FileHandle>,
-    mount_uid: u32,
-    mount_gid: u32,
+    /// Write-back caches of the inodes opened through handles.
+    buffers: HashMap<u64, WriteBuffer>,
+    /// Number of references the kernel holds on each inode, released through forget.
+    lookups: HashMap<u64, u64>,
+    /// Advisory locks, the requests waiting for a lock are answered once it is granted.
+    locks: LockManager<LockReply>,
+    mount_owner: MountOwner,
+    atime: AtimePolicy,
+}
+
+/// Owner of the mount target, the root directory is reported as belonging to it.
+#[derive(Clone, Copy, Debug, Default)]
+struct MountOwner {
+    uid: u32,
+    gid: u32,
+}
+
+impl MountOwner {
+    fn lookup(self, tx: &mut rusqlite::Transaction, ino: u64) -> Result<FileAttr> {
+        let mut attr = queries::inode::lookup(tx, ino)?;
+        // Root directory should have the same owner/group as the mount target
+        if attr.ino == 1 {
+            attr.uid = self.uid;
+            attr.gid = self.gid;
+        }
+        Ok(attr)
+    }
+}
+
+/// Check that the inode is a directory, namespace operations on other kinds fail with ENOTDIR.
+fn check_is_dir(attr: &FileAttr) -> Result<()> {
+    if attr.kind != fuser::FileType::Directory {
+        return Err(Error::NotADirectory);
+    }
+    Ok(())
+}
+
+/// Check if the inode is still referenced by the kernel or by an open handle.
+fn inode_in_use(handles: &Slab<FileHandle>, lookups: &HashMap<u64, u64>, ino: u64) -> bool {
+    lookups.contains_key(&ino) || handles.iter().any(|(_, handle)| handle.ino == ino)
+}
+
+/// The content of the inode changed, which is also a change of its status.
+fn touch_modified(tx: &mut rusqlite::Transaction, ino: u64, now: TimeSpec) -> Result<()> {
+    queries::inode::set_time(tx, ino, "mtime", now)?;
+    queries::inode::set_time(tx, ino, "ctime", now)
+}
+
+/// Read `len` bytes of the inode at `offset`, the caller makes sure they are within the size of the inode.
+fn read_range(tx: &mut rusqlite::Transaction, ino: u64, offset: u64, len: u64) -> Result<Vec<u8>> {
+    // Missing blocks and missing data at the end of blocks are holes that read as zeros.
+    let mut buf = vec![0; len as usize];
+    queries::block::iter_blocks_from(tx, ino, offset, |block| {
+        if block.start_offset() >= offset + len {
+            return Ok(false);
+        }
+        block.copy_into(&mut buf, offset);
+        Ok(true)
+    })?;
+    Ok(buf)
+}
+
+/// Directories can only be opened for reading.
+fn check_open_kind(attr: &FileAttr, flags: OpenFlags) -> Result<()> {
+    if attr.kind == fuser::FileType::Directory && (flags.write || flags.truncate) {
+        return Err(Error::IsADirectory);
+    }
+    Ok(())
 }
 
 impl FuseDriver {
-    pub fn new(db: DatabaseOps, compression: Compression, mount_path: &Path) -> anyhow::Result<Self> {
+    pub fn new(db: DatabaseOps, storage: Storage, atime: AtimePolicy, mount_path: &Path) -> anyhow::Result<Self> {
         let md = fs::metadata(mount_path)?;
         Ok(Self {
             db,
-            compression,
+            storage,
             handles: Slab::new(),
-            mount_uid: md.uid(),
-            mount_gid: md.gid(),
+            buffers: HashMap::new(),
+            lookups: HashMap::new(),
+            locks: LockManager::new(),
+            mount_owner: MountOwner {
+                uid: md.uid(),
+                gid: md.gid(),
+            },
+            atime,
         })
     }
 
     #[cfg(test)]
-    pub fn new_no_io(db: DatabaseOps, compression: Compression) -> Self {
+    pub fn new_no_io(db: DatabaseOps, compression: queries::block::Compression) -> Self {
         Self {
             db,
-            compression,
+            storage: compression.into(),
             handles: Slab::new(),
-            mount_uid: 0,
-            mount_gid: 0,
+            buffers: HashMap::new(),
+            lookups: HashMap::new(),
+            locks: LockManager::new(),
+            mount_owner: MountOwner::default(),
+            atime: AtimePolicy::default(),
         }
     }
 
@@ -78,49 +180,320 @@ impl FuseDriver {
         })
     }
 
-    fn lookup_impl(&mut self, _req: RequestInfo, parent: u64, name: &OsStr) -> Result<FileAttr> {
-        self.db.with_read_tx(|tx| {
-            let ino = queries::dir_entry::lookup(tx, parent, name)?;
-            let mut attr = queries::inode::lookup(tx, ino)?;
-            // Root directory should have the same owner/group as the mount target
-            if attr.ino == 1 {
-                attr.uid = self.mount_uid;
-                attr.gid = self.mount_gid;
+    /// Remove the orphans left behind by a previous mount.
+    fn remove_orphans(&mut self) -> Result<()> {
+        // Snapshots are read-only and never contain orphans.
+        if self.db.is_read_only() {
+            return Ok(());
+        }
+        let removed = self.db.with_write_tx(queries::inode::remove_orphans)?;
+        if removed > 0 {
+            log::info!("Removed {} orphan inodes", removed);
+        }
+        Ok(())
+    }
+
+    fn add_lookup(&mut self, ino: u64) {
+        *self.lookups.entry(ino).or_default() += 1;
+    }
+
+    /// Delete the inode if it has no links left and is no longer in use.
+    fn delete_if_orphan(&mut self, ino: u64) -> Result<()> {
+        if inode_in_use(&self.handles, &self.lookups, ino) {
+            return Ok(());
+        }
+        self.db.with_write_tx(|tx| match queries::inode::lookup(tx, ino) {
+            Ok(attr) if attr.nlink == 0 => {
+                log::debug!("Removing orphan inode {}", ino);
+                queries::inode::remove(tx, ino)
             }
-            Ok(attr)
+            Ok(_) | Err(Error::NotFound) => Ok(()),
+            Err(e) => Err(e),
         })
     }
 
-    fn getattr_impl(&mut self, _req: RequestInfo, ino: u64) -> Result<FileAttr> {
-        let mut attr = self.db.with_read_tx(|tx| queries::inode::lookup(tx, ino))?;
-        // Root directory should have the same owner/group as the mount target
-        if attr.ino == 1 {
-            attr.uid = self.mount_uid;
-            attr.gid = self.mount_gid;
+    /// Create a new inode and its entry in the parent directory. If the parent directory has a default ACL, it is
+    /// inherited by the new inode and the umask is ignored.
+    fn create_child(
+        tx: &mut rusqlite::Transaction,
+        req: RequestInfo,
+        parent: &FileAttr,
+        name: &OsStr,
+        attr: &mut FileAttr,
+        mode: u32,
+    ) -> Result<()> {
+        let default_acl = match attr.kind {
+            fuser::FileType::Symlink => None,
+            _ => load_acl(tx, parent.ino, ACL_DEFAULT)?,
+        };
+
+        let acl = default_acl.as_ref().map(|default_acl| {
+            let mut acl = default_acl.clone();
+            attr.perm = acl.inherit((mode & 0o7777) as u16);
+            acl
+        });
+        Self::inherit_group(req, parent, attr);
+        queries::inode::create(tx, attr)?;
+
+        if let (Some(acl), Some(default_acl)) = (acl, default_acl) {
+            if !acl.is_minimal() {
+                queries::xattr::set(tx, attr.ino, OsStr::new(ACL_ACCESS), &acl.to_bytes())?;
+            }
+            if attr.kind == fuser::FileType::Directory {
+                queries::xattr::set(tx, attr.ino, OsStr::new(ACL_DEFAULT), &default_acl.to_bytes())?;
+            }
         }
-        Ok(attr)
+
+        queries::dir_entry::create(tx, parent.ino, name, attr.ino)?;
+        touch_modified(tx, parent.ino, attr.ctime.into())
+    }
+
+    /// In a set-group-ID directory, new entries belong to the group of the directory and new subdirectories are
+    /// set-group-ID themselves. A new file only keeps its set-group-ID bit if the creator belongs to that group.
+    fn inherit_group(req: RequestInfo, parent: &FileAttr, attr: &mut FileAttr) {
+        const S_ISGID: u16 = libc::S_ISGID as u16;
+        if parent.perm & S_ISGID != 0 {
+            attr.gid = parent.gid;
+            if attr.kind == fuser::FileType::Directory {
+                attr.perm |= S_ISGID;
+            }
+        }
+        let group_exec = S_ISGID | libc::S_IXGRP as u16;
+        if attr.kind != fuser::FileType::Directory
+            && attr.perm & group_exec == group_exec
+            && req.uid != 0
+            && !req.in_group(attr.gid)
+        {
+            attr.perm &= !S_ISGID;
+        }
+    }
+
+    /// Remove the data past `size` and update the size of the inode. The content is kept as a version if data is
+    /// removed.
+    fn truncate(tx: &mut rusqlite::Transaction, ino: u64, size: u64, storage: Storage) -> Result<()> {
+        if size < queries::inode::lookup(tx, ino)?.size {
+            queries::version::record(tx, ino, TimeSpec::now())?;
+        }
+        // Blocks starting at or after the new size are removed, the block containing the new size is cut.
+        queries::block::remove_blocks_from(tx, ino, size.div_ceil(BLOCK_SIZE))?;
+        if !size.is_multiple_of(BLOCK_SIZE) {
+            match queries::block::get_block(tx, ino, Block::offset_to_bno(size)) {
+                Ok(mut block) => {
+                    block.truncate(size);
+                    queries::block::update(tx, &block, storage)?;
+                }
+                Err(Error::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        queries::inode::set_attr(tx, ino, "size", size)?;
+        queries::inode::update_blocks(tx, ino)?;
+        Ok(())
+    }
+
+    /// Keep the content of the file as a version before it is first modified through the handle.
+    fn record_version(&mut self, fh: usize) -> Result<()> {
+        let handle = self.handles.get_mut(fh).ok_or(Error::NotFound)?;
+        if handle.versioned {
+            return Ok(());
+        }
+        handle.versioned = true;
+        let ino = handle.ino;
+        // The data buffered by other handles is part of the current content.
+        self.flush_buffer(ino)?;
+        self.db
+            .with_write_tx(|tx| queries::version::record(tx, ino, TimeSpec::now()))?;
+        Ok(())
+    }
+
+    /// Delete the versions of the files that are outside of the retention.
+    fn prune_versions(&mut self) -> Result<()> {
+        if self.db.is_read_only() {
+            return Ok(());
+        }
+        let removed = self.db.with_write_tx(|tx| {
+            let retention = queries::version::Retention::load(tx)?;
+            queries::version::prune(tx, None, TimeSpec::now(), retention)
+        })?;
+        if removed > 0 {
+            log::info!("Removed {} old file versions", removed);
+        }
+        Ok(())
+    }
+
+    /// Write the buffered data of the inode to the database.
+    fn flush_buffer(&mut self, ino: u64) -> Result<()> {
+        match self.buffers.get_mut(&ino) {
+            Some(buffer) if !buffer.is_empty() => self.db.with_write_tx(|tx| buffer.flush(tx)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Flush the data buffered for the file at the entry, if any. It is part of the content kept as a version when
+    /// the last link to the file is removed.
+    fn flush_entry(&mut self, parent: u64, name: &OsStr) -> Result<()> {
+        if self.buffers.values().all(|b| b.is_empty()) {
+            return Ok(());
+        }
+        match self.db.with_read_tx(|tx| queries::dir_entry::lookup(tx, parent, name)) {
+            Ok(ino) => self.flush_buffer(ino),
+            Err(Error::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The attributes of a file being written include the data still in its buffer. Until it is compressed,
+    /// the buffered data is counted as allocated in full.
+    fn with_buffered_attr(&self, mut attr: FileAttr) -> FileAttr {
+        if let Some(buffer) = self.buffers.get(&attr.ino).filter(|b| !b.is_empty()) {
+            attr.size = cmp::max(attr.size, buffer.write_offset());
+            attr.blocks += (buffer.len() as u64).div_ceil(POSIX_BLOCK_SIZE);
+            attr.mtime = cmp::max(attr.mtime, buffer.modified().into());
+            attr.ctime = cmp::max(attr.ctime, buffer.modified().into());
+        }
+        attr
+    }
+
+    /// Update the access time of the inode after it was read, as allowed by the atime policy.
+    fn touch_accessed(&mut self, attr: &FileAttr) -> Result<()> {
+        let now = SystemTime::now();
+        if self.db.is_read_only() || !self.atime.needs_update(attr, now) {
+            return Ok(());
+        }
+        self.db
+            .with_write_tx(|tx| queries::inode::set_time(tx, attr.ino, "atime", now.into()))
+    }
+
+    /// Modifying a file through the handle without being root drops its set-ID bits.
+    fn drop_setid_on_write(&mut self, req: RequestInfo, fh: usize) -> Result<()> {
+        let handle = self.handles.get(fh).ok_or(Error::NotFound)?;
+        if req.uid == 0 || handle.setid_checked {
+            return Ok(());
+        }
+        let ino = handle.ino;
+        let attr = self.db.with_read_tx(|tx| queries::inode::lookup(tx, ino))?;
+        let perm = drop_setid(attr.kind, attr.perm);
+        if perm != attr.perm {
+            self.db.with_write_tx(|tx| {
+                queries::inode::set_attr(tx, ino, "perm", perm)?;
+                queries::inode::set_time(tx, ino, "ctime", TimeSpec::now())
+            })?;
+        }
+        self.handles[fh].setid_checked = true;
+        Ok(())
+    }
+
+    fn insert_handle(&mut self, ino: u64, flags: OpenFlags) -> Result<u64> {
+        let fh = self.handles.insert(FileHandle::new(ino, flags));
+        u64::try_from(fh).map_err(|_| Error::Overflow)
+    }
+
+    /// Check that the requester can add or remove entries in the directory.
+    fn check_dir_write(
+        tx: &mut rusqlite::Transaction,
+        owner: MountOwner,
+        req: RequestInfo,
+        parent: u64,
+    ) -> Result<FileAttr> {
+        let parent_attr = owner.lookup(tx, parent)?;
+        check_is_dir(&parent_attr)?;
+        check_inode_access(tx, &parent_attr, req, libc::W_OK | libc::X_OK)?;
+        Ok(parent_attr)
+    }
+
+    fn lookup_impl(&mut self, req: RequestInfo, parent: u64, name: &OsStr) -> Result<FileAttr> {
+        self.db
+            .with_read_tx(|tx| {
+                // Searching a directory requires the execute permission.
+                let parent_attr = self.mount_owner.lookup(tx, parent)?;
+                check_is_dir(&parent_attr)?;
+                check_inode_access(tx, &parent_attr, req, libc::X_OK)?;
+                let ino = queries::dir_entry::lookup(tx, parent, name)?;
+                self.mount_owner.lookup(tx, ino)
+            })
+            .map(|attr| self.with_buffered_attr(attr))
+    }
+
+    fn getattr_impl(&mut self, _req: RequestInfo, ino: u64) -> Result<FileAttr> {
+        let attr = self.db.with_read_tx(|tx| self.mount_owner.lookup(tx, ino))?;
+        Ok(self.with_buffered_attr(attr))
+    }
+
+    fn access_impl(&mut self, req: RequestInfo, ino: u64, mask: i32) -> Result<()> {
+        self.db.with_read_tx(|tx| {
+            let attr = self.mount_owner.lookup(tx, ino)?;
+            check_inode_access(tx, &attr, req, mask)
+        })
     }
 
     fn setattr_impl(
         &mut self,
-        _req: RequestInfo,
+        req: RequestInfo,
         ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
         size: Option<u64>,
-        atime: Option<TimeSpec>,
-        mtime: Option<TimeSpec>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
         ctime: Option<TimeSpec>,
-        _fh: Option<u64>,
+        fh: Option<u64>,
         crtime: Option<TimeSpec>,
         _chgtime: Option<TimeSpec>,
         _bkuptime: Option<TimeSpec>,
         flags: Option<u32>,
     ) -> Result<FileAttr> {
-        self.db.with_write_tx(|tx| {
+        // Pending writes must not land after the truncation nor replace the times given explicitly.
+        self.flush_buffer(ino)?;
+        let attr = self.db.with_write_tx(|tx| {
+            let attr = self.mount_owner.lookup(tx, ino)?;
+
+            if mode.is_some() || ctime.is_some() || crtime.is_some() || flags.is_some() {
+                check_owner(&attr, req)?;
+            }
+            if uid.is_some() || gid.is_some() {
+                // Only root can give a file away. The owner can change the group to one they belong to.
+                check_owner(&attr, req)?;
+                let uid_changed = uid.is_some_and(|uid| uid != attr.uid);
+                let gid_changed = gid.is_some_and(|gid| gid != attr.gid && !req.in_group(gid));
+                if req.uid != 0 && (uid_changed || gid_changed) {
+                    return Err(Error::NotPermitted);
+                }
+            }
+            if size.is_some() && attr.kind == fuser::FileType::Directory {
+                return Err(Error::IsADirectory);
+            }
+            // Truncating through a file handle was already checked when the file was opened.
+            if size.is_some() && fh.is_none() {
+                check_inode_access(tx, &attr, req, libc::W_OK)?;
+            }
+            // Setting the times to the current time is allowed with write access, any other time requires ownership.
+            let specific_time = |t: Option<TimeOrNow>| matches!(t, Some(TimeOrNow::SpecificTime(_)));
+            if specific_time(atime) || specific_time(mtime) {
+                check_owner(&attr, req)?;
+            } else if (atime.is_some() || mtime.is_some()) && check_owner(&attr, req).is_err() {
+                check_inode_access(tx, &attr, req, libc::W_OK)?;
+            }
+
+            let mut perm = attr.perm;
             if let Some(mode) = mode {
-                queries::inode::set_attr(tx, ino, "perm", mode)?;
+                perm = (mode & 0o7777) as u16;
+                // Only members of the group of the file can make it set-group-ID, the bit is silently dropped.
+                if req.uid != 0 && perm & libc::S_ISGID as u16 != 0 && !req.in_group(gid.unwrap_or(attr.gid)) {
+                    perm &= !(libc::S_ISGID as u16);
+                }
+                // The access ACL must stay in sync with the permission bits.
+                if let Some(mut acl) = load_acl(tx, ino, ACL_ACCESS)? {
+                    acl.chmod(perm);
+                    queries::xattr::set(tx, ino, OsStr::new(ACL_ACCESS), &acl.to_bytes())?;
+                }
+            }
+            // Giving the file away, or truncating it without being root, drops its set-ID bits.
+            if uid.is_some() || gid.is_some() || (size.is_some() && req.uid != 0) {
+                perm = drop_setid(attr.kind, perm);
+            }
+            if mode.is_some() || perm != attr.perm {
+                queries::inode::set_attr(tx, ino, "perm", perm)?;
             }
             if let Some(uid) = uid {
                 queries::inode::set_attr(tx, ino, "uid", uid)?;
@@ -129,40 +502,51 @@ impl FuseDriver {
                 queries::inode::set_attr(tx, ino, "gid", gid)?;
             }
             if let Some(size) = size {
-                let bno = Block::offset_to_bno(size);
-                queries::block::remove_blocks_from(tx, ino, bno + 1)?;
-                match queries::block::get_block(tx, ino, bno) {
-                    Ok(mut block) => {
-                        block.truncate(size);
-                        queries::block::update(tx, &block, self.compression)?;
-                    }
-                    Err(Error::NotFound) => {}
-                    Err(e) => return Err(e),
-                }
-                queries::inode::set_attr(tx, ino, "size", size)?;
+                Self::truncate(tx, ino, size, self.storage)?;
+            }
+            if let Some(flags) = flags {
+                queries::inode::set_attr(tx, ino, "flags", flags)?;
+            }
+
+            // Any change of the attributes is a change of the status, truncation also modifies the content. The
+            // times given explicitly take precedence.
+            let now = TimeSpec::now();
+            if size.is_some() {
+                queries::inode::set_time(tx, ino, "mtime", now)?;
+            }
+            let status_changed = mode.is_some()
+                || uid.is_some()
+                || gid.is_some()
+                || size.is_some()
+                || flags.is_some()
+                || atime.is_some()
+                || mtime.is_some()
+                || crtime.is_some();
+            if status_changed {
+                queries::inode::set_time(tx, ino, "ctime", now)?;
             }
             if let Some(atime) = atime {
-                queries::inode::set_attr(tx, ino, "atime_secs", atime.secs)?;
-                queries::inode::set_attr(tx, ino, "atime_nanos", atime.nanos)?;
+                queries::inode::set_time(tx, ino, "atime", atime.into())?;
             }
             if let Some(mtime) = mtime {
-                queries::inode::set_attr(tx, ino, "mtime_secs", mtime.secs)?;
-                queries::inode::set_attr(tx, ino, "mtime_nanos", mtime.nanos)?;
+                queries::inode::set_time(tx, ino, "mtime", mtime.into())?;
             }
             if let Some(ctime) = ctime {
-                queries::inode::set_attr(tx, ino, "ctime_secs", ctime.secs)?;
-                queries::inode::set_attr(tx, ino, "ctime_nanos", ctime.nanos)?;
+                queries::inode::set_time(tx, ino, "ctime", ctime)?;
             }
             if let Some(crtime) = crtime {
-                queries::inode::set_attr(tx, ino, "crtime_secs", crtime.secs)?;
-                queries::inode::set_attr(tx, ino, "crtime_nanos", crtime.nanos)?;
-            }
-            if let Some(flags) = flags {
-                queries::inode::set_attr(tx, ino, "flags", flags)?;
+                queries::inode::set_time(tx, ino, "crtime", crtime)?;
             }
 
             queries::inode::lookup(tx, ino)
-        })
+        })?;
+        // The mode can set the set-ID bits again, the next write through each handle checks them.
+        if mode.is_some() {
+            for (_, handle) in self.handles.iter_mut().filter(|(_, h)| h.ino == ino) {
+                handle.setid_checked = false;
+            }
+        }
+        Ok(self.with_buffered_attr(attr))
     }
 
     fn mknod_impl(
@@ -184,39 +568,123 @@ impl FuseDriver {
             .build();
 
         self.db.with_write_tx(|tx| {
-            queries::inode::create(tx, &mut attr)?;
-            queries::dir_entry::create(tx, parent, name, attr.ino)?;
+            let parent_attr = Self::check_dir_write(tx, self.mount_owner, req, parent)?;
+            Self::create_child(tx, req, &parent_attr, name, &mut attr, mode)?;
             Ok(attr)
         })
     }
 
-    fn link_impl(&mut self, _req: RequestInfo, ino: u64, newparent: u64, newname: &OsStr) -> Result<FileAttr> {
+    fn symlink_impl(&mut self, req: RequestInfo, parent: u64, link_name: &OsStr, target: &Path) -> Result<FileAttr> {
+        let target = target.as_os_str();
+
+        // Symlink permissions are always 0777, the target's permissions are the ones that matter.
+        let mut attr = FileAttrBuilder::new_node(FileType::Symlink)
+            .with_uid(req.uid)
+            .with_gid(req.gid)
+            .with_mode_umask(0o777, 0)
+            .with_size(target.len() as u64)
+            .build();
+
+        self.db.with_write_tx(|tx| {
+            let parent_attr = Self::check_dir_write(tx, self.mount_owner, req, parent)?;
+            Self::create_child(tx, req, &parent_attr, link_name, &mut attr, 0o777)?;
+            queries::symlink::create(tx, attr.ino, target)?;
+            Ok(attr)
+        })
+    }
+
+    fn readlink_impl(&mut self, _req: RequestInfo, ino: u64) -> Result<Vec<u8>> {
+        self.db.with_read_tx(|tx| {
+            let attr = queries::inode::lookup(tx, ino)?;
+            if attr.kind != fuser::FileType::Symlink {
+                return Err(Error::InvalidArgument);
+            }
+            queries::symlink::lookup(tx, ino)
+        })
+    }
+
+    fn link_impl(&mut self, req: RequestInfo, ino: u64, newparent: u64, newname: &OsStr) -> Result<FileAttr> {
         self.db.with_write_tx(|tx| {
+            Self::check_dir_write(tx, self.mount_owner, req, newparent)?;
             let mut attr = queries::inode::lookup(tx, ino)?;
+            // Hard links to directories are not allowed.
+            if attr.kind == fuser::FileType::Directory {
+                return Err(Error::NotPermitted);
+            }
             attr.nlink += 1;
             queries::dir_entry::create(tx, newparent, newname, ino)?;
             queries::inode::set_attr(tx, ino, "nlink", attr.nlink)?;
+            let now = TimeSpec::now();
+            queries::inode::set_time(tx, ino, "ctime", now)?;
+            touch_modified(tx, newparent, now)?;
+            attr.ctime = now.into();
             Ok(attr)
         })
     }
 
-    fn unlink_impl(&mut self, _req: RequestInfo, parent: u64, name: &OsStr) -> Result<()> {
+    fn unlink_impl(&mut self, req: RequestInfo, parent: u64, name: &OsStr) -> Result<()> {
+        self.flush_entry(parent, name)?;
         self.db.with_write_tx(|tx| {
+            let parent_attr = Self::check_dir_write(tx, self.mount_owner, req, parent)?;
             let ino = queries::dir_entry::lookup(tx, parent, name)?;
             let mut attr = queries::inode::lookup(tx, ino)?;
-            attr.nlink -= 1;
-            if attr.nlink > 0 {
-                queries::inode::set_attr(tx, ino, "nlink", attr.nlink)?;
-                queries::dir_entry::remove(tx, parent, name)?;
-            } else {
-                // If nlink == 0, the inode removal will remove the dir_entry through CASCADE.
-                // The blocks will also be removed through CASCADE.
-                queries::inode::remove(tx, ino)?;
+            if attr.kind == fuser::FileType::Directory {
+                return Err(Error::IsADirectory);
             }
-            Ok(())
+            check_sticky(&parent_attr, &attr, req)?;
+            let in_use = inode_in_use(&self.handles, &self.lookups, ino);
+            Self::drop_link(tx, parent, name, &mut attr, in_use)
         })
     }
 
+    /// Remove the entry of the inode in the parent directory. The inode is removed with its last link, unless it is
+    /// still in use. It is then kept as an orphan until the last handle is released and the kernel forgets it.
+    fn drop_link(
+        tx: &mut rusqlite::Transaction,
+        parent: u64,
+        name: &OsStr,
+        attr: &mut FileAttr,
+        in_use: bool,
+    ) -> Result<()> {
+        attr.nlink = attr.nlink.saturating_sub(1);
+        if attr.kind == fuser::FileType::Directory {
+            // The '..' entry of the directory links to the parent.
+            queries::inode::add_nlink(tx, parent, -1)?;
+            attr.nlink = 0;
+        }
+        let now = TimeSpec::now();
+        // The content of a regular file is kept as a version with its last link, e.g. when a rename replaces it.
+        if attr.kind == fuser::FileType::RegularFile && attr.nlink == 0 {
+            queries::version::record_removal(tx, attr.ino, parent, name, now)?;
+        }
+        if attr.nlink > 0 || in_use {
+            queries::inode::set_attr(tx, attr.ino, "nlink", attr.nlink)?;
+            queries::inode::set_time(tx, attr.ino, "ctime", now)?;
+            queries::dir_entry::remove(tx, parent, name)?;
+        } else {
+            // If nlink == 0, the inode removal will remove the dir_entry through CASCADE.
+            // The blocks will also be removed through CASCADE.
+            queries::inode::remove(tx, attr.ino)?;
+        }
+        touch_modified(tx, parent, now)
+    }
+
+    /// Check that the directory `ino` is not `dir` or one of its ancestors, so that moving it into `dir` does not
+    /// detach it from the tree.
+    fn check_not_ancestor(tx: &mut rusqlite::Transaction, ino: u64, dir: u64) -> Result<()> {
+        let mut current = dir;
+        loop {
+            if current == ino {
+                return Err(Error::InvalidArgument);
+            }
+            match queries::dir_entry::parent_of(tx, current) {
+                Ok(parent) => current = parent,
+                Err(Error::NotFound) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     fn mkdir_impl(&mut self, req: RequestInfo, parent: u64, name: &OsStr, mode: u32, umask: u32) -> Result<FileAttr> {
         let mut attr = FileAttrBuilder::new_directory()
             .with_mode_umask(mode, umask)
@@ -225,55 +693,190 @@ impl FuseDriver {
             .build();
 
         self.db.with_write_tx(|tx| {
-            queries::inode::create(tx, &mut attr)?;
-            queries::dir_entry::create(tx, parent, name, attr.ino)?;
+            let parent_attr = Self::check_dir_write(tx, self.mount_owner, req, parent)?;
+            Self::create_child(tx, req, &parent_attr, name, &mut attr, mode)?;
+            queries::inode::add_nlink(tx, parent, 1)?;
             Ok(attr)
         })
     }
 
-    fn rmdir_impl(&mut self, _req: RequestInfo, parent: u64, name: &OsStr) -> Result<()> {
+    fn rmdir_impl(&mut self, req: RequestInfo, parent: u64, name: &OsStr) -> Result<()> {
         self.db.with_write_tx(|tx| {
+            let parent_attr = Self::check_dir_write(tx, self.mount_owner, req, parent)?;
             let ino = queries::dir_entry::lookup(tx, parent, name)?;
+            let mut attr = queries::inode::lookup(tx, ino)?;
+            check_is_dir(&attr)?;
+            check_sticky(&parent_attr, &attr, req)?;
             let empty = queries::dir_entry::is_dir_empty(tx, ino)?;
             if !empty {
                 return Err(Error::NotEmpty);
             }
-            queries::inode::remove(tx, ino)?; // CASCADE will remove dir_entry
-            Ok(())
+            let in_use = inode_in_use(&self.handles, &self.lookups, ino);
+            Self::drop_link(tx, parent, name, &mut attr, in_use)
         })
     }
 
-    fn readdir_impl<F>(&mut self, _req: RequestInfo, ino: u64, _fh: u64, offset: i64, iter: F) -> Result<()>
+    fn readdir_impl<F>(&mut self, req: RequestInfo, ino: u64, _fh: u64, offset: i64, mut iter: F) -> Result<()>
     where
         F: FnMut(ListDirEntry) -> bool,
     {
-        self.db.with_read_tx(|tx| {
-            queries::dir_entry::list_dir(tx, ino, offset, iter)?;
-            Ok(())
-        })
+        let attr = self.db.with_read_tx(|tx| {
+            let attr = self.mount_owner.lookup(tx, ino)?;
+            check_is_dir(&attr)?;
+            check_inode_access(tx, &attr, req, libc::R_OK)?;
+
+            // '.' and '..' use the offsets 1 and 2, the offsets of the other entries are shifted past them.
+            if offset < 1 && !iter(ListDirEntry::new(1, ino, OsStr::new("."), fuser::FileType::Directory)) {
+                return Ok(attr);
+            }
+            if offset < 2 {
+                let parent = match queries::dir_entry::parent_of(tx, ino) {
+                    Ok(parent) => parent,
+                    // The parent of the root directory is itself.
+                    Err(Error::NotFound) => ino,
+                    Err(e) => return Err(e),
+                };
+                if !iter(ListDirEntry::new(
+                    2,
+                    parent,
+                    OsStr::new(".."),
+                    fuser::FileType::Directory,
+                )) {
+                    return Ok(attr);
+                }
+            }
+            queries::dir_entry::list_dir(tx, ino, cmp::max(offset - 2, 0), |entry| {
+                iter(ListDirEntry {
+                    offset: entry.offset + 2,
+                    ..entry
+                })
+            })?;
+            Ok(attr)
+        })?;
+        self.touch_accessed(&attr)
     }
 
-    fn open_impl(&mut self, _req: RequestInfo, ino: u64, flags: OpenFlags) -> Result<(u64, u32)> {
-        let attr = self.db.with_read_tx(|tx| queries::inode::lookup(tx, ino))?;
-        let fh = self
-            .handles
-            .insert(FileHandle::new(ino, attr.size, flags, self.compression));
-        let fh = u64::try_from(fh).map_err(|_| Error::Overflow)?;
+    fn open_impl(&mut self, req: RequestInfo, ino: u64, flags: OpenFlags) -> Result<(u64, u32)> {
+        if flags.truncate {
+            // Pending writes must not land after the truncation.
+            self.flush_buffer(ino)?;
+        }
+        self.db.with_write_tx(|tx| {
+            let attr = self.mount_owner.lookup(tx, ino)?;
+            check_open_kind(&attr, flags)?;
+            let mut mask = 0;
+            if flags.read {
+                mask |= libc::R_OK;
+            }
+            if flags.write || flags.truncate {
+                mask |= libc::W_OK;
+            }
+            check_inode_access(tx, &attr, req, mask)?;
+            if flags.truncate {
+                if attr.size > 0 {
+                    Self::truncate(tx, ino, 0, self.storage)?;
+                }
+                touch_modified(tx, ino, TimeSpec::now())?;
+            }
+            Ok(())
+        })?;
+        let fh = self.insert_handle(ino, flags)?;
         Ok((fh, flags.bits as u32))
     }
 
+    fn create_impl(
+        &mut self,
+        req: RequestInfo,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: OpenFlags,
+    ) -> Result<(FileAttr, u64, u32)> {
+        let kind = FileType::from_mode(mode).ok_or(Error::InvalidArgument)?;
+
+        let mut attr = FileAttrBuilder::new_node(kind)
+            .with_uid(req.uid)
+            .with_gid(req.gid)
+            .with_mode_umask(mode, umask)
+            .build();
+
+        // The inode, the directory entry and the handle are created in a single transaction.
+        let attr = self
+            .db
+            .with_write_tx(|tx| match queries::dir_entry::lookup(tx, parent, name) {
+                Ok(_) if flags.exclusive => Err(Error::AlreadyExists),
+                Ok(ino) => {
+                    // The file already exists, this is a regular open.
+                    let mut existing = self.mount_owner.lookup(tx, ino)?;
+                    check_open_kind(&existing, flags)?;
+                    let mut mask = 0;
+                    if flags.read {
+                        mask |= libc::R_OK;
+                    }
+                    if flags.write || flags.truncate {
+                        mask |= libc::W_OK;
+                    }
+                    check_inode_access(tx, &existing, req, mask)?;
+                    if let Some(buffer) = self.buffers.get_mut(&ino).filter(|_| flags.truncate) {
+                        buffer.flush(tx)?;
+                        existing.size = queries::inode::lookup(tx, ino)?.size;
+                    }
+                    if flags.truncate {
+                        if existing.size > 0 {
+                            Self::truncate(tx, ino, 0, self.storage)?;
+                            existing = self.mount_owner.lookup(tx, ino)?;
+                        }
+                        let now = TimeSpec::now();
+                        touch_modified(tx, ino, now)?;
+                        existing.mtime = now.into();
+                        existing.ctime = existing.mtime;
+                    }
+                    Ok(existing)
+                }
+                Err(Error::NotFound) => {
+                    let parent_attr = Self::check_dir_write(tx, self.mount_owner, req, parent)?;
+                    Self::create_child(tx, req, &parent_attr, name, &mut attr, mode)?;
+                    Ok(attr)
+                }
+                Err(e) => Err(e),
+            })?;
+
+        let fh = self.insert_handle(attr.ino, flags)?;
+        Ok((attr, fh, flags.bits as u32))
+    }
+
     fn release_impl(
         &mut self,
         _req: RequestInfo,
         _ino: u64,
         fh: u64,
         _flags: i32,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
         _flush: bool,
     ) -> Result<()> {
         let fh = usize::try_from(fh).map_err(|_| Error::Overflow)?;
-        let mut handle = self.handles.try_remove(fh).ok_or(Error::NotFound)?;
-        self.db.with_write_tx(|tx| handle.flush(tx))?;
+        let handle = self.handles.try_remove(fh).ok_or(Error::NotFound)?;
+        // The owner is only given when the flock locks of the handle must be released.
+        if let Some(lock_owner) = lock_owner {
+            self.release_locks(handle.ino, lock_owner);
+        }
+        self.flush_buffer(handle.ino)?;
+        // The buffer is dropped with the last handle of the inode.
+        if !self.handles.iter().any(|(_, h)| h.ino == handle.ino) {
+            self.buffers.remove(&handle.ino);
+        }
+        self.delete_if_orphan(handle.ino)
+    }
+
+    fn forget_impl(&mut self, ino: u64, nlookup: u64) -> Result<()> {
+        if let Entry::Occupied(mut entry) = self.lookups.entry(ino) {
+            *entry.get_mut() = entry.get().saturating_sub(nlookup);
+            if *entry.get() == 0 {
+                entry.remove();
+                self.delete_if_orphan(ino)?;
+            }
+        }
         Ok(())
     }
 
@@ -288,32 +891,28 @@ impl FuseDriver {
         _lock_owner: Option<u64>,
     ) -> Result<Vec<u8>> {
         let fh = usize::try_from(fh).map_err(|_| Error::Overflow)?;
-        let handle = self.handles.get_mut(fh).ok_or(Error::NotFound)?;
+        if !self.handles.contains(fh) {
+            return Err(Error::NotFound);
+        }
 
         // If any data is left in the write buffer, flush it before reading.
-        if !handle.buffer_empty() {
-            self.db.with_write_tx(|tx| handle.flush(tx))?;
-        }
+        self.flush_buffer(ino)?;
 
-        self.db.with_read_tx(|tx| {
+        let (attr, buf) = self.db.with_read_tx(|tx| {
             let attr = queries::inode::lookup(tx, ino)?;
             let offset = offset as u64;
-            let remaining = attr.size - offset;
-            let cap = cmp::min(size as u64, remaining) as usize;
-            let mut buf = Vec::with_capacity(cap);
-
-            queries::block::iter_blocks_from(tx, ino, offset, |block| {
-                block.copy_into(&mut buf, offset);
-                Ok(buf.len() < buf.capacity())
-            })?;
-            assert!(buf.len() <= size as usize);
-            Ok(buf)
-        })
+            let remaining = attr.size.saturating_sub(offset);
+            let len = cmp::min(size as u64, remaining);
+            let buf = read_range(tx, ino, offset, len)?;
+            Ok((attr, buf))
+        })?;
+        self.touch_accessed(&attr)?;
+        Ok(buf)
     }
 
     fn write_impl(
         &mut self,
-        _req: RequestInfo,
+        req: RequestInfo,
         _ino: u64,
         fh: u64,
         offset: i64,
@@ -323,50 +922,593 @@ impl FuseDriver {
         _lock_owner: Option<u64>,
     ) -> Result<u32> {
         let fh = usize::try_from(fh).map_err(|_| Error::Overflow)?;
-        let handle = self.handles.get_mut(fh).ok_or(Error::NotFound)?;
+        self.record_version(fh)?;
+        let handle = self.handles.get(fh).ok_or(Error::NotFound)?;
+        let (ino, flags) = (handle.ino, handle.flags);
+        self.drop_setid_on_write(req, fh)?;
+        let storage = self.storage;
+        let buffer = self
+            .buffers
+            .entry(ino)
+            .or_insert_with(|| WriteBuffer::new(ino, storage));
+
+        let offset = if flags.append {
+            // Append writes always go to the end of the file, which might still be in the buffer.
+            let size = self.db.with_read_tx(|tx| queries::inode::lookup(tx, ino))?.size;
+            if buffer.is_empty() {
+                size
+            } else {
+                cmp::max(size, buffer.write_offset())
+            }
+        } else {
+            offset as u64
+        };
         let start_size = data.len();
-        let offset = offset as u64;
 
         // Detect if seek happened. If it did flush whatever is in the buffer
         // where it belongs and then update the offset where to write to.
-        if handle.write_offset() != offset {
+        if buffer.write_offset() != offset {
             log::debug!(
                 "seek occured, flushing, old offset = {}, new offset = {}",
-                handle.write_offset(),
+                buffer.write_offset(),
                 offset
             );
-            self.db.with_write_tx(|tx| handle.flush(tx))?;
-            handle.seek_to(offset);
+            self.db.with_write_tx(|tx| buffer.flush(tx))?;
+            buffer.seek_to(offset);
         }
 
         while !data.is_empty() {
-            if handle.buffer_full() {
-                log::debug!("handle buffer full, flushing");
-                self.db.with_write_tx(|tx| handle.flush(tx))?;
+            if buffer.is_full() {
+                log::debug!("write buffer full, flushing");
+                self.db.with_write_tx(|tx| buffer.flush(tx))?;
             }
-            let consumed = handle.consume_input(data);
+            let consumed = buffer.consume_input(data);
             data = &data[consumed..];
         }
+
+        if flags.sync {
+            self.db.with_write_tx(|tx| buffer.flush(tx))?;
+            self.db.sync()?;
+        }
         Ok(start_size as u32)
     }
 
-    fn flush_impl(&mut self, _req: RequestInfo, _ino: u64, fh: u64, _lock_owner: u64) -> Result<()> {
+    fn flush_impl(&mut self, _req: RequestInfo, _ino: u64, fh: u64, lock_owner: u64) -> Result<()> {
         let fh = usize::try_from(fh).map_err(|_| Error::Overflow)?;
-        let handle = self.handles.get_mut(fh).ok_or(Error::NotFound)?;
-        self.db.with_write_tx(|tx| handle.flush(tx))
+        let ino = self.handles.get(fh).ok_or(Error::NotFound)?.ino;
+        // Closing any descriptor of the file releases the POSIX locks of the process on it.
+        self.release_locks(ino, lock_owner);
+        self.flush_buffer(ino)
     }
 
-    fn rename_impl(
+    /// Release the locks of the owner on the inode and answer the requests that were waiting for them. The pending
+    /// requests of the owner itself fail, their lock would outlive the descriptor.
+    fn release_locks(&mut self, ino: u64, lock_owner: u64) {
+        let (granted, cancelled) = self.locks.release_owner(ino, lock_owner);
+        for reply in granted {
+            reply(Ok(()));
+        }
+        for reply in cancelled {
+            reply(Err(Error::Interrupted));
+        }
+    }
+
+    fn getlk_impl(
         &mut self,
         _req: RequestInfo,
-        parent: u64,
-        name: &OsStr,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+    ) -> Result<Lock> {
+        if start > end || (typ != libc::F_RDLCK && typ != libc::F_WRLCK) {
+            return Err(Error::InvalidArgument);
+        }
+        let lock = Lock {
+            owner: lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+        };
+        // Without a conflicting lock, the requested lock is reported as unlocked.
+        Ok(self.locks.conflict(ino, &lock).unwrap_or(Lock {
+            typ: libc::F_UNLCK,
+            ..lock
+        }))
+    }
+
+    /// Acquire or release a lock without waiting, fails with `WouldBlock` if it conflicts with the lock of another
+    /// owner.
+    fn setlk_impl(
+        &mut self,
+        _req: RequestInfo,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+    ) -> Result<()> {
+        if start > end || (typ != libc::F_RDLCK && typ != libc::F_WRLCK && typ != libc::F_UNLCK) {
+            return Err(Error::InvalidArgument);
+        }
+        let lock = Lock {
+            owner: lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+        };
+        for reply in self.locks.set(ino, lock)? {
+            reply(Ok(()));
+        }
+        Ok(())
+    }
+
+    /// Acquire a lock, waiting until the conflicting locks of other owners are released. The reply is called once the
+    /// lock is granted, or with an error if waiting would deadlock or the owner releases its locks first.
+    fn setlkw_impl(
+        &mut self,
+        req: RequestInfo,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: LockReply,
+    ) {
+        match self.setlk_impl(req, ino, fh, lock_owner, start, end, typ, pid) {
+            Err(Error::WouldBlock) => {
+                let lock = Lock {
+                    owner: lock_owner,
+                    start,
+                    end,
+                    typ,
+                    pid,
+                };
+                if let Err(reply) = self.locks.wait(ino, lock, reply) {
+                    reply(Err(Error::Deadlock));
+                }
+            }
+            res => reply(res),
+        }
+    }
+
+    fn fallocate_impl(
+        &mut self,
+        req: RequestInfo,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+    ) -> Result<()> {
+        const SUPPORTED: i32 = libc::FALLOC_FL_KEEP_SIZE | libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_ZERO_RANGE;
+        if mode & !SUPPORTED != 0 {
+            return Err(Error::NotSupported);
+        }
+        let punch_hole = mode & libc::FALLOC_FL_PUNCH_HOLE != 0;
+        let zero_range = mode & libc::FALLOC_FL_ZERO_RANGE != 0;
+        let keep_size = mode & libc::FALLOC_FL_KEEP_SIZE != 0;
+        // Punching a hole never changes the size, the flag must be explicit like on other filesystems.
+        if punch_hole && (!keep_size || zero_range) {
+            return Err(Error::NotSupported);
+        }
+        if offset < 0 || length <= 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let start = offset as u64;
+        let end = start.checked_add(length as u64).ok_or(Error::Overflow)?;
+        let fh = usize::try_from(fh).map_err(|_| Error::Overflow)?;
+        if punch_hole || zero_range {
+            self.record_version(fh)?;
+        }
+
+        // Buffered writes must not land over the range after it is deallocated.
+        self.flush_buffer(ino)?;
+        self.drop_setid_on_write(req, fh)?;
+
+        self.db.with_write_tx(|tx| {
+            let attr = queries::inode::lookup(tx, ino)?;
+            if attr.kind != fuser::FileType::RegularFile {
+                return Err(Error::InvalidArgument);
+            }
+            let extend = !keep_size && end > attr.size;
+            if punch_hole || zero_range {
+                Self::zero_range(tx, ino, start, cmp::min(end, attr.size), self.storage)?;
+            }
+            // Blocks are allocated lazily, so preallocation only needs to extend the size.
+            if extend {
+                queries::inode::set_attr(tx, ino, "size", end)?;
+            }
+            queries::inode::update_blocks(tx, ino)?;
+            if punch_hole || zero_range || extend {
+                touch_modified(tx, ino, TimeSpec::now())?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Copy a range of data between files. When both offsets are aligned on blocks, whole blocks are shared with the
+    /// source instead of being copied, they are copied on write later.
+    fn copy_file_range_impl(
+        &mut self,
+        req: RequestInfo,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+    ) -> Result<u32> {
+        if flags != 0 || offset_in < 0 || offset_out < 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let fh_in = usize::try_from(fh_in).map_err(|_| Error::Overflow)?;
+        if !self.handles.contains(fh_in) {
+            return Err(Error::NotFound);
+        }
+        let fh_out = usize::try_from(fh_out).map_err(|_| Error::Overflow)?;
+        let (offset_in, offset_out) = (offset_in as u64, offset_out as u64);
+
+        // Buffered writes of both files must be in the block table before sharing or reading it.
+        self.flush_buffer(ino_in)?;
+        self.flush_buffer(ino_out)?;
+
+        let len = self.db.with_read_tx(|tx| {
+            let src = queries::inode::lookup(tx, ino_in)?;
+            let dst = queries::inode::lookup(tx, ino_out)?;
+            if src.kind != fuser::FileType::RegularFile || dst.kind != fuser::FileType::RegularFile {
+                return Err(Error::InvalidArgument);
+            }
+            Ok(cmp::min(len, src.size.saturating_sub(offset_in)).min(MAX_COPY))
+        })?;
+        // Nothing to copy, the destination is left as it is.
+        if len == 0 {
+            return Ok(0);
+        }
+        let end_out = offset_out.checked_add(len).ok_or(Error::Overflow)?;
+        if ino_in == ino_out && offset_in < end_out && offset_out < offset_in + len {
+            return Err(Error::InvalidArgument);
+        }
+        self.record_version(fh_out)?;
+        self.drop_setid_on_write(req, fh_out)?;
+
+        let storage = self.storage;
+        self.db.with_write_tx(|tx| {
+            let src = queries::inode::lookup(tx, ino_in)?;
+            let dst = queries::inode::lookup(tx, ino_out)?;
+
+            let mut shared = 0;
+            if offset_in % BLOCK_SIZE == 0 && offset_out % BLOCK_SIZE == 0 {
+                // The last partial block can only be shared if nothing follows it in either file.
+                shared = if offset_in + len == src.size && end_out >= dst.size {
+                    len
+                } else {
+                    len / BLOCK_SIZE * BLOCK_SIZE
+                };
+                queries::block::share_blocks(
+                    tx,
+                    ino_in,
+                    offset_in / BLOCK_SIZE,
+                    ino_out,
+                    offset_out / BLOCK_SIZE,
+                    shared.div_ceil(BLOCK_SIZE),
+                )?;
+            }
+
+            if shared < len {
+                let mut buffer = WriteBuffer::new(ino_out, storage);
+                buffer.seek_to(offset_out + shared);
+                let mut copied = shared;
+                while copied < len {
+                    let chunk = cmp::min(len - copied, COPY_CHUNK);
+                    let data = read_range(tx, ino_in, offset_in + copied, chunk)?;
+                    let mut data = &data[..];
+                    while !data.is_empty() {
+                        if buffer.is_full() {
+                            buffer.flush(tx)?;
+                        }
+                        let consumed = buffer.consume_input(data);
+                        data = &data[consumed..];
+                    }
+                    copied += chunk;
+                }
+                buffer.flush(tx)?;
+            }
+
+            if end_out > dst.size {
+                queries::inode::set_attr(tx, ino_out, "size", end_out)?;
+            }
+            queries::inode::update_blocks(tx, ino_out)?;
+            touch_modified(tx, ino_out, TimeSpec::now())?;
+            Ok(len as u32)
+        })
+    }
+
+    fn lseek_impl(&mut self, _req: RequestInfo, ino: u64, _fh: u64, offset: i64, whence: i32) -> Result<i64> {
+        // The kernel handles the other values of whence by itself.
+        if whence != libc::SEEK_DATA && whence != libc::SEEK_HOLE {
+            return Err(Error::InvalidArgument);
+        }
+        if offset < 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let offset = offset as u64;
+
+        // Buffered data must be in the block table to be found.
+        self.flush_buffer(ino)?;
+
+        self.db.with_read_tx(|tx| {
+            let attr = queries::inode::lookup(tx, ino)?;
+            if offset >= attr.size {
+                return Err(Error::NoSuchAddress);
+            }
+            let bno = Block::offset_to_bno(offset);
+            let found = if whence == libc::SEEK_DATA {
+                match queries::block::next_data(tx, ino, bno)? {
+                    Some(data_bno) => cmp::max(offset, data_bno * BLOCK_SIZE),
+                    None => return Err(Error::NoSuchAddress),
+                }
+            } else {
+                // There is always an implicit hole at the end of the file.
+                let hole_bno = queries::block::next_hole(tx, ino, bno)?;
+                cmp::min(cmp::max(offset, hole_bno * BLOCK_SIZE), attr.size)
+            };
+            if found >= attr.size && whence == libc::SEEK_DATA {
+                return Err(Error::NoSuchAddress);
+            }
+            i64::try_from(found).map_err(|_| Error::Overflow)
+        })
+    }
+
+    /// Zero the data in the range `start..end`. Blocks entirely inside the range are removed since holes read as
+    /// zeros, blocks partially inside the range are zeroed in place.
+    fn zero_range(tx: &mut rusqlite::Transaction, ino: u64, start: u64, end: u64, storage: Storage) -> Result<()> {
+        if start >= end {
+            return Ok(());
+        }
+        let first_whole = start.div_ceil(BLOCK_SIZE);
+        let last_whole = end / BLOCK_SIZE;
+        if first_whole < last_whole {
+            queries::block::remove_block_range(tx, ino, first_whole, last_whole)?;
+        }
+
+        let mut partial = Vec::new();
+        queries::block::iter_blocks_from(tx, ino, start, |mut block| {
+            if block.start_offset() >= end {
+                return Ok(false);
+            }
+            block.zero_range(start, end);
+            partial.push(block);
+            Ok(true)
+        })?;
+        for block in partial {
+            queries::block::update(tx, &block, storage)?;
+        }
+        Ok(())
+    }
+
+    fn fsync_impl(&mut self, _req: RequestInfo, ino: u64, fh: u64, _datasync: bool) -> Result<()> {
+        let fh = usize::try_from(fh).map_err(|_| Error::Overflow)?;
+        if !self.handles.contains(fh) {
+            return Err(Error::NotFound);
+        }
+        self.flush_buffer(ino)?;
+        self.db.sync()
+    }
+
+    fn fsyncdir_impl(&mut self, _req: RequestInfo, _ino: u64, _fh: u64, _datasync: bool) -> Result<()> {
+        // Directory operations are committed immediately, they only need to be synced.
+        self.db.sync()
+    }
+
+    fn rename_impl(
+        &mut self,
+        req: RequestInfo,
+        parent: u64,
+        name: &OsStr,
         newparent: u64,
         newname: &OsStr,
-        _flags: u32,
+        flags: u32,
     ) -> Result<()> {
-        self.db
-            .with_write_tx(|tx| queries::dir_entry::rename(tx, parent, name, newparent, newname))
+        let noreplace = flags & libc::RENAME_NOREPLACE != 0;
+        let exchange = flags & libc::RENAME_EXCHANGE != 0;
+        if flags & !(libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE) != 0 || (noreplace && exchange) {
+            return Err(Error::InvalidArgument);
+        }
+        if !exchange {
+            self.flush_entry(newparent, newname)?;
+        }
+
+        self.db.with_write_tx(|tx| {
+            let parent_attr = Self::check_dir_write(tx, self.mount_owner, req, parent)?;
+            let newparent_attr = Self::check_dir_write(tx, self.mount_owner, req, newparent)?;
+
+            let ino = queries::dir_entry::lookup(tx, parent, name)?;
+            let attr = queries::inode::lookup(tx, ino)?;
+            check_sticky(&parent_attr, &attr, req)?;
+            let target = match queries::dir_entry::lookup(tx, newparent, newname) {
+                Ok(target_ino) => {
+                    let target_attr = queries::inode::lookup(tx, target_ino)?;
+                    check_sticky(&newparent_attr, &target_attr, req)?;
+                    Some(target_attr)
+                }
+                Err(Error::NotFound) => None,
+                Err(e) => return Err(e),
+            };
+
+            let is_dir = attr.kind == fuser::FileType::Directory;
+            if parent != newparent {
+                // Moving a directory to another parent updates its '..' entry.
+                if is_dir {
+                    check_inode_access(tx, &attr, req, libc::W_OK)?;
+                    Self::check_not_ancestor(tx, ino, newparent)?;
+                }
+                if exchange {
+                    if let Some(target_attr) = target.as_ref().filter(|t| t.kind == fuser::FileType::Directory) {
+                        check_inode_access(tx, target_attr, req, libc::W_OK)?;
+                        Self::check_not_ancestor(tx, target_attr.ino, parent)?;
+                    }
+                }
+            }
+
+            if exchange {
+                let target_attr = target.ok_or(Error::NotFound)?;
+                queries::dir_entry::set_ino(tx, parent, name, target_attr.ino)?;
+                queries::dir_entry::set_ino(tx, newparent, newname, ino)?;
+                // The '..' entries of exchanged directories now point to the other parent.
+                if parent != newparent {
+                    if is_dir {
+                        queries::inode::add_nlink(tx, parent, -1)?;
+                        queries::inode::add_nlink(tx, newparent, 1)?;
+                    }
+                    if target_attr.kind == fuser::FileType::Directory {
+                        queries::inode::add_nlink(tx, newparent, -1)?;
+                        queries::inode::add_nlink(tx, parent, 1)?;
+                    }
+                }
+                let now = TimeSpec::now();
+                queries::inode::set_time(tx, ino, "ctime", now)?;
+                queries::inode::set_time(tx, target_attr.ino, "ctime", now)?;
+                touch_modified(tx, parent, now)?;
+                return touch_modified(tx, newparent, now);
+            }
+
+            if let Some(mut target_attr) = target {
+                if noreplace {
+                    return Err(Error::AlreadyExists);
+                }
+                // Both names are links to the same inode, there is nothing to do.
+                if target_attr.ino == ino {
+                    return Ok(());
+                }
+                match (is_dir, target_attr.kind == fuser::FileType::Directory) {
+                    (true, false) => return Err(Error::NotADirectory),
+                    (false, true) => return Err(Error::IsADirectory),
+                    (true, true) if !queries::dir_entry::is_dir_empty(tx, target_attr.ino)? => {
+                        return Err(Error::NotEmpty)
+                    }
+                    _ => {}
+                }
+                let in_use = inode_in_use(&self.handles, &self.lookups, target_attr.ino);
+                Self::drop_link(tx, newparent, newname, &mut target_attr, in_use)?;
+            }
+
+            if is_dir && parent != newparent {
+                queries::inode::add_nlink(tx, parent, -1)?;
+                queries::inode::add_nlink(tx, newparent, 1)?;
+            }
+            queries::dir_entry::rename(tx, parent, name, newparent, newname)?;
+            let now = TimeSpec::now();
+            queries::inode::set_time(tx, ino, "ctime", now)?;
+            touch_modified(tx, parent, now)?;
+            touch_modified(tx, newparent, now)
+        })
+    }
+
+    fn statfs_impl(&mut self, _req: RequestInfo, _ino: u64) -> Result<StatFs> {
+        let usage = self.db.usage()?;
+        let inodes = self.db.with_read_tx(queries::inode::count)?;
+        Ok(StatFs::new(&usage, inodes))
+    }
+
+    fn setxattr_impl(
+        &mut self,
+        req: RequestInfo,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+    ) -> Result<()> {
+        let create = flags & libc::XATTR_CREATE != 0;
+        let replace = flags & libc::XATTR_REPLACE != 0;
+        if create && replace {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.db.with_write_tx(|tx| {
+            let attr = self.mount_owner.lookup(tx, ino)?;
+            check_xattr_access(tx, &attr, req, name, libc::W_OK)?;
+            let exists = queries::xattr::exists(tx, ino, name)?;
+            if create && exists {
+                return Err(Error::AlreadyExists);
+            }
+            if replace && !exists {
+                return Err(Error::NoData);
+            }
+
+            if name == ACL_ACCESS {
+                // The permission bits reflect the ACL. If the ACL does not say more than the permission bits, there
+                // is no need to store it.
+                let acl = Acl::parse(value)?;
+                let perm = (attr.perm & !0o777) | acl.mode();
+                queries::inode::set_attr(tx, ino, "perm", perm)?;
+                queries::inode::set_time(tx, ino, "ctime", TimeSpec::now())?;
+                if acl.is_minimal() {
+                    if exists {
+                        queries::xattr::remove(tx, ino, name)?;
+                    }
+                    return Ok(());
+                }
+            } else if name == ACL_DEFAULT {
+                if attr.kind != fuser::FileType::Directory {
+                    return Err(Error::PermissionDenied);
+                }
+                Acl::parse(value)?;
+            }
+
+            queries::xattr::set(tx, ino, name, value)?;
+            queries::inode::set_time(tx, ino, "ctime", TimeSpec::now())
+        })
+    }
+
+    fn getxattr_impl(&mut self, req: RequestInfo, ino: u64, name: &OsStr, size: u32) -> Result<XattrReply> {
+        let value = self.db.with_read_tx(|tx| {
+            let attr = self.mount_owner.lookup(tx, ino)?;
+            check_xattr_access(tx, &attr, req, name, libc::R_OK)?;
+            match queries::xattr::get(tx, ino, name) {
+                Err(Error::NotFound) => Err(Error::NoData),
+                res => res,
+            }
+        })?;
+        XattrReply::new(value, size)
+    }
+
+    fn listxattr_impl(&mut self, _req: RequestInfo, ino: u64, size: u32) -> Result<XattrReply> {
+        let names = self.db.with_read_tx(|tx| {
+            queries::inode::lookup(tx, ino)?;
+            queries::xattr::list(tx, ino)
+        })?;
+        // The list is a sequence of NUL terminated names.
+        let mut data = Vec::new();
+        for name in names {
+            data.extend_from_slice(&name);
+            data.push(0);
+        }
+        XattrReply::new(data, size)
+    }
+
+    fn removexattr_impl(&mut self, req: RequestInfo, ino: u64, name: &OsStr) -> Result<()> {
+        self.db.with_write_tx(|tx| {
+            let attr = self.mount_owner.lookup(tx, ino)?;
+            check_xattr_access(tx, &attr, req, name, libc::W_OK)?;
+            match queries::xattr::remove(tx, ino, name) {
+                Err(Error::NotFound) => return Err(Error::NoData),
+                res => res?,
+            }
+            queries::inode::set_time(tx, ino, "ctime", TimeSpec::now())
+        })
     }
 }
 
@@ -377,7 +1519,19 @@ impl fuser::Filesystem for FuseDriver {
         config: &mut fuser::KernelConfig,
     ) -> std::result::Result<(), libc::c_int> {
         config.set_max_write(128 * 1024).expect("unable to set max_write");
-        match self.ensure_root_exists() {
+        // Let open handle O_TRUNC instead of sending a separate setattr.
+        if let Err(e) = config.add_capabilities(fuser::consts::FUSE_ATOMIC_O_TRUNC) {
+            log::warn!("kernel does not support atomic O_TRUNC: {:#x}", e);
+        }
+        // Send fcntl and flock locks to getlk/setlk so that they are managed by the driver.
+        if let Err(e) = config.add_capabilities(fuser::consts::FUSE_POSIX_LOCKS | fuser::consts::FUSE_FLOCK_LOCKS) {
+            log::warn!("kernel does not support remote locks: {:#x}", e);
+        }
+        match self
+            .ensure_root_exists()
+            .and_then(|_| self.remove_orphans())
+            .and_then(|_| self.prune_versions())
+        {
             Ok(()) => Ok(()),
             Err(e) => {
                 log::error!("init error: {}", e);
@@ -386,13 +1540,37 @@ impl fuser::Filesystem for FuseDriver {
         }
     }
 
+    fn forget(&mut self, _req: &fuser::Request<'_>, ino: u64, nlookup: u64) {
+        log::trace!("forget(ino={}, nlookup={})", ino, nlookup);
+        let res = self.forget_impl(ino, nlookup);
+        log::trace!("forget: {:?}", res);
+
+        if let Err(e) = res {
+            log::error!("forget error: {}", e);
+        }
+    }
+
     fn lookup(&mut self, req: &fuser::Request<'_>, parent: u64, name: &std::ffi::OsStr, reply: fuser::ReplyEntry) {
         log::trace!("lookup(parent={}, name={:?})", parent, name.to_string_lossy());
         let res = self.lookup_impl(req.into(), parent, name);
         log::trace!("lookup: {:?}", res);
 
         match res {
-            Ok(attr) => reply.entry(&DURATION, &attr, 0),
+            Ok(attr) => {
+                self.add_lookup(attr.ino);
+                reply.entry(&DURATION, &attr, 0)
+            }
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn access(&mut self, req: &fuser::Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        log::trace!("access(ino={}, mask={:#o})", ino, mask);
+        let res = self.access_impl(req.into(), ino, mask);
+        log::trace!("access: {:?}", res);
+
+        match res {
+            Ok(_) => reply.ok(),
             Err(e) => reply.error(e.errno()),
         }
     }
@@ -441,8 +1619,8 @@ impl fuser::Filesystem for FuseDriver {
             uid,
             gid,
             size,
-            atime.map(Into::into),
-            mtime.map(Into::into),
+            atime,
+            mtime,
             ctime.map(Into::into),
             fh,
             crtime.map(Into::into),
@@ -480,7 +1658,47 @@ impl fuser::Filesystem for FuseDriver {
         log::trace!("mknod: {:?}", res);
 
         match res {
-            Ok(attr) => reply.entry(&DURATION, &attr, 0),
+            Ok(attr) => {
+                self.add_lookup(attr.ino);
+                reply.entry(&DURATION, &attr, 0)
+            }
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: fuser::ReplyEntry,
+    ) {
+        log::trace!(
+            "symlink(parent={}, link_name={:?}, target={:?})",
+            parent,
+            link_name.to_string_lossy(),
+            target
+        );
+        let res = self.symlink_impl(req.into(), parent, link_name, target);
+        log::trace!("symlink: {:?}", res);
+
+        match res {
+            Ok(attr) => {
+                self.add_lookup(attr.ino);
+                reply.entry(&DURATION, &attr, 0)
+            }
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn readlink(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        log::trace!("readlink(ino={})", ino);
+        let res = self.readlink_impl(req.into(), ino);
+        log::trace!("readlink: {:?}", res.as_ref().map(|t| OsStr::from_bytes(t)));
+
+        match res {
+            Ok(target) => reply.data(&target),
             Err(e) => reply.error(e.errno()),
         }
     }
@@ -491,7 +1709,10 @@ impl fuser::Filesystem for FuseDriver {
         log::trace!("link: {:?}", res);
 
         match res {
-            Ok(attr) => reply.entry(&DURATION, &attr, 0),
+            Ok(attr) => {
+                self.add_lookup(attr.ino);
+                reply.entry(&DURATION, &attr, 0)
+            }
             Err(e) => reply.error(e.errno()),
         }
     }
@@ -527,7 +1748,10 @@ impl fuser::Filesystem for FuseDriver {
         log::trace!("mkdir: {:?}", res);
 
         match res {
-            Ok(attr) => reply.entry(&DURATION, &attr, 0),
+            Ok(attr) => {
+                self.add_lookup(attr.ino);
+                reply.entry(&DURATION, &attr, 0)
+            }
             Err(e) => reply.error(e.errno()),
         }
     }
@@ -545,8 +1769,9 @@ impl fuser::Filesystem for FuseDriver {
 
     fn readdir(&mut self, req: &fuser::Request<'_>, ino: u64, fh: u64, offset: i64, mut reply: fuser::ReplyDirectory) {
         log::trace!("readdir(ino={}, fh={}, offset={})", ino, fh, offset);
+        // add() returns true when the reply buffer is full.
         let res = self.readdir_impl(req.into(), ino, fh, offset, |entry| {
-            reply.add(entry.ino, entry.offset, entry.kind, entry.name)
+            !reply.add(entry.ino, entry.offset, entry.kind, entry.name)
         });
         log::trace!("readdir: {:?}", res);
 
@@ -568,6 +1793,37 @@ impl fuser::Filesystem for FuseDriver {
         }
     }
 
+    fn create(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: fuser::ReplyCreate,
+    ) {
+        let flags = OpenFlags::from(flags);
+        log::trace!(
+            "create(parent={}, name={:?}, mode={}, umask={:#o}, flags={:?})",
+            parent,
+            name.to_string_lossy(),
+            mode,
+            umask,
+            flags
+        );
+        let res = self.create_impl(req.into(), parent, name, mode, umask, flags);
+        log::trace!("create: {:?}", res);
+
+        match res {
+            Ok((attr, fh, flags)) => {
+                self.add_lookup(attr.ino);
+                reply.created(&DURATION, &attr, 0, fh, flags)
+            }
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
     fn release(
         &mut self,
         req: &fuser::Request<'_>,
@@ -643,44 +1899,316 @@ impl fuser::Filesystem for FuseDriver {
         }
     }
 
-    fn rename(
+    fn fallocate(
         &mut self,
         req: &fuser::Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        newparent: u64,
-        newname: &OsStr,
-        flags: u32,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
         reply: fuser::ReplyEmpty,
     ) {
         log::trace!(
-            "rename(parent={}, name={:?}, newparent={}, newname={:?}",
-            parent,
-            name,
-            newparent,
-            newname
+            "fallocate(ino={}, fh={}, offset={}, length={}, mode={:#x})",
+            ino,
+            fh,
+            offset,
+            length,
+            mode
         );
-        let res = self.rename_impl(req.into(), parent, name, newparent, newname, flags);
-        log::trace!("rename: {:?}", res);
+        let res = self.fallocate_impl(req.into(), ino, fh, offset, length, mode);
+        log::trace!("fallocate: {:?}", res);
 
         match res {
             Ok(_) => reply.ok(),
             Err(e) => reply.error(e.errno()),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::ffi::OsStr;
+    fn copy_file_range(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: fuser::ReplyWrite,
+    ) {
+        log::trace!(
+            "copy_file_range(ino_in={}, fh_in={}, offset_in={}, ino_out={}, fh_out={}, offset_out={}, len={}, flags={:#x})",
+            ino_in,
+            fh_in,
+            offset_in,
+            ino_out,
+            fh_out,
+            offset_out,
+            len,
+            flags
+        );
+        let res = self.copy_file_range_impl(
+            req.into(),
+            ino_in,
+            fh_in,
+            offset_in,
+            ino_out,
+            fh_out,
+            offset_out,
+            len,
+            flags,
+        );
+        log::trace!("copy_file_range: {:?}", res);
 
-    use super::{attr::FileAttrBuilder, FuseDriver, OpenFlags, RequestInfo};
-    use crate::{
-        database::DatabaseOps,
-        errors::Error,
-        queries::{self, block::Compression},
-        types::FileType,
+        match res {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn lseek(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: fuser::ReplyLseek,
+    ) {
+        log::trace!("lseek(ino={}, fh={}, offset={}, whence={})", ino, fh, offset, whence);
+        let res = self.lseek_impl(req.into(), ino, fh, offset, whence);
+        log::trace!("lseek: {:?}", res);
+
+        match res {
+            Ok(offset) => reply.offset(offset),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn fsync(&mut self, req: &fuser::Request<'_>, ino: u64, fh: u64, datasync: bool, reply: fuser::ReplyEmpty) {
+        log::trace!("fsync(ino={}, fh={}, datasync={})", ino, fh, datasync);
+        let res = self.fsync_impl(req.into(), ino, fh, datasync);
+        log::trace!("fsync: {:?}", res);
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn fsyncdir(&mut self, req: &fuser::Request<'_>, ino: u64, fh: u64, datasync: bool, reply: fuser::ReplyEmpty) {
+        log::trace!("fsyncdir(ino={}, fh={}, datasync={})", ino, fh, datasync);
+        let res = self.fsyncdir_impl(req.into(), ino, fh, datasync);
+        log::trace!("fsyncdir: {:?}", res);
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        req: &fuser::Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        log::trace!(
+            "rename(parent={}, name={:?}, newparent={}, newname={:?}, flags={:#x})",
+            parent,
+            name,
+            newparent,
+            newname,
+            flags
+        );
+        let res = self.rename_impl(req.into(), parent, name, newparent, newname, flags);
+        log::trace!("rename: {:?}", res);
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn getlk(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: fuser::ReplyLock,
+    ) {
+        log::trace!(
+            "getlk(ino={}, fh={}, lock_owner={}, start={}, end={}, typ={})",
+            ino,
+            fh,
+            lock_owner,
+            start,
+            end,
+            typ
+        );
+        let res = self.getlk_impl(req.into(), ino, fh, lock_owner, start, end, typ, pid);
+        log::trace!("getlk: {:?}", res);
+
+        match res {
+            Ok(lock) => reply.locked(lock.start, lock.end, lock.typ, lock.pid),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn setlk(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        log::trace!(
+            "setlk(ino={}, fh={}, lock_owner={}, start={}, end={}, typ={}, sleep={})",
+            ino,
+            fh,
+            lock_owner,
+            start,
+            end,
+            typ,
+            sleep
+        );
+        if sleep {
+            // The reply is kept until the lock is granted.
+            let reply = Box::new(move |res: Result<()>| {
+                log::trace!("setlk: {:?}", res);
+                match res {
+                    Ok(_) => reply.ok(),
+                    Err(e) => reply.error(e.errno()),
+                }
+            });
+            return self.setlkw_impl(req.into(), ino, fh, lock_owner, start, end, typ, pid, reply);
+        }
+        let res = self.setlk_impl(req.into(), ino, fh, lock_owner, start, end, typ, pid);
+        log::trace!("setlk: {:?}", res);
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn statfs(&mut self, req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
+        log::trace!("statfs(ino={})", ino);
+        let res = self.statfs_impl(req.into(), ino);
+        log::trace!("statfs: {:?}", res);
+
+        match res {
+            Ok(st) => reply.statfs(
+                st.blocks, st.bfree, st.bavail, st.files, st.ffree, st.bsize, st.namelen, st.frsize,
+            ),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        log::trace!(
+            "setxattr(ino={}, name={:?}, value_len={}, flags={:#x})",
+            ino,
+            name,
+            value.len(),
+            flags
+        );
+        let res = self.setxattr_impl(req.into(), ino, name, value, flags, position);
+        log::trace!("setxattr: {:?}", res);
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn getxattr(&mut self, req: &fuser::Request<'_>, ino: u64, name: &OsStr, size: u32, reply: fuser::ReplyXattr) {
+        log::trace!("getxattr(ino={}, name={:?}, size={})", ino, name, size);
+        let res = self.getxattr_impl(req.into(), ino, name, size);
+        log::trace!("getxattr: {:?}", res);
+
+        match res {
+            Ok(XattrReply::Size(size)) => reply.size(size),
+            Ok(XattrReply::Data(data)) => reply.data(&data),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn listxattr(&mut self, req: &fuser::Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
+        log::trace!("listxattr(ino={}, size={})", ino, size);
+        let res = self.listxattr_impl(req.into(), ino, size);
+        log::trace!("listxattr: {:?}", res);
+
+        match res {
+            Ok(XattrReply::Size(size)) => reply.size(size),
+            Ok(XattrReply::Data(data)) => reply.data(&data),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn removexattr(&mut self, req: &fuser::Request<'_>, ino: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        log::trace!("removexattr(ino={}, name={:?})", ino, name);
+        let res = self.removexattr_impl(req.into(), ino, name);
+        log::trace!("removexattr: {:?}", res);
+
+        match res {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsStr,
+        path::Path,
+        sync::mpsc,
+        time::{Duration, SystemTime},
     };
+
+    use super::{
+        attr::FileAttrBuilder, AtimePolicy, FuseDriver, LockReply, OpenFlags, RequestInfo, StatFs, XattrReply,
+    };
+    use crate::{
+        database::{DatabaseOps, DatabaseUsage},
+        errors::{Error, Result},
+        queries::{
+            self,
+            block::{Compression, BLOCK_SIZE},
+            inode::POSIX_BLOCK_SIZE,
+        },
+        time::TimeSpec,
+        types::FileType,
+    };
+    use fuser::TimeOrNow;
     use rand::{Rng, RngCore};
     use sha1::{Digest, Sha1};
     use test_log::test;
@@ -760,6 +2288,46 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_create() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let mode = libc::S_IFREG | 0o666;
+        let flags = OpenFlags::from(libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL);
+
+        let (attr, fh, _) = driver.create_impl(req, 1, OsStr::new("foo.txt"), mode, 0o022, flags)?;
+        assert_eq!(attr.perm, 0o644);
+        assert_eq!(attr.kind, fuser::FileType::RegularFile);
+        let lookup_attr = driver.lookup_impl(req, 1, OsStr::new("foo.txt"))?;
+        assert_eq!(lookup_attr.ino, attr.ino);
+
+        driver.write_impl(req, attr.ino, fh, 0, b"hello world", 0, 0, None)?;
+        driver.release_impl(req, attr.ino, fh, 0, None, true)?;
+
+        // O_EXCL fails if the file exists
+        let res = driver.create_impl(req, 1, OsStr::new("foo.txt"), mode, 0o022, flags);
+        assert_eq!(res, Err(Error::AlreadyExists));
+
+        // Without O_EXCL the existing file is opened
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let (existing, fh, _) = driver.create_impl(req, 1, OsStr::new("foo.txt"), mode, 0o022, flags)?;
+        assert_eq!(existing.ino, attr.ino);
+        assert_eq!(existing.size, 11);
+        driver.release_impl(req, attr.ino, fh, 0, None, true)?;
+
+        // O_TRUNC drops the data
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC);
+        let (existing, fh, _) = driver.create_impl(req, 1, OsStr::new("foo.txt"), mode, 0o022, flags)?;
+        assert_eq!(existing.size, 0);
+        let data = driver.read_impl(req, attr.ino, fh, 0, 100, 0, None)?;
+        assert!(data.is_empty());
+
+        Ok(())
+    }
+
     #[test]
     fn test_link_unlink() -> anyhow::Result<()> {
         let db = DatabaseOps::open_in_memory()?;
@@ -775,7 +2343,13 @@ mod tests {
             queries::inode::create(tx, &mut root_dir)?;
             queries::inode::create(tx, &mut node)?;
             queries::dir_entry::create(tx, root_dir.ino, OsStr::new("foo.txt"), node.ino)?;
-            queries::block::create(tx, node.ino, 0, b"hello world!", queries::block::Compression::Zstd)?;
+            queries::block::create(
+                tx,
+                node.ino,
+                0,
+                b"hello world!",
+                queries::block::Compression::Zstd.into(),
+            )?;
             Ok(())
         })?;
 
@@ -817,6 +2391,47 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_symlink_readlink() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+
+        let mut root_dir = FileAttrBuilder::new_directory().build();
+
+        driver.db.with_write_tx(|tx| {
+            queries::inode::create(tx, &mut root_dir)?;
+            Ok(())
+        })?;
+
+        let attr = driver.symlink_impl(
+            RequestInfo::default(),
+            root_dir.ino,
+            OsStr::new("link"),
+            Path::new("../some/target.txt"),
+        )?;
+        assert_eq!(attr.kind, fuser::FileType::Symlink);
+        assert_eq!(attr.size, 18);
+
+        let lookup_attr = driver.lookup_impl(RequestInfo::default(), root_dir.ino, OsStr::new("link"))?;
+        assert_eq!(lookup_attr.ino, attr.ino);
+        assert_eq!(lookup_attr.kind, fuser::FileType::Symlink);
+        assert_eq!(lookup_attr.size, 18);
+
+        let target = driver.readlink_impl(RequestInfo::default(), attr.ino)?;
+        assert_eq!(target, b"../some/target.txt");
+
+        // readlink on something that is not a symlink
+        let res = driver.readlink_impl(RequestInfo::default(), root_dir.ino);
+        assert_eq!(res, Err(Error::InvalidArgument));
+
+        // Target is removed with the inode
+        driver.unlink_impl(RequestInfo::default(), root_dir.ino, OsStr::new("link"))?;
+        let res = driver.db.with_read_tx(|tx| queries::symlink::lookup(tx, attr.ino));
+        assert_eq!(res, Err(Error::NotFound));
+
+        Ok(())
+    }
+
     #[test]
     fn test_mkdir() -> anyhow::Result<()> {
         let db = DatabaseOps::open_in_memory()?;
@@ -906,42 +2521,2042 @@ mod tests {
     }
 
     #[test]
-    fn test_rename() -> anyhow::Result<()> {
+    fn test_open_truncate_append() -> anyhow::Result<()> {
         let db = DatabaseOps::open_in_memory()?;
         let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let flags = OpenFlags::from(libc::O_WRONLY | libc::O_CREAT);
+        let (attr, fh, _) = driver.create_impl(req, 1, OsStr::new("log.txt"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.write_impl(req, attr.ino, fh, 0, &[1u8; BLOCK_SIZE as usize + 100], 0, 0, None)?;
+        driver.release_impl(req, attr.ino, fh, 0, None, true)?;
+        assert_eq!(count_blocks(&mut driver, attr.ino)?, 2);
+
+        // O_TRUNC drops the existing blocks
+        let (fh, _) = driver.open_impl(req, attr.ino, OpenFlags::from(libc::O_WRONLY | libc::O_TRUNC))?;
+        assert_eq!(driver.getattr_impl(req, attr.ino)?.size, 0);
+        assert!(count_blocks(&mut driver, attr.ino)? <= 1);
+        driver.write_impl(req, attr.ino, fh, 0, b"abc", 0, 0, None)?;
+        driver.release_impl(req, attr.ino, fh, 0, None, true)?;
+
+        // Two append handles interleaving their writes, the kernel supplied offsets are ignored.
+        let append = OpenFlags::from(libc::O_WRONLY | libc::O_APPEND);
+        let (fh1, _) = driver.open_impl(req, attr.ino, append)?;
+        let (fh2, _) = driver.open_impl(req, attr.ino, append)?;
+        driver.write_impl(req, attr.ino, fh1, 0, b"def", 0, 0, None)?;
+        driver.write_impl(req, attr.ino, fh2, 0, b"ghi", 0, 0, None)?;
+        driver.write_impl(req, attr.ino, fh1, 0, b"jkl", 0, 0, None)?;
+        driver.release_impl(req, attr.ino, fh1, 0, None, true)?;
+        driver.release_impl(req, attr.ino, fh2, 0, None, true)?;
+
+        let (fh, _) = driver.open_impl(req, attr.ino, OpenFlags::from(libc::O_RDONLY))?;
+        let data = driver.read_impl(req, attr.ino, fh, 0, 100, 0, None)?;
+        assert_eq!(data, b"abcdefghijkl");
+        driver.release_impl(req, attr.ino, fh, 0, None, true)?;
 
-        let mut root_dir = FileAttrBuilder::new_directory().build();
-        let mut node = FileAttrBuilder::new_node(FileType::RegularFile)
-            .with_uid(1337)
-            .with_gid(1338)
-            .build();
+        Ok(())
+    }
 
-        driver.db.with_write_tx(|tx| {
-            queries::inode::create(tx, &mut root_dir)?;
-            queries::inode::create(tx, &mut node)?;
-            queries::dir_entry::create(tx, root_dir.ino, OsStr::new("foo.txt"), node.ino)?;
-            Ok(())
+    fn block_data(driver: &mut FuseDriver, ino: u64) -> anyhow::Result<Vec<(u64, Vec<u8>)>> {
+        let mut blocks = Vec::new();
+        driver.db.with_read_tx(|tx| {
+            queries::block::iter_blocks_from(tx, ino, 0, |block| {
+                blocks.push((block.bno, block.data));
+                Ok(true)
+            })
         })?;
+        Ok(blocks)
+    }
 
-        driver.rename_impl(
-            RequestInfo::default(),
-            root_dir.ino,
-            OsStr::new("foo.txt"),
-            root_dir.ino,
-            OsStr::new("foo2.txt"),
-            0,
-        )?;
-
-        let res = driver
+    fn data_rows(driver: &mut FuseDriver) -> anyhow::Result<u64> {
+        let count = driver
             .db
-            .with_read_tx(|tx| queries::dir_entry::lookup(tx, root_dir.ino, OsStr::new("foo.txt")));
-        assert_eq!(res, Err(Error::NotFound));
+            .with_read_tx(|tx| Ok(tx.query_row("SELECT count(*) FROM block_data", [], |row| row.get(0))?))?;
+        Ok(count)
+    }
 
-        let db_attr = driver.db.with_read_tx(|tx| {
-            let ino = queries::dir_entry::lookup(tx, root_dir.ino, OsStr::new("foo2.txt"))?;
-            queries::inode::lookup(tx, ino)
+    #[test]
+    fn test_copy_file_range() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::LZ4);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let bs = BLOCK_SIZE as i64;
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let data = (0..2 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let (src, src_fh, _) = driver.create_impl(req, 1, OsStr::new("a"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.write_impl(req, src.ino, src_fh, 0, &data, 0, 0, None)?;
+        let (dst, dst_fh, _) = driver.create_impl(req, 1, OsStr::new("b"), libc::S_IFREG | 0o644, 0, flags)?;
+
+        // Aligned copies share the blocks, including the last partial one. The length is clamped to the source.
+        let copied = driver.copy_file_range_impl(req, src.ino, src_fh, 0, dst.ino, dst_fh, 0, 10 * BLOCK_SIZE, 0)?;
+        assert_eq!(copied as usize, data.len());
+        assert_eq!(data_rows(&mut driver)?, 3);
+        assert_eq!(driver.getattr_impl(req, dst.ino)?.size, data.len() as u64);
+        assert_eq!(driver.read_impl(req, dst.ino, dst_fh, 0, 3 * bs as u32, 0, None)?, data);
+
+        // Writing to the copy leaves the source untouched.
+        driver.write_impl(req, dst.ino, dst_fh, 10, &[0xff; 10], 0, 0, None)?;
+        driver.flush_impl(req, dst.ino, dst_fh, 0)?;
+        assert_eq!(data_rows(&mut driver)?, 4);
+        assert_eq!(driver.read_impl(req, src.ino, src_fh, 0, 3 * bs as u32, 0, None)?, data);
+        let copy = driver.read_impl(req, dst.ino, dst_fh, 0, 3 * bs as u32, 0, None)?;
+        assert_eq!(&copy[10..20], &[0xff; 10]);
+        assert_eq!(&copy[20..], &data[20..]);
+
+        // Unaligned copies copy the data.
+        let (other, other_fh, _) = driver.create_impl(req, 1, OsStr::new("c"), libc::S_IFREG | 0o644, 0, flags)?;
+        let copied = driver.copy_file_range_impl(req, src.ino, src_fh, 100, other.ino, other_fh, 7, BLOCK_SIZE, 0)?;
+        assert_eq!(copied as u64, BLOCK_SIZE);
+        assert_eq!(data_rows(&mut driver)?, 6);
+        let copy = driver.read_impl(req, other.ino, other_fh, 0, 2 * bs as u32, 0, None)?;
+        assert_eq!(copy.len() as u64, BLOCK_SIZE + 7);
+        assert_eq!(&copy[..7], &[0; 7]);
+        assert_eq!(&copy[7..], &data[100..100 + bs as usize]);
+
+        // Overlapping ranges of the same file are rejected.
+        assert_eq!(
+            driver.copy_file_range_impl(req, src.ino, src_fh, 0, src.ino, src_fh, 100, 200, 0),
+            Err(Error::InvalidArgument)
+        );
+
+        // Shared data is deleted with the last block referencing it.
+        for (ino, fh) in [(src.ino, src_fh), (dst.ino, dst_fh), (other.ino, other_fh)] {
+            driver.setattr_impl(
+                req,
+                ino,
+                None,
+                None,
+                None,
+                Some(0),
+                None,
+                None,
+                None,
+                Some(fh),
+                None,
+                None,
+                None,
+                None,
+            )?;
+        }
+        assert_eq!(data_rows(&mut driver)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::Zstd);
+        driver.storage.dedup = true;
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let bs = BLOCK_SIZE as usize;
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        // Two identical blocks followed by a partial block.
+        let mut data = vec![7u8; 2 * bs];
+        data.extend_from_slice(&[8u8; 100]);
+
+        let mut files = Vec::new();
+        for name in ["a", "b"] {
+            let (attr, fh, _) = driver.create_impl(req, 1, OsStr::new(name), libc::S_IFREG | 0o644, 0, flags)?;
+            driver.write_impl(req, attr.ino, fh, 0, &data, 0, 0, None)?;
+            driver.flush_impl(req, attr.ino, fh, 0)?;
+            files.push((attr.ino, fh));
+        }
+        let stats = driver.db.with_read_tx(queries::block::stats)?;
+        assert_eq!((stats.blocks, stats.stored_blocks), (6, 2));
+        let (full, partial) = (
+            stored_len(&mut driver, files[0].0, 0)?,
+            stored_len(&mut driver, files[0].0, 2)?,
+        );
+        assert_eq!(stats.stored_bytes, full + partial);
+        assert_eq!(stats.referenced_bytes, 4 * full + 2 * partial);
+
+        // Changing a block gives it its own data, the other blocks keep theirs.
+        let (ino, fh) = files[1];
+        driver.write_impl(req, ino, fh, 10, &[9u8; 10], 0, 0, None)?;
+        driver.flush_impl(req, ino, fh, 0)?;
+        let stats = driver.db.with_read_tx(queries::block::stats)?;
+        assert_eq!((stats.blocks, stats.stored_blocks), (6, 3));
+        let (a, a_fh) = files[0];
+        assert_eq!(driver.read_impl(req, a, a_fh, 0, 3 * bs as u32, 0, None)?, data);
+        let b = driver.read_impl(req, ino, fh, 0, 3 * bs as u32, 0, None)?;
+        assert_eq!(&b[10..20], &[9u8; 10]);
+        assert_eq!(&b[20..], &data[20..]);
+
+        // Writing the previous content back shares the data again.
+        driver.write_impl(req, ino, fh, 10, &[7u8; 10], 0, 0, None)?;
+        driver.flush_impl(req, ino, fh, 0)?;
+        let stats = driver.db.with_read_tx(queries::block::stats)?;
+        assert_eq!((stats.blocks, stats.stored_blocks), (6, 2));
+
+        for (ino, fh) in files {
+            driver.setattr_impl(
+                req,
+                ino,
+                None,
+                None,
+                None,
+                Some(0),
+                None,
+                None,
+                None,
+                Some(fh),
+                None,
+                None,
+                None,
+                None,
+            )?;
+        }
+        assert_eq!(data_rows(&mut driver)?, 0);
+
+        Ok(())
+    }
+
+    /// Length of the stored data of a block.
+    fn stored_len(driver: &mut FuseDriver, ino: u64, bno: u64) -> anyhow::Result<u64> {
+        let len = driver.db.with_read_tx(|tx| {
+            Ok(tx.query_row(
+                "SELECT length(d.data) FROM block b JOIN block_data d ON d.id = b.data_id WHERE b.ino = ? AND b.bno = ?",
+                [ino, bno],
+                |row| row.get(0),
+            )?)
         })?;
-        assert_eq!(db_attr.ino, node.ino);
+        Ok(len)
+    }
+
+    #[test]
+    fn test_snapshots() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::LZ4);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let bs = BLOCK_SIZE as usize;
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let data = (0..2 * bs + 100).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+        let dir = driver.mkdir_impl(req, 1, OsStr::new("d"), 0o755, 0)?;
+        let (a, a_fh, _) = driver.create_impl(req, dir.ino, OsStr::new("a"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.write_impl(req, a.ino, a_fh, 0, &data, 0, 0, None)?;
+        driver.flush_impl(req, a.ino, a_fh, 0)?;
+        driver.setxattr_impl(req, a.ino, OsStr::new("user.k"), b"v", 0, 0)?;
+        let (b, b_fh, _) = driver.create_impl(req, 1, OsStr::new("b"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.release_impl(req, b.ino, b_fh, 0, None, true)?;
+        driver.symlink_impl(req, 1, OsStr::new("l"), Path::new("d/a"))?;
+
+        let snapshot = driver
+            .db
+            .with_write_tx(|tx| queries::snapshot::create(tx, "s1", TimeSpec::new(1, 0)))?;
+        assert_eq!(
+            driver.db.with_read_tx(queries::snapshot::list)?,
+            vec![queries::snapshot::Snapshot {
+                id: snapshot.id,
+                name: "s1".to_string(),
+                created: TimeSpec::new(1, 0),
+            }]
+        );
+        assert_eq!(
+            driver
+                .db
+                .with_write_tx(|tx| queries::snapshot::create(tx, "s1", TimeSpec::now())),
+            Err(Error::AlreadyExists)
+        );
+        // The snapshot shares the data of the blocks, its references are counted by the stats.
+        assert_eq!(data_rows(&mut driver)?, 3);
+        let stats = driver.db.with_read_tx(queries::block::stats)?;
+        assert_eq!((stats.blocks, stats.snapshot_blocks, stats.stored_blocks), (3, 3, 3));
+        assert_eq!(stats.referenced_bytes, 2 * stats.stored_bytes);
+
+        // Changes to the files are copied on write.
+        let first = stored_len(&mut driver, a.ino, 0)?;
+        driver.write_impl(req, a.ino, a_fh, 10, &[0xff; 10], 0, 0, None)?;
+        driver.flush_impl(req, a.ino, a_fh, 0)?;
+        assert_eq!(data_rows(&mut driver)?, 4);
+        let changed = stored_len(&mut driver, a.ino, 0)?;
+        let new_stats = driver.db.with_read_tx(queries::block::stats)?;
+        assert_eq!(new_stats.stored_blocks, 4);
+        assert_eq!(new_stats.stored_bytes, stats.stored_bytes + changed);
+        assert_eq!(new_stats.referenced_bytes, stats.referenced_bytes - first + changed);
+        driver.unlink_impl(req, 1, OsStr::new("b"))?;
+        let (c, c_fh, _) = driver.create_impl(req, 1, OsStr::new("c"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.release_impl(req, c.ino, c_fh, 0, None, true)?;
+
+        let changes = driver
+            .db
+            .with_read_tx(|tx| queries::snapshot::diff(tx, Some(snapshot.id), None))?;
+        assert_eq!(
+            changes,
+            vec![
+                (queries::snapshot::Change::Removed, b"/b".to_vec()),
+                (queries::snapshot::Change::Added, b"/c".to_vec()),
+                (queries::snapshot::Change::Modified, b"/d/a".to_vec()),
+            ]
+        );
+        let reverse = driver
+            .db
+            .with_read_tx(|tx| queries::snapshot::diff(tx, None, Some(snapshot.id)))?;
+        assert_eq!(reverse[0], (queries::snapshot::Change::Added, b"/b".to_vec()));
+
+        // The snapshot shows the files as they were.
+        driver.release_impl(req, a.ino, a_fh, 0, None, true)?;
+        driver.db.use_snapshot("s1")?;
+        let b = driver.lookup_impl(req, 1, OsStr::new("b"))?;
+        assert_eq!(b.size, 0);
+        assert_eq!(driver.lookup_impl(req, 1, OsStr::new("c")), Err(Error::NotFound));
+        let a = driver.lookup_impl(req, dir.ino, OsStr::new("a"))?;
+        let (fh, _) = driver.open_impl(req, a.ino, OpenFlags::from(libc::O_RDONLY))?;
+        assert_eq!(driver.read_impl(req, a.ino, fh, 0, 3 * bs as u32, 0, None)?, data);
+        assert_eq!(
+            driver.getxattr_impl(req, a.ino, OsStr::new("user.k"), 16)?,
+            XattrReply::Data(b"v".to_vec())
+        );
+        let l = driver.lookup_impl(req, 1, OsStr::new("l"))?;
+        assert_eq!(driver.readlink_impl(req, l.ino)?, b"d/a");
+        let mut names = Vec::new();
+        driver.readdir_impl(req, 1, 0, 0, |entry| {
+            names.push(entry.name.to_owned());
+            true
+        })?;
+        assert_eq!(names, [".", "..", "d", "b", "l"]);
+        assert_eq!(
+            driver
+                .write_impl(req, a.ino, fh, 0, b"x", 0, 0, None)
+                .and_then(|_| driver.flush_impl(req, a.ino, fh, 0)),
+            Err(Error::ReadOnly)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_snapshot() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let (attr, fh, _) = driver.create_impl(req, 1, OsStr::new("a"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.write_impl(req, attr.ino, fh, 0, b"hello", 0, 0, None)?;
+        driver.release_impl(req, attr.ino, fh, 0, None, true)?;
+        driver
+            .db
+            .with_write_tx(|tx| queries::snapshot::create(tx, "s1", TimeSpec::now()))?;
+
+        // The data stays while the snapshot references it.
+        driver.unlink_impl(req, 1, OsStr::new("a"))?;
+        assert_eq!(data_rows(&mut driver)?, 1);
+        driver.db.with_write_tx(|tx| queries::snapshot::remove(tx, "s1"))?;
+        assert_eq!(data_rows(&mut driver)?, 0);
+        assert_eq!(
+            driver.db.with_write_tx(|tx| queries::snapshot::remove(tx, "s1")),
+            Err(Error::NotFound)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_snapshot_rows() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let mut inos = Vec::new();
+        for (name, data) in [("a", b"hello"), ("b", b"world")] {
+            let (attr, fh, _) = driver.create_impl(req, 1, OsStr::new(name), libc::S_IFREG | 0o644, 0, flags)?;
+            driver.write_impl(req, attr.ino, fh, 0, data, 0, 0, None)?;
+            driver.release_impl(req, attr.ino, fh, 0, None, true)?;
+            inos.push(attr.ino);
+        }
+        let snapshot = |driver: &mut FuseDriver, name: &str| -> anyhow::Result<i64> {
+            let snapshot = driver
+                .db
+                .with_write_tx(|tx| queries::snapshot::create(tx, name, TimeSpec::now()))?;
+            Ok(snapshot.id)
+        };
+        let rows = |driver: &mut FuseDriver| -> anyhow::Result<(u64, u64)> {
+            let rows = driver.db.with_read_tx(|tx| {
+                let inodes = tx.query_row("SELECT count(*) FROM snapshot_inode", [], |row| row.get(0))?;
+                let blocks = tx.query_row("SELECT count(*) FROM snapshot_block", [], |row| row.get(0))?;
+                Ok((inodes, blocks))
+            })?;
+            Ok(rows)
+        };
+
+        // Unchanged snapshots share their rows, the stats count the blocks once per snapshot.
+        let s1 = snapshot(&mut driver, "s1")?;
+        let s2 = snapshot(&mut driver, "s2")?;
+        assert_eq!(rows(&mut driver)?, (3, 2));
+        assert_eq!(driver.db.with_read_tx(queries::block::stats)?.snapshot_blocks, 4);
+        let diff = |driver: &mut FuseDriver, from: Option<i64>, to: Option<i64>| {
+            driver.db.with_read_tx(|tx| queries::snapshot::diff(tx, from, to))
+        };
+        assert_eq!(diff(&mut driver, Some(s1), Some(s2))?, vec![]);
+
+        // Only the changed rows are copied.
+        let (fh, _) = driver.open_impl(req, inos[0], OpenFlags::from(libc::O_RDWR))?;
+        driver.write_impl(req, inos[0], fh, 0, b"HELLO", 0, 0, None)?;
+        driver.release_impl(req, inos[0], fh, 0, None, true)?;
+        let s3 = snapshot(&mut driver, "s3")?;
+        assert_eq!(rows(&mut driver)?, (4, 3));
+        let modified = vec![(queries::snapshot::Change::Modified, b"/a".to_vec())];
+        assert_eq!(diff(&mut driver, Some(s2), Some(s3))?, modified);
+        assert_eq!(diff(&mut driver, Some(s3), None)?, vec![]);
+
+        // Removing a snapshot keeps the rows the others share.
+        driver.db.with_write_tx(|tx| queries::snapshot::remove(tx, "s2"))?;
+        assert_eq!(rows(&mut driver)?, (4, 3));
+        assert_eq!(diff(&mut driver, Some(s1), Some(s3))?, modified);
+        driver.db.with_write_tx(|tx| queries::snapshot::remove(tx, "s1"))?;
+        assert_eq!(rows(&mut driver)?, (3, 2));
+        assert_eq!(data_rows(&mut driver)?, 2);
+
+        // The id of the last snapshot can be used again.
+        driver.db.with_write_tx(|tx| queries::snapshot::remove(tx, "s3"))?;
+        assert_eq!(rows(&mut driver)?, (0, 0));
+        let s4 = snapshot(&mut driver, "s4")?;
+        assert_eq!(rows(&mut driver)?, (3, 2));
+        assert_eq!(diff(&mut driver, Some(s4), None)?, vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_versions() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::LZ4);
+        driver.ensure_root_exists()?;
+        driver.db.with_write_tx(|tx| {
+            assert_eq!(queries::setting::set(tx, "unknown", "1"), Err(Error::NotFound));
+            assert_eq!(
+                queries::setting::set(tx, queries::setting::VERSIONS_KEEP, "-1"),
+                Err(Error::InvalidArgument)
+            );
+            queries::setting::set(tx, queries::setting::VERSIONS_KEEP, "2")
+        })?;
+
+        let req = RequestInfo::default();
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let (attr, fh, _) = driver.create_impl(req, 1, OsStr::new("a"), libc::S_IFREG | 0o644, 0, flags)?;
+        let ino = attr.ino;
+        let sizes = |driver: &mut FuseDriver| -> anyhow::Result<Vec<u64>> {
+            let versions = driver
+                .db
+                .with_read_tx(|tx| queries::version::list(tx, Path::new("/a")))?;
+            Ok(versions.iter().map(|v| v.size).collect())
+        };
+        // An empty file has no version.
+        driver.write_impl(req, ino, fh, 0, b"v1", 0, 0, None)?;
+        driver.release_impl(req, ino, fh, 0, None, true)?;
+        assert_eq!(sizes(&mut driver)?, Vec::<u64>::new());
+
+        // A version is kept before the first write of each handle.
+        let (fh, _) = driver.open_impl(req, ino, OpenFlags::from(libc::O_RDWR))?;
+        driver.write_impl(req, ino, fh, 0, b"v2!", 0, 0, None)?;
+        driver.write_impl(req, ino, fh, 3, b"!", 0, 0, None)?;
+        driver.release_impl(req, ino, fh, 0, None, true)?;
+        assert_eq!(sizes(&mut driver)?, vec![2]);
+
+        // Truncation keeps a version, only the newest versions are kept.
+        let (fh, _) = driver.open_impl(req, ino, OpenFlags::from(libc::O_RDWR | libc::O_TRUNC))?;
+        driver.write_impl(req, ino, fh, 0, b"v3", 0, 0, None)?;
+        driver.release_impl(req, ino, fh, 0, None, true)?;
+        assert_eq!(sizes(&mut driver)?, vec![2, 4]);
+        driver.setattr_impl(
+            req,
+            ino,
+            None,
+            None,
+            None,
+            Some(1),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )?;
+        let versions = driver
+            .db
+            .with_read_tx(|tx| queries::version::list(tx, Path::new("/a")))?;
+        assert_eq!(versions.iter().map(|v| v.size).collect::<Vec<_>>(), vec![4, 2]);
+        let stats = driver.db.with_read_tx(queries::block::stats)?;
+        assert_eq!((stats.blocks, stats.version_blocks, stats.stored_blocks), (1, 2, 3));
+
+        // Restoring keeps the replaced content as a version.
+        driver
+            .db
+            .with_write_tx(|tx| queries::version::restore(tx, Path::new("/a"), versions[0].id, TimeSpec::now()))?;
+        assert_eq!(sizes(&mut driver)?, vec![2, 1]);
+        let (fh, _) = driver.open_impl(req, ino, OpenFlags::from(libc::O_RDONLY))?;
+        assert_eq!(driver.read_impl(req, ino, fh, 0, 100, 0, None)?, b"v2!!");
+        driver.release_impl(req, ino, fh, 0, None, true)?;
+
+        // A copy past the end of the source changes nothing and keeps no version.
+        let (fh, _) = driver.open_impl(req, ino, OpenFlags::from(libc::O_RDWR))?;
+        assert_eq!(driver.copy_file_range_impl(req, ino, fh, 100, ino, fh, 0, 10, 0)?, 0);
+        driver.release_impl(req, ino, fh, 0, None, true)?;
+        assert_eq!(sizes(&mut driver)?, vec![2, 1]);
+
+        // Old versions are pruned with their data.
+        assert_eq!(data_rows(&mut driver)?, 3);
+        let retention = queries::version::Retention { keep: 0, max_age: 60 };
+        let later = TimeSpec::new(TimeSpec::now().secs + 120, 0);
+        let removed = driver
+            .db
+            .with_write_tx(|tx| queries::version::prune(tx, None, later, retention))?;
+        assert_eq!(removed, 2);
+        assert_eq!(data_rows(&mut driver)?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deleted_file_versions() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::LZ4);
+        driver.ensure_root_exists()?;
+        driver
+            .db
+            .with_write_tx(|tx| queries::setting::set(tx, queries::setting::VERSIONS_KEEP, "3"))?;
+
+        let req = RequestInfo::default();
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let dir = driver.mkdir_impl(req, 1, OsStr::new("d"), 0o755, 0)?;
+        let versions = |driver: &mut FuseDriver, path: &str| -> anyhow::Result<Vec<(u64, bool)>> {
+            let versions = driver
+                .db
+                .with_read_tx(|tx| queries::version::list(tx, Path::new(path)))?;
+            Ok(versions.iter().map(|v| (v.size, v.deleted)).collect())
+        };
+
+        let (a, fh, _) = driver.create_impl(req, dir.ino, OsStr::new("a"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.write_impl(req, a.ino, fh, 0, b"1", 0, 0, None)?;
+        driver.release_impl(req, a.ino, fh, 0, None, true)?;
+        let (fh, _) = driver.open_impl(req, a.ino, OpenFlags::from(libc::O_RDWR))?;
+        driver.write_impl(req, a.ino, fh, 0, b"22", 0, 0, None)?;
+        driver.release_impl(req, a.ino, fh, 0, None, true)?;
+        assert_eq!(versions(&mut driver, "/d/a")?, vec![(1, false)]);
+
+        // Saving through a rename replaces the file, its content and versions stay listed under the path.
+        let (tmp, fh, _) = driver.create_impl(req, dir.ino, OsStr::new("tmp"), libc::S_IFREG | 0o640, 0, flags)?;
+        driver.write_impl(req, tmp.ino, fh, 0, b"333", 0, 0, None)?;
+        driver.release_impl(req, tmp.ino, fh, 0, None, true)?;
+        driver.rename_impl(req, dir.ino, OsStr::new("tmp"), dir.ino, OsStr::new("a"), 0)?;
+        assert_eq!(driver.getattr_impl(req, a.ino), Err(Error::NotFound));
+        assert_eq!(versions(&mut driver, "/d/a")?, vec![(1, true), (2, true)]);
+
+        // Unlinking keeps the last content, including the writes still buffered by open handles. Only the newest
+        // versions of the path are kept.
+        let (fh, _) = driver.open_impl(req, tmp.ino, OpenFlags::from(libc::O_RDWR))?;
+        driver.write_impl(req, tmp.ino, fh, 3, b"4", 0, 0, None)?;
+        driver.unlink_impl(req, dir.ino, OsStr::new("a"))?;
+        assert_eq!(versions(&mut driver, "d//a")?, vec![(2, true), (3, true), (4, true)]);
+        driver.release_impl(req, tmp.ino, fh, 0, None, true)?;
+        assert_eq!(driver.getattr_impl(req, tmp.ino), Err(Error::NotFound));
+
+        let (b, fh, _) = driver.create_impl(req, dir.ino, OsStr::new("a"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.write_impl(req, b.ino, fh, 0, b"55555", 0, 0, None)?;
+        driver.release_impl(req, b.ino, fh, 0, None, true)?;
+        driver.unlink_impl(req, dir.ino, OsStr::new("a"))?;
+        let listed = driver
+            .db
+            .with_read_tx(|tx| queries::version::list(tx, Path::new("/d/a")))?;
+        assert_eq!(listed.iter().map(|v| v.size).collect::<Vec<_>>(), vec![3, 4, 5]);
+
+        // Restoring a version of a deleted file creates the file again, with the mode it had.
+        driver
+            .db
+            .with_write_tx(|tx| queries::version::restore(tx, Path::new("/d/a"), listed[0].id, TimeSpec::now()))?;
+        let restored = driver.lookup_impl(req, dir.ino, OsStr::new("a"))?;
+        assert_eq!((restored.size, restored.perm), (3, 0o640));
+        let (fh, _) = driver.open_impl(req, restored.ino, OpenFlags::from(libc::O_RDONLY))?;
+        assert_eq!(driver.read_impl(req, restored.ino, fh, 0, 100, 0, None)?, b"333");
+        driver.release_impl(req, restored.ino, fh, 0, None, true)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fallocate() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::LZ4);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let bs = BLOCK_SIZE as usize;
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let (attr, fh, _) = driver.create_impl(req, 1, OsStr::new("disk.img"), libc::S_IFREG | 0o644, 0, flags)?;
+        let ino = attr.ino;
+
+        // Mode 0 extends the size without allocating blocks.
+        driver.fallocate_impl(req, ino, fh, 0, 4 * BLOCK_SIZE as i64, 0)?;
+        assert_eq!(driver.getattr_impl(req, ino)?.size, 4 * BLOCK_SIZE);
+        assert_eq!(count_blocks(&mut driver, ino)?, 0);
+        assert_eq!(driver.read_impl(req, ino, fh, 100, 10, 0, None)?, vec![0; 10]);
+
+        // KEEP_SIZE does not change anything.
+        driver.fallocate_impl(req, ino, fh, 0, 8 * BLOCK_SIZE as i64, libc::FALLOC_FL_KEEP_SIZE)?;
+        assert_eq!(driver.getattr_impl(req, ino)?.size, 4 * BLOCK_SIZE);
+
+        driver.write_impl(req, ino, fh, 0, &vec![1u8; 4 * bs], 0, 0, None)?;
+        driver.flush_impl(req, ino, fh, 0)?;
+        assert_eq!(count_blocks(&mut driver, ino)?, 4);
+
+        // Punch a hole from the middle of block 0 to the middle of block 2, block 1 is removed.
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        driver.fallocate_impl(req, ino, fh, (bs / 2) as i64, 2 * BLOCK_SIZE as i64, mode)?;
+        let blocks = block_data(&mut driver, ino)?;
+        assert_eq!(blocks.iter().map(|(bno, _)| *bno).collect::<Vec<_>>(), vec![0, 2, 3]);
+        assert!(blocks[0].1[..bs / 2].iter().all(|&b| b == 1));
+        assert!(blocks[0].1[bs / 2..].iter().all(|&b| b == 0));
+        assert!(blocks[1].1[..bs / 2].iter().all(|&b| b == 0));
+        assert!(blocks[1].1[bs / 2..].iter().all(|&b| b == 1));
+        assert_eq!(driver.getattr_impl(req, ino)?.size, 4 * BLOCK_SIZE);
+
+        let data = driver.read_impl(req, ino, fh, 0, 4 * BLOCK_SIZE as u32, 0, None)?;
+        assert_eq!(data.len(), 4 * bs);
+        assert!(data[..bs / 2].iter().all(|&b| b == 1));
+        assert!(data[bs / 2..bs * 5 / 2].iter().all(|&b| b == 0));
+        assert!(data[bs * 5 / 2..].iter().all(|&b| b == 1));
+
+        // Zero the end of the file and past it, the size grows.
+        driver.fallocate_impl(
+            req,
+            ino,
+            fh,
+            3 * BLOCK_SIZE as i64,
+            2 * BLOCK_SIZE as i64,
+            libc::FALLOC_FL_ZERO_RANGE,
+        )?;
+        let blocks = block_data(&mut driver, ino)?;
+        assert_eq!(blocks.iter().map(|(bno, _)| *bno).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(driver.getattr_impl(req, ino)?.size, 5 * BLOCK_SIZE);
+        let data = driver.read_impl(req, ino, fh, 3 * BLOCK_SIZE as i64, 2 * BLOCK_SIZE as u32, 0, None)?;
+        assert_eq!(data, vec![0; 2 * bs]);
+
+        // Punching a hole requires KEEP_SIZE.
+        let res = driver.fallocate_impl(req, ino, fh, 0, 10, libc::FALLOC_FL_PUNCH_HOLE);
+        assert_eq!(res, Err(Error::NotSupported));
+        assert_eq!(
+            driver.fallocate_impl(req, ino, fh, 0, 0, 0),
+            Err(Error::InvalidArgument)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sparse_file() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::Zstd);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let bs = BLOCK_SIZE as i64;
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let (attr, fh, _) = driver.create_impl(req, 1, OsStr::new("vm.img"), libc::S_IFREG | 0o644, 0, flags)?;
+        let ino = attr.ino;
+
+        // Data in the middle of block 1 and at the start of block 4, the rest is a hole.
+        driver.write_impl(req, ino, fh, bs + 100, &[1u8; 50], 0, 0, None)?;
+        driver.write_impl(req, ino, fh, 4 * bs, &[2u8; 10], 0, 0, None)?;
+        driver.flush_impl(req, ino, fh, 0)?;
+
+        let attr = driver.getattr_impl(req, ino)?;
+        assert_eq!(attr.size, 4 * BLOCK_SIZE + 10);
+        assert_eq!(
+            block_data(&mut driver, ino)?
+                .iter()
+                .map(|(bno, _)| *bno)
+                .collect::<Vec<_>>(),
+            vec![1, 4]
+        );
+        // Only the compressed data of the allocated blocks is accounted for.
+        let stored = driver.db.with_read_tx(|tx| queries::block::stored_bytes(tx, ino))?;
+        assert_eq!(attr.blocks, stored.div_ceil(POSIX_BLOCK_SIZE));
+        assert!(attr.blocks < (BLOCK_SIZE + 10).div_ceil(POSIX_BLOCK_SIZE));
+        assert_eq!(attr.blksize as u64, BLOCK_SIZE);
+
+        let data = driver.read_impl(req, ino, fh, 0, 5 * BLOCK_SIZE as u32, 0, None)?;
+        assert_eq!(data.len() as u64, attr.size);
+        assert!(data[..bs as usize + 100].iter().all(|&b| b == 0));
+        assert_eq!(&data[bs as usize + 100..][..50], &[1u8; 50]);
+        assert!(data[bs as usize + 150..4 * bs as usize].iter().all(|&b| b == 0));
+        assert_eq!(&data[4 * bs as usize..], &[2u8; 10]);
+
+        // Overwriting inside a block keeps the rest of the block.
+        driver.write_impl(req, ino, fh, bs + 110, &[3u8; 10], 0, 0, None)?;
+        driver.flush_impl(req, ino, fh, 0)?;
+        let data = driver.read_impl(req, ino, fh, bs + 100, 50, 0, None)?;
+        assert_eq!(&data[..10], &[1u8; 10]);
+        assert_eq!(&data[10..20], &[3u8; 10]);
+        assert_eq!(&data[20..], &[1u8; 30]);
+
+        assert_eq!(driver.lseek_impl(req, ino, fh, 0, libc::SEEK_DATA)?, bs);
+        assert_eq!(driver.lseek_impl(req, ino, fh, bs + 10, libc::SEEK_DATA)?, bs + 10);
+        assert_eq!(driver.lseek_impl(req, ino, fh, 2 * bs, libc::SEEK_DATA)?, 4 * bs);
+        assert_eq!(driver.lseek_impl(req, ino, fh, 0, libc::SEEK_HOLE)?, 0);
+        assert_eq!(driver.lseek_impl(req, ino, fh, bs, libc::SEEK_HOLE)?, 2 * bs);
+        assert_eq!(driver.lseek_impl(req, ino, fh, 4 * bs, libc::SEEK_HOLE)?, 4 * bs + 10);
+        assert_eq!(
+            driver.lseek_impl(req, ino, fh, 4 * bs + 10, libc::SEEK_DATA),
+            Err(Error::NoSuchAddress)
+        );
+
+        // Truncating into a hole drops the blocks after it.
+        driver.setattr_impl(
+            req,
+            ino,
+            None,
+            None,
+            None,
+            Some(3 * BLOCK_SIZE),
+            None,
+            None,
+            None,
+            Some(fh),
+            None,
+            None,
+            None,
+            None,
+        )?;
+        assert_eq!(block_data(&mut driver, ino)?.len(), 1);
+        let stored = driver.db.with_read_tx(|tx| queries::block::stored_bytes(tx, ino))?;
+        assert_eq!(driver.getattr_impl(req, ino)?.blocks, stored.div_ceil(POSIX_BLOCK_SIZE));
+        assert_eq!(
+            driver.lseek_impl(req, ino, fh, 2 * bs, libc::SEEK_DATA),
+            Err(Error::NoSuchAddress)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_write_buffer() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let (attr, fh1, _) = driver.create_impl(req, 1, OsStr::new("shared"), libc::S_IFREG | 0o644, 0, flags)?;
+        let (fh2, _) = driver.open_impl(req, attr.ino, OpenFlags::from(libc::O_RDWR))?;
+
+        // The size includes the buffered data before it is flushed.
+        driver.write_impl(req, attr.ino, fh1, 0, &[1u8; 1000], 0, 0, None)?;
+        assert_eq!(
+            driver.db.with_read_tx(|tx| queries::inode::lookup(tx, attr.ino))?.size,
+            0
+        );
+        assert_eq!(driver.getattr_impl(req, attr.ino)?.size, 1000);
+        assert_eq!(driver.getattr_impl(req, attr.ino)?.blocks, 2);
+        assert_eq!(driver.lookup_impl(req, 1, OsStr::new("shared"))?.size, 1000);
+
+        // Writes through both handles go to the same buffer and reads through either handle see them.
+        driver.write_impl(req, attr.ino, fh2, 1000, &[2u8; 1000], 0, 0, None)?;
+        assert_eq!(driver.buffers.len(), 1);
+        let data = driver.read_impl(req, attr.ino, fh2, 0, 3000, 0, None)?;
+        assert_eq!(data.len(), 2000);
+        assert!(data[..1000].iter().all(|&b| b == 1));
+        assert!(data[1000..].iter().all(|&b| b == 2));
+
+        // Overlapping writes from different handles are applied in order.
+        driver.write_impl(req, attr.ino, fh1, 500, &[3u8; 1000], 0, 0, None)?;
+        driver.write_impl(req, attr.ino, fh2, 1000, &[4u8; 100], 0, 0, None)?;
+        driver.release_impl(req, attr.ino, fh1, 0, None, true)?;
+        assert_eq!(driver.getattr_impl(req, attr.ino)?.size, 2000);
+        let data = driver.read_impl(req, attr.ino, fh2, 0, 3000, 0, None)?;
+        assert!(data[500..1000].iter().all(|&b| b == 3));
+        assert!(data[1000..1100].iter().all(|&b| b == 4));
+        assert!(data[1100..1500].iter().all(|&b| b == 3));
+        assert!(data[1500..].iter().all(|&b| b == 2));
+
+        // The buffer is dropped with the last handle.
+        driver.release_impl(req, attr.ino, fh2, 0, None, true)?;
+        assert!(driver.buffers.is_empty());
+        let db_attr = driver.db.with_read_tx(|tx| queries::inode::lookup(tx, attr.ino))?;
+        assert_eq!(db_attr.size, 2000);
+        assert_eq!(db_attr.blocks, 2000u64.div_ceil(POSIX_BLOCK_SIZE));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsync() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("nightshift-fsync-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let _cleanup = scopeguard::guard((), |_| {
+            let _ = std::fs::remove_dir_all(&dir);
+        });
+        let path = dir.join("fs.db");
+
+        let db = DatabaseOps::open(&path, "key".to_string())?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let flags = OpenFlags::from(libc::O_WRONLY | libc::O_CREAT);
+        let (attr, fh, _) = driver.create_impl(req, 1, OsStr::new("dump.sql"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.write_impl(req, attr.ino, fh, 0, b"hello", 0, 0, None)?;
+        driver.fsync_impl(req, attr.ino, fh, false)?;
+        driver.fsyncdir_impl(req, 1, 0, false)?;
+
+        // The buffer was written and every frame of the WAL was checkpointed into the database file.
+        let (log, checkpointed): (i64, i64) = driver.db.db.query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |row| {
+            Ok((row.get(1)?, row.get(2)?))
+        })?;
+        assert!(log > 0);
+        assert_eq!(log, checkpointed);
+        assert_eq!(driver.getattr_impl(req, attr.ino)?.size, 5);
+
+        // O_SYNC writes are flushed right away.
+        let (fh, _) = driver.open_impl(req, attr.ino, OpenFlags::from(libc::O_WRONLY | libc::O_SYNC))?;
+        driver.write_impl(req, attr.ino, fh, 5, b" world", 0, 0, None)?;
+        assert_eq!(driver.getattr_impl(req, attr.ino)?.size, 11);
+
+        assert_eq!(driver.fsync_impl(req, attr.ino, 42, false), Err(Error::NotFound));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+
+        let mut root_dir = FileAttrBuilder::new_directory().build();
+        let mut node = FileAttrBuilder::new_node(FileType::RegularFile)
+            .with_uid(1337)
+            .with_gid(1338)
+            .build();
+
+        driver.db.with_write_tx(|tx| {
+            queries::inode::create(tx, &mut root_dir)?;
+            queries::inode::create(tx, &mut node)?;
+            queries::dir_entry::create(tx, root_dir.ino, OsStr::new("foo.txt"), node.ino)?;
+            Ok(())
+        })?;
+
+        driver.rename_impl(
+            RequestInfo::default(),
+            root_dir.ino,
+            OsStr::new("foo.txt"),
+            root_dir.ino,
+            OsStr::new("foo2.txt"),
+            0,
+        )?;
+
+        let res = driver
+            .db
+            .with_read_tx(|tx| queries::dir_entry::lookup(tx, root_dir.ino, OsStr::new("foo.txt")));
+        assert_eq!(res, Err(Error::NotFound));
+
+        let db_attr = driver.db.with_read_tx(|tx| {
+            let ino = queries::dir_entry::lookup(tx, root_dir.ino, OsStr::new("foo2.txt"))?;
+            queries::inode::lookup(tx, ino)
+        })?;
+        assert_eq!(db_attr.ino, node.ino);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_replace() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let flags = OpenFlags::from(libc::O_WRONLY | libc::O_CREAT);
+        let (old, fh, _) = driver.create_impl(req, 1, OsStr::new("config"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.write_impl(req, old.ino, fh, 0, b"old", 0, 0, None)?;
+        driver.release_impl(req, old.ino, fh, 0, None, true)?;
+        let (new, fh, _) = driver.create_impl(req, 1, OsStr::new("config.tmp"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.write_impl(req, new.ino, fh, 0, b"new", 0, 0, None)?;
+        driver.release_impl(req, new.ino, fh, 0, None, true)?;
+
+        // NOREPLACE refuses to overwrite.
+        let res = driver.rename_impl(
+            req,
+            1,
+            OsStr::new("config.tmp"),
+            1,
+            OsStr::new("config"),
+            libc::RENAME_NOREPLACE,
+        );
+        assert_eq!(res, Err(Error::AlreadyExists));
+
+        // The target is replaced and freed.
+        driver.rename_impl(req, 1, OsStr::new("config.tmp"), 1, OsStr::new("config"), 0)?;
+        assert_eq!(driver.lookup_impl(req, 1, OsStr::new("config"))?.ino, new.ino);
+        assert_eq!(
+            driver.lookup_impl(req, 1, OsStr::new("config.tmp")),
+            Err(Error::NotFound)
+        );
+        assert_eq!(driver.getattr_impl(req, old.ino), Err(Error::NotFound));
+        assert_eq!(count_blocks(&mut driver, old.ino)?, 0);
+        let mut names = Vec::new();
+        driver.readdir_impl(req, 1, 0, 0, |entry| {
+            names.push(entry.name.to_owned());
+            true
+        })?;
+        assert_eq!(
+            names,
+            vec![
+                OsStr::new(".").to_owned(),
+                OsStr::new("..").to_owned(),
+                OsStr::new("config").to_owned()
+            ]
+        );
+
+        // A replaced file with other links is kept.
+        let (other, fh, _) = driver.create_impl(req, 1, OsStr::new("other"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.release_impl(req, other.ino, fh, 0, None, true)?;
+        driver.link_impl(req, new.ino, 1, OsStr::new("config.link"))?;
+        driver.rename_impl(req, 1, OsStr::new("other"), 1, OsStr::new("config"), 0)?;
+        assert_eq!(driver.getattr_impl(req, new.ino)?.nlink, 1);
+        assert_eq!(driver.lookup_impl(req, 1, OsStr::new("config.link"))?.ino, new.ino);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_directories() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let a = driver.mkdir_impl(req, 1, OsStr::new("a"), 0o755, 0)?;
+        let b = driver.mkdir_impl(req, a.ino, OsStr::new("b"), 0o755, 0)?;
+        let c = driver.mkdir_impl(req, 1, OsStr::new("c"), 0o755, 0)?;
+        let file = driver.mknod_impl(req, 1, OsStr::new("file"), libc::S_IFREG | 0o644, 0, 0)?;
+        driver.mknod_impl(req, c.ino, OsStr::new("inner"), libc::S_IFREG | 0o644, 0, 0)?;
+
+        // Moving a directory into its own subtree.
+        let res = driver.rename_impl(req, 1, OsStr::new("a"), b.ino, OsStr::new("a"), 0);
+        assert_eq!(res, Err(Error::InvalidArgument));
+        let res = driver.rename_impl(req, 1, OsStr::new("a"), a.ino, OsStr::new("a"), 0);
+        assert_eq!(res, Err(Error::InvalidArgument));
+
+        // Type mismatches and non-empty targets.
+        let res = driver.rename_impl(req, 1, OsStr::new("a"), 1, OsStr::new("file"), 0);
+        assert_eq!(res, Err(Error::NotADirectory));
+        let res = driver.rename_impl(req, 1, OsStr::new("file"), 1, OsStr::new("a"), 0);
+        assert_eq!(res, Err(Error::IsADirectory));
+        let res = driver.rename_impl(req, a.ino, OsStr::new("b"), 1, OsStr::new("c"), 0);
+        assert_eq!(res, Err(Error::NotEmpty));
+
+        // Replace an empty directory.
+        driver.unlink_impl(req, c.ino, OsStr::new("inner"))?;
+        driver.rename_impl(req, a.ino, OsStr::new("b"), 1, OsStr::new("c"), 0)?;
+        assert_eq!(driver.lookup_impl(req, 1, OsStr::new("c"))?.ino, b.ino);
+        assert_eq!(driver.getattr_impl(req, c.ino), Err(Error::NotFound));
+        assert!(driver
+            .db
+            .with_read_tx(|tx| queries::dir_entry::is_dir_empty(tx, a.ino))?);
+
+        // Exchange a file and a directory.
+        driver.rename_impl(req, 1, OsStr::new("file"), 1, OsStr::new("c"), libc::RENAME_EXCHANGE)?;
+        assert_eq!(driver.lookup_impl(req, 1, OsStr::new("c"))?.ino, file.ino);
+        assert_eq!(driver.lookup_impl(req, 1, OsStr::new("file"))?.ino, b.ino);
+
+        // Exchange requires both names to exist.
+        let res = driver.rename_impl(req, 1, OsStr::new("c"), 1, OsStr::new("missing"), libc::RENAME_EXCHANGE);
+        assert_eq!(res, Err(Error::NotFound));
+        // Exchange a directory with its own child.
+        driver.mknod_impl(req, a.ino, OsStr::new("child"), libc::S_IFREG | 0o644, 0, 0)?;
+        let res = driver.rename_impl(
+            req,
+            1,
+            OsStr::new("a"),
+            a.ino,
+            OsStr::new("child"),
+            libc::RENAME_EXCHANGE,
+        );
+        assert_eq!(res, Err(Error::InvalidArgument));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_nlink() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let nlink = |driver: &mut FuseDriver, ino| driver.getattr_impl(req, ino).map(|a| a.nlink);
+        let a = driver.mkdir_impl(req, 1, OsStr::new("a"), 0o755, 0)?;
+        let b = driver.mkdir_impl(req, 1, OsStr::new("b"), 0o755, 0)?;
+        let c = driver.mkdir_impl(req, a.ino, OsStr::new("c"), 0o755, 0)?;
+        driver.mknod_impl(req, a.ino, OsStr::new("file"), libc::S_IFREG | 0o644, 0, 0)?;
+        assert_eq!(nlink(&mut driver, 1)?, 4);
+        assert_eq!(nlink(&mut driver, a.ino)?, 3);
+        assert_eq!(nlink(&mut driver, b.ino)?, 2);
+
+        // Moving a directory to another parent.
+        driver.rename_impl(req, a.ino, OsStr::new("c"), b.ino, OsStr::new("c"), 0)?;
+        assert_eq!(nlink(&mut driver, a.ino)?, 2);
+        assert_eq!(nlink(&mut driver, b.ino)?, 3);
+
+        // Exchanging a directory and a file between parents.
+        driver.rename_impl(
+            req,
+            b.ino,
+            OsStr::new("c"),
+            a.ino,
+            OsStr::new("file"),
+            libc::RENAME_EXCHANGE,
+        )?;
+        assert_eq!(nlink(&mut driver, a.ino)?, 3);
+        assert_eq!(nlink(&mut driver, b.ino)?, 2);
+
+        // Replacing a directory with another one.
+        let d = driver.mkdir_impl(req, a.ino, OsStr::new("d"), 0o755, 0)?;
+        assert_eq!(nlink(&mut driver, a.ino)?, 4);
+        driver.rename_impl(req, a.ino, OsStr::new("d"), a.ino, OsStr::new("file"), 0)?;
+        assert_eq!(nlink(&mut driver, a.ino)?, 3);
+        assert_eq!(nlink(&mut driver, c.ino), Err(Error::NotFound));
+
+        driver.rmdir_impl(req, a.ino, OsStr::new("file"))?;
+        assert_eq!(nlink(&mut driver, a.ino)?, 2);
+        assert_eq!(nlink(&mut driver, d.ino), Err(Error::NotFound));
+        assert_eq!(nlink(&mut driver, 1)?, 4);
+
+        // '.' and '..' come first and the offsets can be used to resume.
+        let mut entries = Vec::new();
+        driver.readdir_impl(req, a.ino, 0, 0, |entry| {
+            entries.push((entry.offset, entry.ino, entry.name.to_owned()));
+            true
+        })?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], (1, a.ino, OsStr::new(".").to_owned()));
+        assert_eq!(entries[1], (2, 1, OsStr::new("..").to_owned()));
+
+        driver.mknod_impl(req, a.ino, OsStr::new("x"), libc::S_IFREG | 0o644, 0, 0)?;
+        driver.mknod_impl(req, a.ino, OsStr::new("y"), libc::S_IFREG | 0o644, 0, 0)?;
+        let mut entries = Vec::new();
+        driver.readdir_impl(req, a.ino, 0, 0, |entry| {
+            entries.push((entry.offset, entry.name.to_owned()));
+            entries.len() < 3
+        })?;
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].1, OsStr::new("x"));
+        let mut rest = Vec::new();
+        driver.readdir_impl(req, a.ino, 0, entries[2].0, |entry| {
+            rest.push(entry.name.to_owned());
+            true
+        })?;
+        assert_eq!(rest, vec![OsStr::new("y").to_owned()]);
+
+        // The root directory is its own parent.
+        let mut entries = Vec::new();
+        driver.readdir_impl(req, 1, 0, 1, |entry| {
+            entries.push((entry.ino, entry.name.to_owned()));
+            false
+        })?;
+        assert_eq!(entries, vec![(1, OsStr::new("..").to_owned())]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlink_open_file() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let (attr, fh, _) = driver.create_impl(req, 1, OsStr::new("tmp"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.write_impl(req, attr.ino, fh, 0, b"hello", 0, 0, None)?;
+
+        // The name is gone but the open handle keeps the inode alive.
+        driver.unlink_impl(req, 1, OsStr::new("tmp"))?;
+        assert_eq!(driver.lookup_impl(req, 1, OsStr::new("tmp")), Err(Error::NotFound));
+        assert_eq!(driver.getattr_impl(req, attr.ino)?.nlink, 0);
+        driver.write_impl(req, attr.ino, fh, 5, b" world", 0, 0, None)?;
+        assert_eq!(driver.read_impl(req, attr.ino, fh, 0, 100, 0, None)?, b"hello world");
+
+        driver.release_impl(req, attr.ino, fh, 0, None, true)?;
+        assert_eq!(driver.getattr_impl(req, attr.ino), Err(Error::NotFound));
+        assert_eq!(count_blocks(&mut driver, attr.ino)?, 0);
+
+        // A kernel reference also keeps the inode alive until it is forgotten.
+        let attr = driver.mknod_impl(req, 1, OsStr::new("tmp"), libc::S_IFREG | 0o644, 0, 0)?;
+        driver.add_lookup(attr.ino);
+        driver.add_lookup(attr.ino);
+        driver.unlink_impl(req, 1, OsStr::new("tmp"))?;
+        driver.forget_impl(attr.ino, 1)?;
+        assert_eq!(driver.getattr_impl(req, attr.ino)?.nlink, 0);
+        driver.forget_impl(attr.ino, 1)?;
+        assert_eq!(driver.getattr_impl(req, attr.ino), Err(Error::NotFound));
+
+        // Replacing an open file by rename.
+        let (old, fh, _) = driver.create_impl(req, 1, OsStr::new("old"), libc::S_IFREG | 0o644, 0, flags)?;
+        driver.mknod_impl(req, 1, OsStr::new("new"), libc::S_IFREG | 0o644, 0, 0)?;
+        driver.rename_impl(req, 1, OsStr::new("new"), 1, OsStr::new("old"), 0)?;
+        assert_eq!(driver.getattr_impl(req, old.ino)?.nlink, 0);
+
+        // Orphans left behind by a crash are removed at the next mount.
+        driver.handles.remove(fh as usize);
+        driver.remove_orphans()?;
+        assert_eq!(driver.getattr_impl(req, old.ino), Err(Error::NotFound));
+        assert_eq!(driver.db.with_read_tx(queries::inode::count)?, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_existing_names() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let dir = driver.mkdir_impl(req, 1, OsStr::new("dir"), 0o755, 0)?;
+        let file = driver.mknod_impl(req, 1, OsStr::new("file"), libc::S_IFREG | 0o644, 0, 0)?;
+
+        let res = driver.mkdir_impl(req, 1, OsStr::new("dir"), 0o755, 0);
+        assert_eq!(res, Err(Error::AlreadyExists));
+        let res = driver.mknod_impl(req, 1, OsStr::new("dir"), libc::S_IFREG | 0o644, 0, 0);
+        assert_eq!(res, Err(Error::AlreadyExists));
+        let res = driver.link_impl(req, file.ino, 1, OsStr::new("dir"));
+        assert_eq!(res, Err(Error::AlreadyExists));
+        let res = driver.symlink_impl(req, 1, OsStr::new("file"), Path::new("dir"));
+        assert_eq!(res, Err(Error::AlreadyExists));
+
+        // The failed operations left nothing behind.
+        assert_eq!(driver.getattr_impl(req, file.ino)?.nlink, 1);
+        let mut count = 0;
+        driver.readdir_impl(req, 1, 0, 0, |_| {
+            count += 1;
+            true
+        })?;
+        assert_eq!(count, 4);
+        assert_eq!(driver.db.with_read_tx(queries::inode::count)?, 3);
+
+        let long_name = "x".repeat(256);
+        let res = driver.mknod_impl(req, dir.ino, OsStr::new(&long_name), libc::S_IFREG | 0o644, 0, 0);
+        assert_eq!(res, Err(Error::NameTooLong));
+        let res = driver.rename_impl(req, 1, OsStr::new("file"), dir.ino, OsStr::new(&long_name), 0);
+        assert_eq!(res, Err(Error::NameTooLong));
+        driver.mknod_impl(req, dir.ino, OsStr::new(&long_name[..255]), libc::S_IFREG | 0o644, 0, 0)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_kind_checks() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let dir = driver.mkdir_impl(req, 1, OsStr::new("dir"), 0o755, 0)?;
+        let file = driver.mknod_impl(req, 1, OsStr::new("file"), libc::S_IFREG | 0o644, 0, 0)?;
+        let name = OsStr::new("child");
+
+        // A regular file used as a directory.
+        assert_eq!(driver.lookup_impl(req, file.ino, name), Err(Error::NotADirectory));
+        assert_eq!(
+            driver.readdir_impl(req, file.ino, 0, 0, |_| true),
+            Err(Error::NotADirectory)
+        );
+        assert_eq!(
+            driver.mkdir_impl(req, file.ino, name, 0o755, 0),
+            Err(Error::NotADirectory)
+        );
+        assert_eq!(
+            driver.mknod_impl(req, file.ino, name, libc::S_IFREG | 0o644, 0, 0),
+            Err(Error::NotADirectory)
+        );
+        assert_eq!(
+            driver.symlink_impl(req, file.ino, name, Path::new("dir")),
+            Err(Error::NotADirectory)
+        );
+        assert_eq!(
+            driver.link_impl(req, file.ino, file.ino, name),
+            Err(Error::NotADirectory)
+        );
+        let flags = OpenFlags::from(libc::O_WRONLY | libc::O_CREAT);
+        assert_eq!(
+            driver.create_impl(req, file.ino, name, libc::S_IFREG | 0o644, 0, flags),
+            Err(Error::NotADirectory)
+        );
+        assert_eq!(driver.unlink_impl(req, file.ino, name), Err(Error::NotADirectory));
+        assert_eq!(driver.rmdir_impl(req, file.ino, name), Err(Error::NotADirectory));
+        assert_eq!(
+            driver.rename_impl(req, 1, OsStr::new("dir"), file.ino, name, 0),
+            Err(Error::NotADirectory)
+        );
+        assert_eq!(
+            driver.rename_impl(req, file.ino, name, 1, OsStr::new("other"), 0),
+            Err(Error::NotADirectory)
+        );
+
+        // Removing with the wrong operation.
+        assert_eq!(driver.rmdir_impl(req, 1, OsStr::new("file")), Err(Error::NotADirectory));
+        assert_eq!(driver.unlink_impl(req, 1, OsStr::new("dir")), Err(Error::IsADirectory));
+        assert_eq!(driver.link_impl(req, dir.ino, 1, name), Err(Error::NotPermitted));
+
+        // Writing to a directory.
+        assert_eq!(
+            driver.open_impl(req, dir.ino, OpenFlags::from(libc::O_RDWR)),
+            Err(Error::IsADirectory)
+        );
+        assert_eq!(
+            driver.create_impl(req, 1, OsStr::new("dir"), libc::S_IFREG | 0o644, 0, flags),
+            Err(Error::IsADirectory)
+        );
+        let res = driver.setattr_impl(
+            req,
+            dir.ino,
+            None,
+            None,
+            None,
+            Some(0),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(res, Err(Error::IsADirectory));
+
+        // Nothing was changed by the failed operations.
+        assert_eq!(driver.lookup_impl(req, 1, OsStr::new("dir"))?.ino, dir.ino);
+        assert_eq!(driver.lookup_impl(req, 1, OsStr::new("file"))?.ino, file.ino);
+        assert_eq!(driver.db.with_read_tx(queries::inode::count)?, 3);
+
+        Ok(())
+    }
+
+    /// Set all the times of the inode to `time`.
+    fn set_times(driver: &mut FuseDriver, ino: u64, time: SystemTime) -> anyhow::Result<()> {
+        let time = TimeSpec::from(time);
+        driver.setattr_impl(
+            RequestInfo::default(),
+            ino,
+            None,
+            None,
+            None,
+            None,
+            Some(time.into()),
+            Some(time.into()),
+            Some(time),
+            None,
+            None,
+            None,
+            None,
+            None,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamps() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let dir = driver.mkdir_impl(req, 1, OsStr::new("dir"), 0o755, 0)?.ino;
+
+        // Creating an entry modifies the directory.
+        set_times(&mut driver, dir, old)?;
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let (attr, fh, _) = driver.create_impl(req, dir, OsStr::new("file"), libc::S_IFREG | 0o644, 0, flags)?;
+        let ino = attr.ino;
+        let dir_attr = driver.getattr_impl(req, dir)?;
+        assert!(dir_attr.mtime > old && dir_attr.ctime > old);
+
+        // Writing modifies the file, even before the data is flushed, but not its directory.
+        set_times(&mut driver, dir, old)?;
+        set_times(&mut driver, ino, old)?;
+        driver.write_impl(req, ino, fh, 0, b"hello", 0, 0, None)?;
+        let attr = driver.getattr_impl(req, ino)?;
+        assert!(attr.mtime > old && attr.ctime > old);
+        assert_eq!(attr.atime, old);
+        driver.flush_impl(req, ino, fh, 0)?;
+        let db_attr = driver.db.with_read_tx(|tx| queries::inode::lookup(tx, ino))?;
+        assert_eq!((db_attr.mtime, db_attr.ctime), (attr.mtime, attr.ctime));
+        assert_eq!(driver.getattr_impl(req, dir)?.mtime, old);
+
+        // A time given explicitly while data is buffered is kept once it is flushed.
+        let (fh2, _) = driver.open_impl(req, ino, OpenFlags::from(libc::O_RDWR))?;
+        driver.write_impl(req, ino, fh2, 0, b"hello", 0, 0, None)?;
+        driver.setattr_impl(
+            req,
+            ino,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(TimeSpec::from(old).into()),
+            None,
+            Some(fh2),
+            None,
+            None,
+            None,
+            None,
+        )?;
+        assert_eq!(driver.getattr_impl(req, ino)?.mtime, old);
+        driver.release_impl(req, ino, fh2, 0, None, true)?;
+        assert_eq!(driver.getattr_impl(req, ino)?.mtime, old);
+
+        // A change of the status after the data was buffered is reported.
+        driver.write_impl(req, ino, fh, 0, b"hello", 0, 0, None)?;
+        driver.link_impl(req, ino, dir, OsStr::new("link"))?;
+        let db_attr = driver.db.with_read_tx(|tx| queries::inode::lookup(tx, ino))?;
+        assert_eq!(driver.getattr_impl(req, ino)?.ctime, db_attr.ctime);
+        driver.unlink_impl(req, dir, OsStr::new("link"))?;
+
+        // Changing the permissions only changes the status.
+        set_times(&mut driver, ino, old)?;
+        driver.setattr_impl(
+            req,
+            ino,
+            Some(0o600),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )?;
+        let attr = driver.getattr_impl(req, ino)?;
+        assert_eq!(attr.mtime, old);
+        assert!(attr.ctime > old);
+
+        // Truncating modifies the file.
+        set_times(&mut driver, ino, old)?;
+        driver.setattr_impl(
+            req,
+            ino,
+            None,
+            None,
+            None,
+            Some(2),
+            None,
+            None,
+            None,
+            Some(fh),
+            None,
+            None,
+            None,
+            None,
+        )?;
+        let attr = driver.getattr_impl(req, ino)?;
+        assert!(attr.mtime > old && attr.ctime > old);
+
+        // Linking and unlinking modify the directories and change the status of the file.
+        set_times(&mut driver, 1, old)?;
+        set_times(&mut driver, ino, old)?;
+        driver.link_impl(req, ino, 1, OsStr::new("hard"))?;
+        assert!(driver.getattr_impl(req, 1)?.mtime > old);
+        let attr = driver.getattr_impl(req, ino)?;
+        assert_eq!(attr.mtime, old);
+        assert!(attr.ctime > old);
+        set_times(&mut driver, 1, old)?;
+        set_times(&mut driver, ino, old)?;
+        driver.unlink_impl(req, 1, OsStr::new("hard"))?;
+        assert!(driver.getattr_impl(req, 1)?.mtime > old);
+        assert!(driver.getattr_impl(req, ino)?.ctime > old);
+
+        // Renaming modifies both directories.
+        set_times(&mut driver, 1, old)?;
+        set_times(&mut driver, dir, old)?;
+        set_times(&mut driver, ino, old)?;
+        driver.rename_impl(req, dir, OsStr::new("file"), 1, OsStr::new("moved"), 0)?;
+        assert!(driver.getattr_impl(req, 1)?.mtime > old);
+        assert!(driver.getattr_impl(req, dir)?.mtime > old);
+        let attr = driver.getattr_impl(req, ino)?;
+        assert_eq!(attr.mtime, old);
+        assert!(attr.ctime > old);
+
+        // Reading follows the atime policy.
+        driver.atime = AtimePolicy::Noatime;
+        set_times(&mut driver, ino, old)?;
+        driver.read_impl(req, ino, fh, 0, 10, 0, None)?;
+        assert_eq!(driver.getattr_impl(req, ino)?.atime, old);
+
+        driver.atime = AtimePolicy::Relatime;
+        driver.read_impl(req, ino, fh, 0, 10, 0, None)?;
+        let atime = driver.getattr_impl(req, ino)?.atime;
+        assert!(atime > old);
+        // The file was not modified since it was last read an instant ago.
+        driver.read_impl(req, ino, fh, 0, 10, 0, None)?;
+        assert_eq!(driver.getattr_impl(req, ino)?.atime, atime);
+
+        driver.atime = AtimePolicy::Strictatime;
+        driver.read_impl(req, ino, fh, 0, 10, 0, None)?;
+        assert!(driver.getattr_impl(req, ino)?.atime > atime);
+
+        // Listing a directory reads it.
+        set_times(&mut driver, dir, old)?;
+        driver.readdir_impl(req, dir, 0, 0, |_| true)?;
+        let dir_attr = driver.getattr_impl(req, dir)?;
+        assert!(dir_attr.atime > old);
+        assert_eq!(dir_attr.mtime, old);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pre_epoch_times() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let ino = driver
+            .mknod_impl(req, 1, OsStr::new("old"), libc::S_IFREG | 0o644, 0, 0)?
+            .ino;
+
+        // 1960-01-01 and a time with a fraction of a second before the epoch.
+        for time in [
+            SystemTime::UNIX_EPOCH - Duration::from_secs(315_619_200),
+            SystemTime::UNIX_EPOCH - Duration::from_millis(1500),
+        ] {
+            set_times(&mut driver, ino, time)?;
+            let attr = driver.getattr_impl(req, ino)?;
+            assert_eq!((attr.atime, attr.mtime, attr.ctime), (time, time, time));
+        }
+
+        // Far in the future.
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 40);
+        set_times(&mut driver, ino, time)?;
+        assert_eq!(driver.getattr_impl(req, ino)?.mtime, time);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_locks() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        let req = RequestInfo::default();
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let (attr, fh1, _) = driver.create_impl(req, 1, OsStr::new("db"), libc::S_IFREG | 0o644, 0, flags)?;
+        let (fh2, _) = driver.open_impl(req, attr.ino, OpenFlags::from(libc::O_RDWR))?;
+        let end = i64::MAX as u64;
+
+        // Two processes share the first bytes, the second one also locks the rest of the file exclusively.
+        driver.setlk_impl(req, attr.ino, fh1, 1, 0, 99, libc::F_RDLCK, 10)?;
+        driver.setlk_impl(req, attr.ino, fh2, 2, 0, 99, libc::F_RDLCK, 20)?;
+        driver.setlk_impl(req, attr.ino, fh2, 2, 100, end, libc::F_WRLCK, 20)?;
+
+        let lock = driver.getlk_impl(req, attr.ino, fh1, 1, 0, end, libc::F_WRLCK, 10)?;
+        assert_eq!((lock.start, lock.end, lock.typ, lock.pid), (0, 99, libc::F_RDLCK, 20));
+        let lock = driver.getlk_impl(req, attr.ino, fh1, 1, 0, 99, libc::F_RDLCK, 10)?;
+        assert_eq!(lock.typ, libc::F_UNLCK);
+        assert_eq!(
+            driver.setlk_impl(req, attr.ino, fh1, 1, 50, 150, libc::F_WRLCK, 10),
+            Err(Error::WouldBlock)
+        );
+        assert_eq!(
+            driver.setlk_impl(req, attr.ino, fh1, 1, 10, 0, libc::F_WRLCK, 10),
+            Err(Error::InvalidArgument)
+        );
+
+        // Closing a descriptor releases the locks of the process.
+        driver.flush_impl(req, attr.ino, fh2, 2)?;
+        driver.setlk_impl(req, attr.ino, fh1, 1, 50, 150, libc::F_WRLCK, 10)?;
+
+        // flock locks are released with the file description.
+        driver.setlk_impl(req, attr.ino, fh1, 1, 0, end, libc::F_UNLCK, 10)?;
+        driver.setlk_impl(req, attr.ino, fh2, 0xf2, 0, end, libc::F_WRLCK, 20)?;
+        assert_eq!(
+            driver.setlk_impl(req, attr.ino, fh1, 0xf1, 0, end, libc::F_RDLCK, 10),
+            Err(Error::WouldBlock)
+        );
+        driver.release_impl(req, attr.ino, fh2, 0, Some(0xf2), true)?;
+        driver.setlk_impl(req, attr.ino, fh1, 0xf1, 0, end, libc::F_RDLCK, 10)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_wait() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        // The replies are sent through a channel checked after each request.
+        fn reply() -> (LockReply, mpsc::Receiver<Result<()>>) {
+            let (tx, rx) = mpsc::channel();
+            (Box::new(move |res| tx.send(res).unwrap()), rx)
+        }
+
+        let req = RequestInfo::default();
+        let flags = OpenFlags::from(libc::O_RDWR | libc::O_CREAT);
+        let (attr, fh1, _) = driver.create_impl(req, 1, OsStr::new("db"), libc::S_IFREG | 0o644, 0, flags)?;
+        let (fh2, _) = driver.open_impl(req, attr.ino, OpenFlags::from(libc::O_RDWR))?;
+        let (fh3, _) = driver.open_impl(req, attr.ino, OpenFlags::from(libc::O_RDWR))?;
+        let end = i64::MAX as u64;
+
+        // A free lock is granted right away.
+        let (r1, rx1) = reply();
+        driver.setlkw_impl(req, attr.ino, fh1, 1, 0, end, libc::F_WRLCK, 10, r1);
+        assert_eq!(rx1.try_recv()?, Ok(()));
+
+        // Conflicting requests wait for the lock to be released.
+        let (r2, rx2) = reply();
+        driver.setlkw_impl(req, attr.ino, fh2, 2, 0, 99, libc::F_RDLCK, 20, r2);
+        let (r3, rx3) = reply();
+        driver.setlkw_impl(req, attr.ino, fh3, 3, 0, end, libc::F_WRLCK, 30, r3);
+        assert!(rx2.try_recv().is_err());
+        assert!(rx3.try_recv().is_err());
+
+        driver.flush_impl(req, attr.ino, fh1, 1)?;
+        assert_eq!(rx2.try_recv()?, Ok(()));
+        assert!(rx3.try_recv().is_err());
+
+        // Waiting for a lock held by an owner waiting for us deadlocks.
+        let (r1, rx1) = reply();
+        driver.setlkw_impl(req, attr.ino, fh1, 1, 100, 199, libc::F_WRLCK, 10, r1);
+        assert_eq!(rx1.try_recv()?, Ok(()));
+        let (r2, rx2) = reply();
+        driver.setlkw_impl(req, attr.ino, fh2, 2, 100, 199, libc::F_RDLCK, 20, r2);
+        assert!(rx2.try_recv().is_err());
+        let (r1, rx1) = reply();
+        driver.setlkw_impl(req, attr.ino, fh1, 1, 0, 99, libc::F_WRLCK, 10, r1);
+        assert_eq!(rx1.try_recv()?, Err(Error::Deadlock));
+
+        // Closing a descriptor cancels the pending requests of the process, its other requests are granted.
+        driver.flush_impl(req, attr.ino, fh3, 3)?;
+        assert_eq!(rx3.try_recv()?, Err(Error::Interrupted));
+        driver.flush_impl(req, attr.ino, fh1, 1)?;
+        assert_eq!(rx2.try_recv()?, Ok(()));
+        assert_eq!(
+            driver.setlk_impl(req, attr.ino, fh1, 1, 0, end, libc::F_WRLCK, 10),
+            Err(Error::WouldBlock)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_statfs() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+        driver.ensure_root_exists()?;
+
+        driver.mknod_impl(
+            RequestInfo::default(),
+            1,
+            OsStr::new("foo"),
+            libc::S_IFREG | 0o644,
+            0,
+            0,
+        )?;
+        driver.mkdir_impl(RequestInfo::default(), 1, OsStr::new("bar"), 0o755, 0)?;
+
+        let st = driver.statfs_impl(RequestInfo::default(), 1)?;
+        let usage = driver.db.usage()?;
+        assert!(usage.page_count > 0);
+        // In-memory database, there is no host filesystem.
+        assert_eq!(st.blocks, usage.page_count);
+        assert_eq!(st.bfree, usage.freelist_count);
+        assert_eq!(st.files - st.ffree, 3);
+        assert_eq!(st.frsize as u64, usage.page_size);
+        assert_eq!(st.namelen, 255);
+
+        // More space is reserved for root on the host than the database uses.
+        let usage = DatabaseUsage {
+            page_size: 4096,
+            page_count: 10,
+            freelist_count: 2,
+            host_free: 1000 * 4096,
+            host_avail: 900 * 4096,
+        };
+        let st = StatFs::new(&usage, 3);
+        assert!(st.bavail <= st.bfree && st.bfree <= st.blocks);
+        assert_eq!((st.blocks, st.bfree, st.bavail), (1010, 1002, 902));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_xattr() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+
+        let mut root_dir = FileAttrBuilder::new_directory().build();
+        let mut node = FileAttrBuilder::new_node(FileType::RegularFile).build();
+
+        driver.db.with_write_tx(|tx| {
+            queries::inode::create(tx, &mut root_dir)?;
+            queries::inode::create(tx, &mut node)?;
+            queries::dir_entry::create(tx, root_dir.ino, OsStr::new("foo.txt"), node.ino)?;
+            Ok(())
+        })?;
+
+        let req = RequestInfo::default();
+        let name = OsStr::new("user.comment");
+
+        let res = driver.getxattr_impl(req, node.ino, name, 0);
+        assert_eq!(res, Err(Error::NoData));
+
+        // XATTR_REPLACE requires the attribute to exist
+        let res = driver.setxattr_impl(req, node.ino, name, b"hello", libc::XATTR_REPLACE, 0);
+        assert_eq!(res, Err(Error::NoData));
+
+        driver.setxattr_impl(req, node.ino, name, b"hello", libc::XATTR_CREATE, 0)?;
+        driver.setxattr_impl(req, node.ino, OsStr::new("user.other"), b"", 0, 0)?;
+
+        // XATTR_CREATE requires the attribute to not exist
+        let res = driver.setxattr_impl(req, node.ino, name, b"hello", libc::XATTR_CREATE, 0);
+        assert_eq!(res, Err(Error::AlreadyExists));
+
+        driver.setxattr_impl(req, node.ino, name, b"hello world", libc::XATTR_REPLACE, 0)?;
+
+        assert_eq!(driver.getxattr_impl(req, node.ino, name, 0)?, XattrReply::Size(11));
+        assert_eq!(
+            driver.getxattr_impl(req, node.ino, name, 11)?,
+            XattrReply::Data(b"hello world".to_vec())
+        );
+        assert_eq!(driver.getxattr_impl(req, node.ino, name, 5), Err(Error::OutOfRange));
+
+        assert_eq!(driver.listxattr_impl(req, node.ino, 0)?, XattrReply::Size(24));
+        assert_eq!(
+            driver.listxattr_impl(req, node.ino, 100)?,
+            XattrReply::Data(b"user.comment\0user.other\0".to_vec())
+        );
+        assert_eq!(driver.listxattr_impl(req, node.ino, 10), Err(Error::OutOfRange));
+
+        driver.removexattr_impl(req, node.ino, OsStr::new("user.other"))?;
+        let res = driver.removexattr_impl(req, node.ino, OsStr::new("user.other"));
+        assert_eq!(res, Err(Error::NoData));
+
+        // Attributes are removed with the inode
+        driver.unlink_impl(req, root_dir.ino, OsStr::new("foo.txt"))?;
+        let names = driver.db.with_read_tx(|tx| queries::xattr::list(tx, node.ino))?;
+        assert!(names.is_empty());
+
+        Ok(())
+    }
+
+    fn user(uid: u32, gid: u32) -> RequestInfo {
+        RequestInfo { uid, gid, pid: 0 }
+    }
+
+    fn driver_root(tx: &mut rusqlite::Transaction) -> crate::errors::Result<()> {
+        let mut root_dir = FileAttrBuilder::new_directory().build();
+        queries::inode::create(tx, &mut root_dir)
+    }
+
+    fn acl_bytes(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut data = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&perm.to_le_bytes());
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_access_checks() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+
+        let mut home_dir = FileAttrBuilder::new_directory().with_uid(1000).with_gid(1000).build();
+        let mut node = FileAttrBuilder::new_node(FileType::RegularFile)
+            .with_uid(1000)
+            .with_gid(100)
+            .with_mode_umask(0o640, 0)
+            .build();
+
+        driver.db.with_write_tx(|tx| {
+            // The root directory belongs to the mount owner, use a directory inside of it.
+            driver_root(tx)?;
+            queries::inode::create(tx, &mut home_dir)?;
+            queries::inode::create(tx, &mut node)?;
+            queries::dir_entry::create(tx, home_dir.ino, OsStr::new("foo.txt"), node.ino)?;
+            Ok(())
+        })?;
+
+        // Owner, group and others
+        driver.open_impl(user(1000, 1000), node.ino, OpenFlags::from(libc::O_RDWR))?;
+        driver.open_impl(user(2000, 100), node.ino, OpenFlags::from(libc::O_RDONLY))?;
+        let res = driver.open_impl(user(2000, 100), node.ino, OpenFlags::from(libc::O_RDWR));
+        assert_eq!(res, Err(Error::PermissionDenied));
+        let res = driver.open_impl(user(3000, 3000), node.ino, OpenFlags::from(libc::O_RDONLY));
+        assert_eq!(res, Err(Error::PermissionDenied));
+
+        // root can do anything
+        driver.open_impl(user(0, 0), node.ino, OpenFlags::from(libc::O_RDWR))?;
+
+        driver.access_impl(user(2000, 2000), home_dir.ino, libc::R_OK | libc::X_OK)?;
+        let res = driver.access_impl(user(2000, 2000), home_dir.ino, libc::W_OK);
+        assert_eq!(res, Err(Error::PermissionDenied));
+
+        // Modifying the directory requires write permission on it
+        let res = driver.mkdir_impl(user(2000, 2000), home_dir.ino, OsStr::new("dir"), 0o755, 0);
+        assert_eq!(res, Err(Error::PermissionDenied));
+        let res = driver.unlink_impl(user(2000, 2000), home_dir.ino, OsStr::new("foo.txt"));
+        assert_eq!(res, Err(Error::PermissionDenied));
+        driver.mkdir_impl(user(1000, 1000), home_dir.ino, OsStr::new("dir"), 0o755, 0)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_permission_model() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+
+        // Shared directory with the sticky bit, like /tmp
+        let mut tmp_dir = FileAttrBuilder::new_directory()
+            .with_mode_umask(0o1777, 0)
+            .with_uid(1000)
+            .with_gid(1000)
+            .build();
+        let mut private_dir = FileAttrBuilder::new_directory()
+            .with_mode_umask(0o700, 0)
+            .with_uid(1000)
+            .with_gid(1000)
+            .build();
+
+        driver.db.with_write_tx(|tx| {
+            driver_root(tx)?;
+            queries::inode::create(tx, &mut tmp_dir)?;
+            queries::inode::create(tx, &mut private_dir)?;
+            queries::dir_entry::create(tx, private_dir.ino, OsStr::new("secret"), tmp_dir.ino)?;
+            Ok(())
+        })?;
+
+        // Lookup requires search permission on the parent
+        let res = driver.lookup_impl(user(2000, 2000), private_dir.ino, OsStr::new("secret"));
+        assert_eq!(res, Err(Error::PermissionDenied));
+        driver.lookup_impl(user(1000, 1000), private_dir.ino, OsStr::new("secret"))?;
+
+        // Sticky bit: only the owner of the entry or of the directory can remove it
+        let file = driver.mknod_impl(
+            user(2000, 2000),
+            tmp_dir.ino,
+            OsStr::new("a"),
+            libc::S_IFREG | 0o666,
+            0,
+            0,
+        )?;
+        driver.mknod_impl(
+            user(2000, 2000),
+            tmp_dir.ino,
+            OsStr::new("b"),
+            libc::S_IFREG | 0o666,
+            0,
+            0,
+        )?;
+        let res = driver.unlink_impl(user(3000, 3000), tmp_dir.ino, OsStr::new("a"));
+        assert_eq!(res, Err(Error::NotPermitted));
+        let res = driver.rename_impl(
+            user(3000, 3000),
+            tmp_dir.ino,
+            OsStr::new("a"),
+            tmp_dir.ino,
+            OsStr::new("c"),
+            0,
+        );
+        assert_eq!(res, Err(Error::NotPermitted));
+        driver.unlink_impl(user(1000, 1000), tmp_dir.ino, OsStr::new("b"))?;
+
+        let setattr = |driver: &mut FuseDriver,
+                       req: RequestInfo,
+                       mode: Option<u32>,
+                       uid: Option<u32>,
+                       gid: Option<u32>,
+                       size: Option<u64>,
+                       mtime: Option<TimeOrNow>| {
+            driver.setattr_impl(
+                req, file.ino, mode, uid, gid, size, None, mtime, None, None, None, None, None, None,
+            )
+        };
+
+        // chmod and chown are restricted to the owner, giving a file away is restricted to root
+        let res = setattr(&mut driver, user(3000, 3000), Some(0o600), None, None, None, None);
+        assert_eq!(res, Err(Error::NotPermitted));
+        let res = setattr(&mut driver, user(2000, 2000), None, Some(3000), None, None, None);
+        assert_eq!(res, Err(Error::NotPermitted));
+        let res = setattr(&mut driver, user(2000, 2000), None, None, Some(4000), None, None);
+        assert_eq!(res, Err(Error::NotPermitted));
+        let attr = setattr(&mut driver, user(2000, 2000), Some(0o644), None, Some(2000), None, None)?;
+        assert_eq!((attr.perm, attr.gid), (0o644, 2000));
+        let attr = setattr(&mut driver, user(0, 0), None, Some(3000), Some(3000), None, None)?;
+        assert_eq!((attr.uid, attr.gid), (3000, 3000));
+
+        // Truncate requires write permission, setting a specific time requires ownership
+        let res = setattr(&mut driver, user(2000, 2000), None, None, None, Some(0), None);
+        assert_eq!(res, Err(Error::PermissionDenied));
+        setattr(&mut driver, user(3000, 3000), None, None, None, Some(0), None)?;
+        let res = setattr(
+            &mut driver,
+            user(2000, 2000),
+            None,
+            None,
+            None,
+            None,
+            Some(TimeOrNow::Now),
+        );
+        assert_eq!(res, Err(Error::PermissionDenied));
+        setattr(&mut driver, user(0, 0), Some(0o666), None, None, None, None)?;
+        setattr(
+            &mut driver,
+            user(2000, 2000),
+            None,
+            None,
+            None,
+            None,
+            Some(TimeOrNow::Now),
+        )?;
+        let res = setattr(
+            &mut driver,
+            user(2000, 2000),
+            None,
+            None,
+            None,
+            None,
+            Some(TimeOrNow::SpecificTime(std::time::SystemTime::UNIX_EPOCH)),
+        );
+        assert_eq!(res, Err(Error::NotPermitted));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_setid_bits() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+
+        let mut shared = FileAttrBuilder::new_directory()
+            .with_mode_umask(0o2777, 0)
+            .with_gid(100)
+            .build();
+        driver.db.with_write_tx(|tx| {
+            driver_root(tx)?;
+            queries::inode::create(tx, &mut shared)?;
+            queries::dir_entry::create(tx, 1, OsStr::new("shared"), shared.ino)?;
+            Ok(())
+        })?;
+
+        let root = RequestInfo::default();
+        let alice = user(1000, 1000);
+        let bob = user(2000, 100);
+
+        // New entries in a set-group-ID directory belong to its group and subdirectories inherit the bit.
+        let dir = driver.mkdir_impl(alice, shared.ino, OsStr::new("dir"), 0o755, 0)?;
+        assert_eq!((dir.gid, dir.perm), (100, 0o2755));
+        // Only members of the group can create set-group-ID files.
+        let file = driver.mknod_impl(alice, shared.ino, OsStr::new("file"), libc::S_IFREG | 0o2755, 0, 0)?;
+        assert_eq!((file.gid, file.perm), (100, 0o755));
+        let other = driver.mknod_impl(bob, shared.ino, OsStr::new("other"), libc::S_IFREG | 0o2755, 0, 0)?;
+        assert_eq!((other.gid, other.perm), (100, 0o2755));
+
+        let setattr = |driver: &mut FuseDriver,
+                       req: RequestInfo,
+                       ino: u64,
+                       mode: Option<u32>,
+                       uid: Option<u32>,
+                       gid: Option<u32>,
+                       size: Option<u64>| {
+            driver.setattr_impl(
+                req, ino, mode, uid, gid, size, None, None, None, None, None, None, None, None,
+            )
+        };
+
+        // The owner cannot make the file set-group-ID for a group they do not belong to.
+        let attr = setattr(&mut driver, alice, file.ino, Some(0o6755), None, None, None)?;
+        assert_eq!(attr.perm, 0o4755);
+
+        // Writing without being root drops the set-user-ID bit.
+        let (fh, _) = driver.open_impl(alice, file.ino, OpenFlags::from(libc::O_WRONLY))?;
+        driver.write_impl(alice, file.ino, fh, 0, b"data", 0, 0, None)?;
+        assert_eq!(driver.getattr_impl(root, file.ino)?.perm, 0o755);
+        // The bits are checked again once the mode changes while the file is open.
+        setattr(&mut driver, root, file.ino, Some(0o4755), None, None, None)?;
+        driver.write_impl(alice, file.ino, fh, 4, b"more", 0, 0, None)?;
+        assert_eq!(driver.getattr_impl(root, file.ino)?.perm, 0o755);
+        driver.release_impl(alice, file.ino, fh, 0, None, true)?;
+
+        // Root keeps the bits when writing.
+        setattr(&mut driver, root, file.ino, Some(0o6755), None, None, None)?;
+        let (fh, _) = driver.open_impl(root, file.ino, OpenFlags::from(libc::O_WRONLY))?;
+        driver.write_impl(root, file.ino, fh, 0, b"data", 0, 0, None)?;
+        assert_eq!(driver.getattr_impl(root, file.ino)?.perm, 0o6755);
+        driver.release_impl(root, file.ino, fh, 0, None, true)?;
+
+        // Changing the owner drops both bits when the group can execute the file.
+        let attr = setattr(&mut driver, root, file.ino, None, Some(2000), None, None)?;
+        assert_eq!((attr.uid, attr.perm), (2000, 0o755));
+        setattr(&mut driver, root, file.ino, Some(0o2745), None, None, None)?;
+        let attr = setattr(&mut driver, root, file.ino, None, None, Some(1000), None)?;
+        assert_eq!((attr.gid, attr.perm), (1000, 0o2745));
+
+        // Truncating without being root drops the set-user-ID bit.
+        setattr(&mut driver, root, file.ino, Some(0o4755), None, None, None)?;
+        let attr = setattr(&mut driver, bob, file.ino, None, None, None, Some(0))?;
+        assert_eq!(attr.perm, 0o755);
+
+        // Directories keep their bits.
+        let attr = setattr(&mut driver, root, dir.ino, None, Some(2000), None, None)?;
+        assert_eq!(attr.perm, 0o2755);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_acl_enforcement() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+
+        let mut home_dir = FileAttrBuilder::new_directory().with_uid(1000).with_gid(1000).build();
+        let mut node = FileAttrBuilder::new_node(FileType::RegularFile)
+            .with_uid(1000)
+            .with_gid(1000)
+            .with_mode_umask(0o600, 0)
+            .build();
+
+        driver.db.with_write_tx(|tx| {
+            // The root directory belongs to the mount owner, use a directory inside of it.
+            driver_root(tx)?;
+            queries::inode::create(tx, &mut home_dir)?;
+            queries::inode::create(tx, &mut node)?;
+            queries::dir_entry::create(tx, home_dir.ino, OsStr::new("foo.txt"), node.ino)?;
+            Ok(())
+        })?;
+
+        let res = driver.open_impl(user(2000, 2000), node.ino, OpenFlags::from(libc::O_RDONLY));
+        assert_eq!(res, Err(Error::PermissionDenied));
+
+        // user::rw- user:2000:r-- group::--- mask::r-- other::---
+        let acl = acl_bytes(&[
+            (0x01, 0o6, u32::MAX),
+            (0x02, 0o4, 2000),
+            (0x04, 0o0, u32::MAX),
+            (0x10, 0o4, u32::MAX),
+            (0x20, 0o0, u32::MAX),
+        ]);
+        let acl_name = OsStr::new("system.posix_acl_access");
+
+        // Only the owner can change the ACL
+        let res = driver.setxattr_impl(user(2000, 2000), node.ino, acl_name, &acl, 0, 0);
+        assert_eq!(res, Err(Error::NotPermitted));
+        driver.setxattr_impl(user(1000, 1000), node.ino, acl_name, &acl, 0, 0)?;
+
+        driver.open_impl(user(2000, 2000), node.ino, OpenFlags::from(libc::O_RDONLY))?;
+        let res = driver.open_impl(user(2000, 2000), node.ino, OpenFlags::from(libc::O_RDWR));
+        assert_eq!(res, Err(Error::PermissionDenied));
+        let res = driver.open_impl(user(3000, 3000), node.ino, OpenFlags::from(libc::O_RDONLY));
+        assert_eq!(res, Err(Error::PermissionDenied));
+
+        // The group bits report the mask
+        let attr = driver.getattr_impl(user(1000, 1000), node.ino)?;
+        assert_eq!(attr.perm, 0o640);
+
+        // chmod updates the mask
+        driver.setattr_impl(
+            user(1000, 1000),
+            node.ino,
+            Some(0o600),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )?;
+        let res = driver.open_impl(user(2000, 2000), node.ino, OpenFlags::from(libc::O_RDONLY));
+        assert_eq!(res, Err(Error::PermissionDenied));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_acl_inheritance() -> anyhow::Result<()> {
+        let db = DatabaseOps::open_in_memory()?;
+        let mut driver = FuseDriver::new_no_io(db, Compression::None);
+
+        let mut home_dir = FileAttrBuilder::new_directory().with_uid(1000).with_gid(1000).build();
+
+        driver.db.with_write_tx(|tx| {
+            // The root directory belongs to the mount owner, use a directory inside of it.
+            driver_root(tx)?;
+            queries::inode::create(tx, &mut home_dir)?;
+            Ok(())
+        })?;
+
+        // user::rwx user:2000:rwx group::r-x mask::rwx other::---
+        let acl = acl_bytes(&[
+            (0x01, 0o7, u32::MAX),
+            (0x02, 0o7, 2000),
+            (0x04, 0o5, u32::MAX),
+            (0x10, 0o7, u32::MAX),
+            (0x20, 0o0, u32::MAX),
+        ]);
+        let default_acl_name = OsStr::new("system.posix_acl_default");
+        driver.setxattr_impl(user(1000, 1000), home_dir.ino, default_acl_name, &acl, 0, 0)?;
+
+        // The umask is ignored when there is a default ACL
+        let file = driver.mknod_impl(
+            user(1000, 1000),
+            home_dir.ino,
+            OsStr::new("foo.txt"),
+            libc::S_IFREG | 0o666,
+            0o022,
+            0,
+        )?;
+        assert_eq!(file.perm, 0o660);
+        driver.open_impl(user(2000, 2000), file.ino, OpenFlags::from(libc::O_RDWR))?;
+        let res = driver.getxattr_impl(user(1000, 1000), file.ino, default_acl_name, 0);
+        assert_eq!(res, Err(Error::NoData));
+
+        // Directories also inherit the default ACL
+        let dir = driver.mkdir_impl(user(1000, 1000), home_dir.ino, OsStr::new("dir"), 0o775, 0o022)?;
+        assert_eq!(dir.perm, 0o770);
+        let res = driver.getxattr_impl(user(1000, 1000), dir.ino, default_acl_name, 100)?;
+        assert_eq!(res, XattrReply::Data(acl.clone()));
+        driver.mknod_impl(
+            user(2000, 2000),
+            dir.ino,
+            OsStr::new("bar.txt"),
+            libc::S_IFREG | 0o644,
+            0o022,
+            0,
+        )?;
+
+        // Default ACLs only make sense on directories
+        let res = driver.setxattr_impl(user(1000, 1000), file.ino, default_acl_name, &acl, 0, 0);
+        assert_eq!(res, Err(Error::PermissionDenied));
 
         Ok(())
     }
@@ -955,6 +4570,7 @@ mod tests {
 
             let db = DatabaseOps::open_in_memory()?;
             let mut driver = FuseDriver::new_no_io(db, compression);
+            driver.ensure_root_exists()?;
 
             let attr = driver.mknod_impl(RequestInfo::default(), 1, OsStr::new("foo"), libc::S_IFREG, 0, 0)?;
             let (fh, _) = driver.open_impl(RequestInfo::default(), attr.ino, OpenFlags::from(libc::O_RDWR))?;
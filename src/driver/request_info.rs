@@ -1,11 +1,36 @@
+use std::fs;
+
 #[derive(Clone, Copy, Debug, Default)]
 pub struct RequestInfo {
     pub uid: u32,
     pub gid: u32,
-    #[allow(dead_code)]
     pub pid: u32,
 }
 
+impl RequestInfo {
+    /// Check if the requester belongs to the group, as its primary group or as one of the supplementary groups of
+    /// the requesting process.
+    pub fn in_group(&self, gid: u32) -> bool {
+        gid == self.gid || supplementary_groups(self.pid).contains(&gid)
+    }
+}
+
+/// FUSE requests only carry the primary group, the supplementary groups of the process are read from procfs. If the
+/// process is gone, it is treated as having none.
+fn supplementary_groups(pid: u32) -> Vec<u32> {
+    if pid == 0 {
+        return Vec::new();
+    }
+    let Ok(status) = fs::read_to_string(format!("/proc/{pid}/status")) else {
+        return Vec::new();
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| groups.split_whitespace().filter_map(|g| g.parse().ok()).collect())
+        .unwrap_or_default()
+}
+
 impl<'a> From<&'a fuser::Request<'a>> for RequestInfo {
     fn from(r: &fuser::Request) -> Self {
         Self {
@@ -0,0 +1,38 @@
+use crate::database::DatabaseUsage;
+use crate::queries::block::BLOCK_SIZE;
+use crate::queries::dir_entry::NAME_MAX;
+
+/// Filesystem statistics reported to `df`. Sizes are counted in database pages.
+#[derive(Debug, PartialEq, Eq)]
+pub struct StatFs {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32,
+}
+
+impl StatFs {
+    pub fn new(usage: &DatabaseUsage, inodes: u64) -> StatFs {
+        let page_size = usage.page_size.max(1);
+        // The database can grow until the host filesystem is full, the pages in the freelist are reused first.
+        let bfree = usage.freelist_count + usage.host_free / page_size;
+        let bavail = usage.freelist_count + usage.host_avail / page_size;
+        // Inodes are rows in the database, there is no fixed limit. Every free page could hold at least one.
+        let ffree = bavail;
+        StatFs {
+            // The space reserved for root on the host is counted in the total, so that bavail <= bfree <= blocks.
+            blocks: usage.page_count + usage.host_free / page_size,
+            bfree,
+            bavail,
+            files: inodes + ffree,
+            ffree,
+            bsize: BLOCK_SIZE as u32,
+            namelen: NAME_MAX as u32,
+            frsize: page_size as u32,
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use crate::errors::{Error, Result};
+
+/// Reply to getxattr/listxattr. When the caller passes a size of 0, it only wants to know how big
+/// of a buffer it needs to allocate.
+#[derive(Debug, PartialEq, Eq)]
+pub enum XattrReply {
+    Size(u32),
+    Data(Vec<u8>),
+}
+
+impl XattrReply {
+    pub fn new(data: Vec<u8>, size: u32) -> Result<XattrReply> {
+        let len = u32::try_from(data.len()).map_err(|_| Error::Overflow)?;
+        if size == 0 {
+            Ok(XattrReply::Size(len))
+        } else if len > size {
+            Err(Error::OutOfRange)
+        } else {
+            Ok(XattrReply::Data(data))
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use rusqlite::types::FromSqlError;
+use rusqlite::{ffi, types::FromSqlError, ErrorCode};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -8,6 +8,22 @@ pub enum Error {
     NotFound,
     InvalidArgument,
     Overflow,
+    AlreadyExists,
+    NoData,
+    OutOfRange,
+    PermissionDenied,
+    NotPermitted,
+    NoSuchAddress,
+    NotADirectory,
+    IsADirectory,
+    NotSupported,
+    NoSpace,
+    ReadOnly,
+    NameTooLong,
+    Io,
+    WouldBlock,
+    Deadlock,
+    Interrupted,
     Other(String),
     InvalidCompression,
 }
@@ -19,8 +35,24 @@ impl Error {
             Error::NotFound => libc::ENOENT,
             Error::InvalidArgument => libc::EINVAL,
             Error::Overflow => libc::EOVERFLOW,
+            Error::AlreadyExists => libc::EEXIST,
+            Error::NoData => libc::ENODATA,
+            Error::OutOfRange => libc::ERANGE,
+            Error::PermissionDenied => libc::EACCES,
+            Error::NotPermitted => libc::EPERM,
+            Error::NoSuchAddress => libc::ENXIO,
+            Error::NotADirectory => libc::ENOTDIR,
+            Error::IsADirectory => libc::EISDIR,
+            Error::NotSupported => libc::EOPNOTSUPP,
+            Error::NoSpace => libc::ENOSPC,
+            Error::ReadOnly => libc::EROFS,
+            Error::NameTooLong => libc::ENAMETOOLONG,
+            Error::Io => libc::EIO,
+            Error::WouldBlock => libc::EAGAIN,
+            Error::Deadlock => libc::EDEADLK,
+            Error::Interrupted => libc::EINTR,
             Error::InvalidCompression => libc::EINVAL,
-            Error::Other(_) => libc::ENOTSUP, // Need better code
+            Error::Other(_) => libc::EIO,
         }
     }
 }
@@ -29,6 +61,18 @@ impl From<rusqlite::Error> for Error {
     fn from(err: rusqlite::Error) -> Self {
         match err {
             rusqlite::Error::QueryReturnedNoRows => Error::NotFound,
+            rusqlite::Error::SqliteFailure(e, _) => match e.code {
+                ErrorCode::ConstraintViolation
+                    if e.extended_code == ffi::SQLITE_CONSTRAINT_UNIQUE
+                        || e.extended_code == ffi::SQLITE_CONSTRAINT_PRIMARYKEY =>
+                {
+                    Error::AlreadyExists
+                }
+                ErrorCode::DiskFull => Error::NoSpace,
+                ErrorCode::ReadOnly => Error::ReadOnly,
+                ErrorCode::SystemIoFailure => Error::Io,
+                _ => Error::Other(err.to_string()),
+            },
             _ => Error::Other(err.to_string()),
         }
     }
@@ -47,6 +91,22 @@ impl std::fmt::Display for Error {
             Error::NotFound => write!(f, "Not Found"),
             Error::InvalidArgument => write!(f, "Invalid Argument"),
             Error::Overflow => write!(f, "Overflow"),
+            Error::AlreadyExists => write!(f, "Already Exists"),
+            Error::NoData => write!(f, "No Data"),
+            Error::OutOfRange => write!(f, "Out Of Range"),
+            Error::PermissionDenied => write!(f, "Permission Denied"),
+            Error::NotPermitted => write!(f, "Operation Not Permitted"),
+            Error::NoSuchAddress => write!(f, "No Such Device Or Address"),
+            Error::NotADirectory => write!(f, "Not A Directory"),
+            Error::IsADirectory => write!(f, "Is A Directory"),
+            Error::NotSupported => write!(f, "Operation Not Supported"),
+            Error::NoSpace => write!(f, "No Space Left"),
+            Error::ReadOnly => write!(f, "Read Only Filesystem"),
+            Error::NameTooLong => write!(f, "Name Too Long"),
+            Error::Io => write!(f, "I/O Error"),
+            Error::WouldBlock => write!(f, "Resource Temporarily Unavailable"),
+            Error::Deadlock => write!(f, "Resource Deadlock Avoided"),
+            Error::Interrupted => write!(f, "Interrupted"),
             Error::InvalidCompression => write!(f, "Invalid Compression Scheme"),
             Error::Other(msg) => write!(f, "Other: {}", msg),
         }
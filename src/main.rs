@@ -8,8 +8,10 @@ mod time;
 mod types;
 
 use std::{
+    ffi::OsStr,
     fs,
-    path::PathBuf,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -21,11 +23,13 @@ use std::{
 
 use anyhow::{bail, Context};
 use clap::{Parser, Subcommand};
-use queries::block::Compression;
+use fuser::MountOption;
+use queries::block::{Compression, Storage};
 use scopeguard::defer;
 
 use crate::database::DatabaseOps;
-use crate::driver::FuseDriver;
+use crate::driver::{AtimePolicy, FuseDriver};
+use crate::time::TimeSpec;
 use simple_logger::SimpleLogger;
 
 #[derive(Parser, Debug)]
@@ -50,6 +54,15 @@ enum Commands {
         #[arg(long = "compress", short = 'c', help = "Compression algorithm")]
         compression: Option<Compression>,
 
+        #[arg(long = "atime", help = "When to update the access time of files")]
+        atime: Option<AtimePolicy>,
+
+        #[arg(long = "dedup", help = "Store the data of identical blocks once")]
+        dedup: bool,
+
+        #[arg(long = "snapshot", help = "Mount the snapshot with this name read-only")]
+        snapshot: Option<String>,
+
         #[clap(flatten)]
         key_group: KeyGroup,
     },
@@ -63,6 +76,12 @@ enum Commands {
         #[arg(long = "compress", short = 'c', help = "Compression algorithm")]
         compression: Option<Compression>,
 
+        #[arg(long = "atime", help = "When to update the access time of files")]
+        atime: Option<AtimePolicy>,
+
+        #[arg(long = "dedup", help = "Store the data of identical blocks once")]
+        dedup: bool,
+
         #[clap(flatten)]
         key_group: KeyGroup,
 
@@ -80,6 +99,115 @@ enum Commands {
         #[clap(flatten)]
         key_group: KeyGroup,
     },
+    /// Manage the read-only snapshots of the filesystem stored in the database.
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommands,
+    },
+    /// Show the settings stored in the database, or change them.
+    Settings {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+
+        #[arg(long = "set", value_name = "NAME=VALUE", help = "Change a setting")]
+        set: Vec<String>,
+    },
+    /// Manage the previous versions of the files.
+    Versions {
+        #[command(subcommand)]
+        command: VersionCommands,
+    },
+    /// Show how much space the data of the files uses in the database.
+    Stats {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SnapshotCommands {
+    /// Copy the current state of the filesystem into a new snapshot.
+    Create {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+
+        #[arg(long = "name", help = "Name of the snapshot")]
+        name: String,
+    },
+    /// List the snapshots.
+    List {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+    },
+    /// Delete a snapshot and the data only it references.
+    Delete {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+
+        #[arg(long = "name", help = "Name of the snapshot")]
+        name: String,
+    },
+    /// List the paths added, removed or modified between two snapshots.
+    Diff {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+
+        #[arg(long = "from", help = "Name of the older snapshot")]
+        from: String,
+
+        #[arg(
+            long = "to",
+            help = "Name of the newer snapshot, the current filesystem if not given"
+        )]
+        to: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum VersionCommands {
+    /// List the previous versions of a file, and of the deleted files that were at its path.
+    List {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+
+        #[arg(long = "path", help = "Path of the file inside the filesystem")]
+        path: PathBuf,
+    },
+    /// Replace the content of a file with one of its previous versions, a deleted file is created again.
+    Restore {
+        #[arg(long = "db", help = "Database file path")]
+        database_path: PathBuf,
+
+        #[clap(flatten)]
+        key_group: KeyGroup,
+
+        #[arg(long = "path", help = "Path of the file inside the filesystem")]
+        path: PathBuf,
+
+        #[arg(long = "id", help = "Id of the version, as listed")]
+        id: i64,
+    },
 }
 
 #[derive(Debug, clap::Args)]
@@ -124,12 +252,29 @@ fn main() -> anyhow::Result<()> {
             database_path,
             mount_path,
             compression,
+            atime,
+            dedup,
+            snapshot,
             key_group,
         } => {
-            let db = DatabaseOps::open(&database_path, key_group.read_key()?).context("open db")?;
-            let driver = FuseDriver::new(db, compression.unwrap_or_default(), &mount_path)?;
+            let mut db = DatabaseOps::open(&database_path, key_group.read_key()?).context("open db")?;
+            let mut options = Vec::new();
+            if let Some(name) = snapshot {
+                db.use_snapshot(&name)
+                    .with_context(|| format!("open snapshot {:?}", name))?;
+                options.push(MountOption::RO);
+            }
+            let driver = FuseDriver::new(
+                db,
+                Storage {
+                    compression: compression.unwrap_or_default(),
+                    dedup,
+                },
+                atime.unwrap_or_default(),
+                &mount_path,
+            )?;
 
-            let mount = fuser::spawn_mount2(driver, &mount_path, &[]).context("unable to create mount")?;
+            let mount = fuser::spawn_mount2(driver, &mount_path, &options).context("unable to create mount")?;
             defer! {
                 // Umount & cleanup
                 mount.join();
@@ -146,12 +291,22 @@ fn main() -> anyhow::Result<()> {
             database_path,
             mount_path,
             compression,
+            atime,
+            dedup,
             key_group,
             cmd,
             args,
         } => {
             let db = DatabaseOps::open(&database_path, key_group.read_key()?).context("open db")?;
-            let driver = FuseDriver::new(db, compression.unwrap_or_default(), &mount_path)?;
+            let driver = FuseDriver::new(
+                db,
+                Storage {
+                    compression: compression.unwrap_or_default(),
+                    dedup,
+                },
+                atime.unwrap_or_default(),
+                &mount_path,
+            )?;
             let mount = fuser::spawn_mount2(driver, &mount_path, &[]).context("unable to create mount")?;
             defer! {
                 // Umount & cleanup
@@ -187,7 +342,146 @@ fn main() -> anyhow::Result<()> {
             db.vacuum()?;
             println!("Done!");
         }
+        Commands::Snapshot { command } => run_snapshot_command(command)?,
+        Commands::Settings {
+            database_path,
+            key_group,
+            set,
+        } => {
+            let mut db = DatabaseOps::open(&database_path, key_group.read_key()?).context("open db")?;
+            for assignment in set {
+                let Some((name, value)) = assignment.split_once('=') else {
+                    bail!("Invalid setting {:?}, expected NAME=VALUE", assignment);
+                };
+                db.with_write_tx(|tx| queries::setting::set(tx, name, value))
+                    .with_context(|| format!("set {:?}", assignment))?;
+            }
+            for (name, description) in queries::setting::SETTINGS {
+                let value = db.with_read_tx(|tx| queries::setting::get(tx, name))?;
+                println!("{} = {}\t# {}", name, value, description);
+            }
+        }
+        Commands::Versions { command } => run_version_command(command)?,
+        Commands::Stats {
+            database_path,
+            key_group,
+        } => {
+            let mut db = DatabaseOps::open(&database_path, key_group.read_key()?).context("open db")?;
+            let stats = db.with_read_tx(queries::block::stats)?;
+            // Sharing, with other files, snapshots or versions, is the only reason for referenced bytes to exceed
+            // stored bytes.
+            let ratio = if stats.stored_bytes == 0 {
+                1.0
+            } else {
+                stats.referenced_bytes as f64 / stats.stored_bytes as f64
+            };
+            println!("Blocks:           {}", stats.blocks);
+            println!("Snapshot blocks:  {}", stats.snapshot_blocks);
+            println!("Version blocks:   {}", stats.version_blocks);
+            println!("Stored blocks:    {}", stats.stored_blocks);
+            println!("Referenced bytes: {}", stats.referenced_bytes);
+            println!("Stored bytes:     {}", stats.stored_bytes);
+            println!("Dedup ratio:      {:.2}", ratio);
+        }
     };
 
     Ok(())
 }
+
+fn run_snapshot_command(command: SnapshotCommands) -> anyhow::Result<()> {
+    match command {
+        SnapshotCommands::Create {
+            database_path,
+            key_group,
+            name,
+        } => {
+            let mut db = DatabaseOps::open(&database_path, key_group.read_key()?).context("open db")?;
+            db.with_write_tx(|tx| queries::snapshot::create(tx, &name, TimeSpec::now()))
+                .with_context(|| format!("create snapshot {:?}", name))?;
+            println!("Created snapshot {}", name);
+        }
+        SnapshotCommands::List {
+            database_path,
+            key_group,
+        } => {
+            let mut db = DatabaseOps::open(&database_path, key_group.read_key()?).context("open db")?;
+            for snapshot in db.with_read_tx(queries::snapshot::list)? {
+                println!("{}\t{}", snapshot.created, snapshot.name);
+            }
+        }
+        SnapshotCommands::Delete {
+            database_path,
+            key_group,
+            name,
+        } => {
+            let mut db = DatabaseOps::open(&database_path, key_group.read_key()?).context("open db")?;
+            db.with_write_tx(|tx| queries::snapshot::remove(tx, &name))
+                .with_context(|| format!("delete snapshot {:?}", name))?;
+            println!("Deleted snapshot {}", name);
+        }
+        SnapshotCommands::Diff {
+            database_path,
+            key_group,
+            from,
+            to,
+        } => {
+            let mut db = DatabaseOps::open(&database_path, key_group.read_key()?).context("open db")?;
+            let changes = db
+                .with_read_tx(|tx| {
+                    let from = queries::snapshot::lookup(tx, &from)?.id;
+                    let to = match &to {
+                        Some(name) => Some(queries::snapshot::lookup(tx, name)?.id),
+                        None => None,
+                    };
+                    queries::snapshot::diff(tx, Some(from), to)
+                })
+                .context("diff snapshots")?;
+            for (change, path) in changes {
+                let sign = match change {
+                    queries::snapshot::Change::Added => '+',
+                    queries::snapshot::Change::Removed => '-',
+                    queries::snapshot::Change::Modified => 'M',
+                };
+                println!("{} {}", sign, Path::new(OsStr::from_bytes(&path)).display());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_version_command(command: VersionCommands) -> anyhow::Result<()> {
+    match command {
+        VersionCommands::List {
+            database_path,
+            key_group,
+            path,
+        } => {
+            let mut db = DatabaseOps::open(&database_path, key_group.read_key()?).context("open db")?;
+            let versions = db
+                .with_read_tx(|tx| queries::version::list(tx, &path))
+                .with_context(|| format!("list versions of {}", path.display()))?;
+            for version in versions {
+                println!(
+                    "{}\t{}\t{} bytes, modified {}{}",
+                    version.id,
+                    version.created,
+                    version.size,
+                    version.mtime,
+                    if version.deleted { " (deleted file)" } else { "" }
+                );
+            }
+        }
+        VersionCommands::Restore {
+            database_path,
+            key_group,
+            path,
+            id,
+        } => {
+            let mut db = DatabaseOps::open(&database_path, key_group.read_key()?).context("open db")?;
+            db.with_write_tx(|tx| queries::version::restore(tx, &path, id, TimeSpec::now()))
+                .with_context(|| format!("restore version {} of {}", id, path.display()))?;
+            println!("Restored version {} of {}", id, path.display());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS symlink (
+    ino INTEGER PRIMARY KEY REFERENCES inode(ino) ON UPDATE CASCADE ON DELETE CASCADE, -- if inode is deleted, delete link target
+    target BLOB NOT NULL
+);
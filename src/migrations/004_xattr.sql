@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS xattr (
+    ino INTEGER NOT NULL REFERENCES inode(ino) ON UPDATE CASCADE ON DELETE CASCADE, -- if inode is deleted, delete its attributes
+    name BLOB NOT NULL,
+    value BLOB NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS xattr_ino_name_idx ON xattr (ino, name);
@@ -0,0 +1,2 @@
+-- Used to find the parent of a directory when walking up the tree.
+CREATE INDEX IF NOT EXISTS dir_entry_ino_idx ON dir_entry (ino);
@@ -0,0 +1,35 @@
+-- Older versions allowed several entries with the same name in a directory. The duplicates are renamed instead of
+-- being dropped so that no file becomes unreachable, the oldest entry keeps the name.
+--
+-- A duplicate is renamed to `<name>.dup-<rowid>`, the name being shortened so that it stays within NAME_MAX (255
+-- bytes). The rowid makes the new names of the duplicates distinct, a counter is appended while the name is already
+-- taken by another entry of the directory: `<name>.dup-<rowid>-<n>`.
+CREATE TEMP TABLE dir_entry_rename AS
+WITH RECURSIVE
+    duplicate (id, parent_ino, name) AS (
+        SELECT rowid, parent_ino, CAST(name AS BLOB) FROM dir_entry
+        WHERE rowid NOT IN (SELECT min(rowid) FROM dir_entry GROUP BY parent_ino, name)
+    ),
+    candidate (id, parent_ino, name, n, new_name) AS (
+        SELECT id, parent_ino, name, 0, CAST(substr(name, 1, 255 - length('.dup-' || id)) || '.dup-' || id AS BLOB)
+        FROM duplicate
+        UNION ALL
+        SELECT
+            c.id, c.parent_ino, c.name, c.n + 1,
+            CAST(
+                substr(c.name, 1, 255 - length('.dup-' || c.id || '-' || (c.n + 1))) || '.dup-' || c.id || '-' || (c.n + 1)
+                AS BLOB
+            )
+        FROM candidate c
+        WHERE EXISTS (SELECT 1 FROM dir_entry e WHERE e.parent_ino = c.parent_ino AND e.name = c.new_name)
+    )
+SELECT id, new_name FROM candidate c
+WHERE NOT EXISTS (SELECT 1 FROM dir_entry e WHERE e.parent_ino = c.parent_ino AND e.name = c.new_name);
+
+UPDATE dir_entry SET name = (SELECT new_name FROM dir_entry_rename WHERE id = dir_entry.rowid)
+WHERE rowid IN (SELECT id FROM dir_entry_rename);
+
+DROP TABLE dir_entry_rename;
+
+DROP INDEX IF EXISTS entry_parent_ino_name_idx;
+CREATE UNIQUE INDEX IF NOT EXISTS dir_entry_parent_ino_name_idx ON dir_entry (parent_ino, name);
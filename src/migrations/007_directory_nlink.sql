@@ -0,0 +1,10 @@
+-- Directories are linked from their parent, from their own '.' entry and from the '..' entry of each
+-- subdirectory. Older versions did not count the subdirectories. Kind 4 is a directory.
+UPDATE inode
+SET nlink = 2 + (
+    SELECT count(*)
+    FROM dir_entry
+    JOIN inode AS child ON child.ino = dir_entry.ino
+    WHERE dir_entry.parent_ino = inode.ino AND child.kind = 4
+)
+WHERE kind = 4;
@@ -0,0 +1,10 @@
+-- The preferred I/O size is the size of a data block and the number of 512 byte blocks is
+-- computed from the compressed data actually stored. Older versions used 512 bytes for both.
+UPDATE inode SET blksize = 131072;
+
+UPDATE inode
+SET blocks = (
+    SELECT (coalesce(sum(length(data)), 0) + 511) / 512
+    FROM block
+    WHERE block.ino = inode.ino
+);
@@ -0,0 +1,35 @@
+-- The data of the blocks moves to its own table so that several blocks can share it, e.g. when a file is copied
+-- with copy_file_range. A block is copied on write while its data is shared, the data is deleted with the last
+-- block referencing it.
+CREATE TABLE IF NOT EXISTS block_data (
+    id INTEGER PRIMARY KEY,
+    data BLOB NOT NULL,
+    compression INTEGER -- 1 & NULL is LZ4, 0 is None, 2 is Zstd
+);
+
+INSERT INTO block_data (id, data, compression) SELECT rowid, data, compression FROM block;
+
+CREATE TABLE block_new (
+    ino INTEGER NOT NULL REFERENCES inode(ino) ON UPDATE CASCADE ON DELETE CASCADE, -- if inode is deleted, delete all data blocks
+    bno INTEGER NOT NULL,
+    data_id INTEGER NOT NULL REFERENCES block_data(id)
+);
+
+INSERT INTO block_new (ino, bno, data_id) SELECT ino, bno, rowid FROM block;
+
+DROP TABLE block;
+ALTER TABLE block_new RENAME TO block;
+
+CREATE INDEX IF NOT EXISTS block_bno_idx ON block (bno);
+CREATE INDEX IF NOT EXISTS block_ino_bno_idx ON block (ino, bno);
+CREATE INDEX IF NOT EXISTS block_data_id_idx ON block (data_id);
+
+CREATE TRIGGER IF NOT EXISTS block_delete_data AFTER DELETE ON block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS block_update_data AFTER UPDATE OF data_id ON block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id);
+END;
@@ -0,0 +1,5 @@
+-- Hash of the uncompressed data of the blocks written with deduplication, blocks with the same content share the
+-- data. Data written without deduplication has no hash.
+ALTER TABLE block_data ADD COLUMN hash BLOB;
+
+CREATE INDEX IF NOT EXISTS block_data_hash_idx ON block_data (hash) WHERE hash IS NOT NULL;
@@ -0,0 +1,95 @@
+-- Read-only copies of the filesystem. The rows of the inodes, entries, symlinks and attributes are copied while the
+-- blocks reference the same data as the files, it is copied on write like any shared data.
+CREATE TABLE IF NOT EXISTS snapshot (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    created_secs INTEGER NOT NULL,
+    created_nanos INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS snapshot_inode (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshot(id) ON DELETE CASCADE,
+    ino INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    blocks INTEGER NOT NULL,
+    atime_secs INTEGER NOT NULL,
+    atime_nanos INTEGER NOT NULL,
+    mtime_secs INTEGER NOT NULL,
+    mtime_nanos INTEGER NOT NULL,
+    ctime_secs INTEGER NOT NULL,
+    ctime_nanos INTEGER NOT NULL,
+    crtime_secs INTEGER NOT NULL,
+    crtime_nanos INTEGER NOT NULL,
+    kind INTEGER NOT NULL,
+    perm INTEGER NOT NULL,
+    nlink INTEGER NOT NULL,
+    uid INTEGER NOT NULL,
+    gid INTEGER NOT NULL,
+    rdev INTEGER NOT NULL,
+    blksize INTEGER NOT NULL,
+    flags INTEGER NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS snapshot_inode_ino_idx ON snapshot_inode (snapshot_id, ino);
+
+CREATE TABLE IF NOT EXISTS snapshot_dir_entry (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshot(id) ON DELETE CASCADE,
+    parent_ino INTEGER NOT NULL,
+    name BLOB NOT NULL,
+    ino INTEGER NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS snapshot_dir_entry_parent_ino_name_idx ON snapshot_dir_entry (snapshot_id, parent_ino, name);
+CREATE INDEX IF NOT EXISTS snapshot_dir_entry_ino_idx ON snapshot_dir_entry (snapshot_id, ino);
+
+CREATE TABLE IF NOT EXISTS snapshot_block (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshot(id) ON DELETE CASCADE,
+    ino INTEGER NOT NULL,
+    bno INTEGER NOT NULL,
+    data_id INTEGER NOT NULL REFERENCES block_data(id)
+);
+
+CREATE INDEX IF NOT EXISTS snapshot_block_ino_bno_idx ON snapshot_block (snapshot_id, ino, bno);
+CREATE INDEX IF NOT EXISTS snapshot_block_data_id_idx ON snapshot_block (data_id);
+
+CREATE TABLE IF NOT EXISTS snapshot_symlink (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshot(id) ON DELETE CASCADE,
+    ino INTEGER NOT NULL,
+    target BLOB NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS snapshot_symlink_ino_idx ON snapshot_symlink (snapshot_id, ino);
+
+CREATE TABLE IF NOT EXISTS snapshot_xattr (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshot(id) ON DELETE CASCADE,
+    ino INTEGER NOT NULL,
+    name BLOB NOT NULL,
+    value BLOB NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS snapshot_xattr_ino_name_idx ON snapshot_xattr (snapshot_id, ino, name);
+
+-- The data of the blocks is now also referenced by the snapshots.
+DROP TRIGGER IF EXISTS block_delete_data;
+DROP TRIGGER IF EXISTS block_update_data;
+
+CREATE TRIGGER IF NOT EXISTS block_delete_data AFTER DELETE ON block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id
+        AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM snapshot_block WHERE data_id = OLD.data_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS block_update_data AFTER UPDATE OF data_id ON block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id
+        AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM snapshot_block WHERE data_id = OLD.data_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS snapshot_block_delete_data AFTER DELETE ON snapshot_block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id
+        AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM snapshot_block WHERE data_id = OLD.data_id);
+END;
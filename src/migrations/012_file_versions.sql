@@ -0,0 +1,65 @@
+-- Settings of the filesystem stored with it, e.g. the retention of the file versions.
+CREATE TABLE IF NOT EXISTS setting (
+    name TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+
+-- Previous contents of the regular files, kept when they are overwritten or truncated. Like snapshots, versions
+-- reference the data of the blocks, which is copied on write.
+CREATE TABLE IF NOT EXISTS file_version (
+    id INTEGER PRIMARY KEY,
+    ino INTEGER NOT NULL REFERENCES inode(ino) ON UPDATE CASCADE ON DELETE CASCADE, -- versions go with the file
+    created_secs INTEGER NOT NULL,
+    created_nanos INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    mtime_secs INTEGER NOT NULL,
+    mtime_nanos INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS file_version_ino_idx ON file_version (ino, id);
+
+CREATE TABLE IF NOT EXISTS file_version_block (
+    version_id INTEGER NOT NULL REFERENCES file_version(id) ON DELETE CASCADE,
+    bno INTEGER NOT NULL,
+    data_id INTEGER NOT NULL REFERENCES block_data(id)
+);
+
+CREATE INDEX IF NOT EXISTS file_version_block_bno_idx ON file_version_block (version_id, bno);
+CREATE INDEX IF NOT EXISTS file_version_block_data_id_idx ON file_version_block (data_id);
+
+-- The data of the blocks is now also referenced by the versions.
+DROP TRIGGER IF EXISTS block_delete_data;
+DROP TRIGGER IF EXISTS block_update_data;
+DROP TRIGGER IF EXISTS snapshot_block_delete_data;
+
+CREATE TRIGGER IF NOT EXISTS block_delete_data AFTER DELETE ON block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id
+        AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM snapshot_block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM file_version_block WHERE data_id = OLD.data_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS block_update_data AFTER UPDATE OF data_id ON block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id
+        AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM snapshot_block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM file_version_block WHERE data_id = OLD.data_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS snapshot_block_delete_data AFTER DELETE ON snapshot_block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id
+        AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM snapshot_block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM file_version_block WHERE data_id = OLD.data_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS file_version_block_delete_data AFTER DELETE ON file_version_block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id
+        AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM snapshot_block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM file_version_block WHERE data_id = OLD.data_id);
+END;
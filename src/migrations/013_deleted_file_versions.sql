@@ -0,0 +1,54 @@
+-- The versions of a file outlive it. When its last link is removed, its versions are listed under the path of that
+-- link and the inode can be deleted without them. The mode and owner are kept to create the file again when one of
+-- them is restored.
+--
+-- The blocks of the versions are moved aside while the versions are copied: dropping the versions would delete them
+-- and their data through the cascade.
+CREATE TABLE file_version_block_old AS SELECT version_id, bno, data_id FROM file_version_block;
+DROP TABLE file_version_block;
+
+CREATE TABLE file_version_new (
+    id INTEGER PRIMARY KEY,
+    ino INTEGER REFERENCES inode(ino) ON UPDATE CASCADE ON DELETE SET NULL, -- NULL once the file is deleted
+    path BLOB, -- path of the last link of the file once it is removed
+    created_secs INTEGER NOT NULL,
+    created_nanos INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    mtime_secs INTEGER NOT NULL,
+    mtime_nanos INTEGER NOT NULL,
+    perm INTEGER NOT NULL,
+    uid INTEGER NOT NULL,
+    gid INTEGER NOT NULL
+);
+
+INSERT INTO file_version_new (
+    id, ino, created_secs, created_nanos, size, mtime_secs, mtime_nanos, perm, uid, gid
+)
+SELECT v.id, v.ino, v.created_secs, v.created_nanos, v.size, v.mtime_secs, v.mtime_nanos, i.perm, i.uid, i.gid
+FROM file_version v JOIN inode i ON i.ino = v.ino;
+
+DROP TABLE file_version;
+
+CREATE TABLE file_version_block (
+    version_id INTEGER NOT NULL REFERENCES file_version(id) ON DELETE CASCADE,
+    bno INTEGER NOT NULL,
+    data_id INTEGER NOT NULL REFERENCES block_data(id)
+);
+
+ALTER TABLE file_version_new RENAME TO file_version;
+
+INSERT INTO file_version_block (version_id, bno, data_id) SELECT version_id, bno, data_id FROM file_version_block_old;
+DROP TABLE file_version_block_old;
+
+CREATE INDEX IF NOT EXISTS file_version_ino_idx ON file_version (ino, id);
+CREATE INDEX IF NOT EXISTS file_version_path_idx ON file_version (path, id) WHERE path IS NOT NULL;
+CREATE INDEX IF NOT EXISTS file_version_block_bno_idx ON file_version_block (version_id, bno);
+CREATE INDEX IF NOT EXISTS file_version_block_data_id_idx ON file_version_block (data_id);
+
+CREATE TRIGGER IF NOT EXISTS file_version_block_delete_data AFTER DELETE ON file_version_block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id
+        AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM snapshot_block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM file_version_block WHERE data_id = OLD.data_id);
+END;
@@ -0,0 +1,143 @@
+-- The rows of the snapshots are shared between consecutive snapshots instead of being copied for each of them. A row
+-- is part of the snapshots from `first_snapshot` to `last_snapshot`, both ends being existing snapshots. Creating a
+-- snapshot extends the rows of the previous snapshot that did not change and only adds the rows that did.
+CREATE TABLE snapshot_inode_new (
+    first_snapshot INTEGER NOT NULL,
+    last_snapshot INTEGER NOT NULL,
+    ino INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    blocks INTEGER NOT NULL,
+    atime_secs INTEGER NOT NULL,
+    atime_nanos INTEGER NOT NULL,
+    mtime_secs INTEGER NOT NULL,
+    mtime_nanos INTEGER NOT NULL,
+    ctime_secs INTEGER NOT NULL,
+    ctime_nanos INTEGER NOT NULL,
+    crtime_secs INTEGER NOT NULL,
+    crtime_nanos INTEGER NOT NULL,
+    kind INTEGER NOT NULL,
+    perm INTEGER NOT NULL,
+    nlink INTEGER NOT NULL,
+    uid INTEGER NOT NULL,
+    gid INTEGER NOT NULL,
+    rdev INTEGER NOT NULL,
+    blksize INTEGER NOT NULL,
+    flags INTEGER NOT NULL
+);
+
+INSERT INTO snapshot_inode_new
+SELECT
+    snapshot_id, snapshot_id, ino, size, blocks, atime_secs, atime_nanos, mtime_secs, mtime_nanos, ctime_secs,
+    ctime_nanos, crtime_secs, crtime_nanos, kind, perm, nlink, uid, gid, rdev, blksize, flags
+FROM snapshot_inode ORDER BY rowid;
+
+DROP TABLE snapshot_inode;
+ALTER TABLE snapshot_inode_new RENAME TO snapshot_inode;
+
+CREATE INDEX IF NOT EXISTS snapshot_inode_ino_idx ON snapshot_inode (ino, first_snapshot);
+CREATE INDEX IF NOT EXISTS snapshot_inode_last_idx ON snapshot_inode (last_snapshot);
+
+CREATE TABLE snapshot_dir_entry_new (
+    first_snapshot INTEGER NOT NULL,
+    last_snapshot INTEGER NOT NULL,
+    parent_ino INTEGER NOT NULL,
+    name BLOB NOT NULL,
+    ino INTEGER NOT NULL
+);
+
+INSERT INTO snapshot_dir_entry_new
+SELECT snapshot_id, snapshot_id, parent_ino, name, ino FROM snapshot_dir_entry ORDER BY rowid;
+
+DROP TABLE snapshot_dir_entry;
+ALTER TABLE snapshot_dir_entry_new RENAME TO snapshot_dir_entry;
+
+CREATE INDEX IF NOT EXISTS snapshot_dir_entry_parent_ino_name_idx
+    ON snapshot_dir_entry (parent_ino, name, first_snapshot);
+CREATE INDEX IF NOT EXISTS snapshot_dir_entry_ino_idx ON snapshot_dir_entry (ino, first_snapshot);
+CREATE INDEX IF NOT EXISTS snapshot_dir_entry_last_idx ON snapshot_dir_entry (last_snapshot);
+
+-- The triggers deleting the data of the blocks reference the table, they are created again once it is replaced.
+DROP TRIGGER IF EXISTS block_delete_data;
+DROP TRIGGER IF EXISTS block_update_data;
+DROP TRIGGER IF EXISTS snapshot_block_delete_data;
+DROP TRIGGER IF EXISTS file_version_block_delete_data;
+
+CREATE TABLE snapshot_block_new (
+    first_snapshot INTEGER NOT NULL,
+    last_snapshot INTEGER NOT NULL,
+    ino INTEGER NOT NULL,
+    bno INTEGER NOT NULL,
+    data_id INTEGER NOT NULL REFERENCES block_data(id)
+);
+
+INSERT INTO snapshot_block_new SELECT snapshot_id, snapshot_id, ino, bno, data_id FROM snapshot_block ORDER BY rowid;
+
+DROP TABLE snapshot_block;
+ALTER TABLE snapshot_block_new RENAME TO snapshot_block;
+
+CREATE INDEX IF NOT EXISTS snapshot_block_ino_bno_idx ON snapshot_block (ino, bno, first_snapshot);
+CREATE INDEX IF NOT EXISTS snapshot_block_data_id_idx ON snapshot_block (data_id);
+CREATE INDEX IF NOT EXISTS snapshot_block_last_idx ON snapshot_block (last_snapshot);
+
+CREATE TABLE snapshot_symlink_new (
+    first_snapshot INTEGER NOT NULL,
+    last_snapshot INTEGER NOT NULL,
+    ino INTEGER NOT NULL,
+    target BLOB NOT NULL
+);
+
+INSERT INTO snapshot_symlink_new SELECT snapshot_id, snapshot_id, ino, target FROM snapshot_symlink ORDER BY rowid;
+
+DROP TABLE snapshot_symlink;
+ALTER TABLE snapshot_symlink_new RENAME TO snapshot_symlink;
+
+CREATE INDEX IF NOT EXISTS snapshot_symlink_ino_idx ON snapshot_symlink (ino, first_snapshot);
+CREATE INDEX IF NOT EXISTS snapshot_symlink_last_idx ON snapshot_symlink (last_snapshot);
+
+CREATE TABLE snapshot_xattr_new (
+    first_snapshot INTEGER NOT NULL,
+    last_snapshot INTEGER NOT NULL,
+    ino INTEGER NOT NULL,
+    name BLOB NOT NULL,
+    value BLOB NOT NULL
+);
+
+INSERT INTO snapshot_xattr_new SELECT snapshot_id, snapshot_id, ino, name, value FROM snapshot_xattr ORDER BY rowid;
+
+DROP TABLE snapshot_xattr;
+ALTER TABLE snapshot_xattr_new RENAME TO snapshot_xattr;
+
+CREATE INDEX IF NOT EXISTS snapshot_xattr_ino_name_idx ON snapshot_xattr (ino, name, first_snapshot);
+CREATE INDEX IF NOT EXISTS snapshot_xattr_last_idx ON snapshot_xattr (last_snapshot);
+
+CREATE TRIGGER IF NOT EXISTS block_delete_data AFTER DELETE ON block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id
+        AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM snapshot_block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM file_version_block WHERE data_id = OLD.data_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS block_update_data AFTER UPDATE OF data_id ON block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id
+        AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM snapshot_block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM file_version_block WHERE data_id = OLD.data_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS snapshot_block_delete_data AFTER DELETE ON snapshot_block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id
+        AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM snapshot_block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM file_version_block WHERE data_id = OLD.data_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS file_version_block_delete_data AFTER DELETE ON file_version_block
+BEGIN
+    DELETE FROM block_data WHERE id = OLD.data_id
+        AND NOT EXISTS (SELECT 1 FROM block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM snapshot_block WHERE data_id = OLD.data_id)
+        AND NOT EXISTS (SELECT 1 FROM file_version_block WHERE data_id = OLD.data_id);
+END;
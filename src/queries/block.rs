@@ -2,11 +2,15 @@ use std::cmp;
 
 use crate::errors::Result;
 use rusqlite::params;
+use sha2::{Digest, Sha256};
 
 pub const BLOCK_SIZE: u64 = 128 * 1024;
 
 pub fn get_block(tx: &mut rusqlite::Transaction, ino: u64, bno: u64) -> Result<Block> {
-    let mut stmt = tx.prepare_cached("SELECT bno, data, compression FROM block WHERE ino = ? AND bno = ?")?;
+    let mut stmt = tx.prepare_cached(
+        "SELECT b.bno, d.data, d.compression FROM block b JOIN block_data d ON d.id = b.data_id
+        WHERE b.ino = ? AND b.bno = ?",
+    )?;
     let mut rows = stmt.query(params![ino, bno])?;
     match rows.next()? {
         Some(row) => {
@@ -31,8 +35,10 @@ pub fn iter_blocks_from(
     mut iter: impl FnMut(Block) -> Result<bool>,
 ) -> Result<()> {
     let bno = Block::offset_to_bno(offset);
-    let mut stmt =
-        tx.prepare_cached("SELECT bno, data, compression FROM block WHERE ino = ? AND bno >= ? ORDER BY bno")?;
+    let mut stmt = tx.prepare_cached(
+        "SELECT b.bno, d.data, d.compression FROM block b JOIN block_data d ON d.id = b.data_id
+        WHERE b.ino = ? AND b.bno >= ? ORDER BY b.bno",
+    )?;
     let mut rows = stmt.query(params![ino, bno])?;
     while let Some(row) = rows.next()? {
         let data = row.get_ref(1)?.as_blob()?;
@@ -51,35 +57,174 @@ pub fn iter_blocks_from(
     Ok(())
 }
 
-pub fn update(tx: &mut rusqlite::Transaction, block: &Block, compression: Compression) -> Result<()> {
+/// Store the data of a block and return its id, it is referenced by one or more blocks. With deduplication, the data
+/// already stored for the same content is reused.
+fn store_data(tx: &mut rusqlite::Transaction, block: &Block, storage: Storage) -> Result<i64> {
+    let hash = storage.dedup.then(|| Sha256::digest(&block.data).to_vec());
+    if let Some(hash) = &hash {
+        let mut stmt = tx.prepare_cached("SELECT id FROM block_data WHERE hash = ? LIMIT 1")?;
+        let mut rows = stmt.query(params![hash])?;
+        if let Some(row) = rows.next()? {
+            return Ok(row.get(0)?);
+        }
+    }
+
     let mut buf = Vec::new();
-    let cb = CompressedBlock::compress(block, compression, &mut buf);
+    let cb = CompressedBlock::compress(block, storage.compression, &mut buf);
+    let mut stmt = tx.prepare_cached("INSERT INTO block_data (data, compression, hash) VALUES (?, ?, ?)")?;
+    let id = stmt.insert(params![cb.data, cb.compression as u8, hash])?;
+    Ok(id)
+}
 
-    let mut stmt = tx.prepare_cached("UPDATE block SET data = ?, compression = ? WHERE ino = ? AND bno = ?")?;
-    stmt.execute(params![cb.data, cb.compression as u8, block.ino, block.bno])?;
+/// Update the data of a block. If the data is shared with other blocks, snapshots or versions, it is copied on write
+/// and they keep the previous data.
+pub fn update(tx: &mut rusqlite::Transaction, block: &Block, storage: Storage) -> Result<()> {
+    let (data_id, shared): (i64, bool) = tx
+        .prepare_cached(
+            "SELECT data_id,
+                EXISTS (SELECT 1 FROM block o WHERE o.data_id = b.data_id AND o.rowid != b.rowid)
+                OR EXISTS (SELECT 1 FROM snapshot_block s WHERE s.data_id = b.data_id)
+                OR EXISTS (SELECT 1 FROM file_version_block v WHERE v.data_id = b.data_id)
+            FROM block b WHERE b.ino = ? AND b.bno = ?",
+        )?
+        .query_row(params![block.ino, block.bno], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    // With deduplication the block is pointed to the data of its new content, the previous data is deleted by a
+    // trigger if nothing else references it.
+    if shared || storage.dedup {
+        let new_id = store_data(tx, block, storage)?;
+        if new_id != data_id {
+            let mut stmt = tx.prepare_cached("UPDATE block SET data_id = ? WHERE ino = ? AND bno = ?")?;
+            stmt.execute(params![new_id, block.ino, block.bno])?;
+        }
+    } else {
+        let mut buf = Vec::new();
+        let cb = CompressedBlock::compress(block, storage.compression, &mut buf);
+        let mut stmt =
+            tx.prepare_cached("UPDATE block_data SET data = ?, compression = ?, hash = NULL WHERE id = ?")?;
+        stmt.execute(params![cb.data, cb.compression as u8, data_id])?;
+    }
 
     Ok(())
 }
 
-pub fn create(
-    tx: &mut rusqlite::Transaction,
-    ino: u64,
-    offset: u64,
-    data: &[u8],
-    compression: Compression,
-) -> Result<u64> {
+pub fn create(tx: &mut rusqlite::Transaction, ino: u64, offset: u64, data: &[u8], storage: Storage) -> Result<u64> {
     let bno = Block::offset_to_bno(offset);
     let mut block = Block::empty(ino, bno);
-    let written = block.consume(data);
-    let mut buf = Vec::new();
-    let cb = CompressedBlock::compress(&block, compression, &mut buf);
+    let (written, _) = block.write_at(offset, data);
 
-    let mut stmt = tx.prepare_cached("INSERT INTO block (ino, bno, data, compression) VALUES (?, ?, ?, ?)")?;
-    stmt.execute(params![block.ino, block.bno, cb.data, compression as u8])?;
+    let data_id = store_data(tx, &block, storage)?;
+    let mut stmt = tx.prepare_cached("INSERT INTO block (ino, bno, data_id) VALUES (?, ?, ?)")?;
+    stmt.execute(params![block.ino, block.bno, data_id])?;
 
     Ok(written)
 }
 
+/// Make the `count` blocks of `dst_ino` starting at `dst_bno` share the data of the blocks of `src_ino` starting at
+/// `src_bno`, without copying it. Holes in the source range become holes in the destination range.
+pub fn share_blocks(
+    tx: &mut rusqlite::Transaction,
+    src_ino: u64,
+    src_bno: u64,
+    dst_ino: u64,
+    dst_bno: u64,
+    count: u64,
+) -> Result<()> {
+    remove_block_range(tx, dst_ino, dst_bno, dst_bno + count)?;
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO block (ino, bno, data_id)
+        SELECT ?, bno - ? + ?, data_id FROM block WHERE ino = ? AND bno >= ? AND bno < ?",
+    )?;
+    stmt.execute(params![dst_ino, src_bno, dst_bno, src_ino, src_bno, src_bno + count])?;
+    Ok(())
+}
+
+/// Number of bytes stored in the database for the blocks of the inode, after compression.
+pub fn stored_bytes(tx: &mut rusqlite::Transaction, ino: u64) -> Result<u64> {
+    let mut stmt = tx.prepare_cached(
+        "SELECT coalesce(sum(length(d.data)), 0) FROM block b JOIN block_data d ON d.id = b.data_id WHERE b.ino = ?",
+    )?;
+    let bytes = stmt.query_row(params![ino], |row| row.get(0))?;
+    Ok(bytes)
+}
+
+/// Space used by the blocks of all the inodes.
+#[derive(Debug, PartialEq)]
+pub struct BlockStats {
+    /// Number of blocks in the files.
+    pub blocks: u64,
+    /// Number of blocks kept by the snapshots.
+    pub snapshot_blocks: u64,
+    /// Number of blocks kept by the previous versions of the files.
+    pub version_blocks: u64,
+    /// Number of distinct data stored for the blocks.
+    pub stored_blocks: u64,
+    /// Bytes used by the blocks of the files, snapshots and versions if none shared its data, after compression.
+    pub referenced_bytes: u64,
+    /// Bytes actually stored, after compression.
+    pub stored_bytes: u64,
+}
+
+pub fn stats(tx: &mut rusqlite::Transaction) -> Result<BlockStats> {
+    let mut counts = [0; 3];
+    let mut referenced_bytes = 0;
+    let references = [
+        "block b",
+        // A block of the snapshots is referenced by each of the snapshots it is part of.
+        "snapshot_block b JOIN snapshot s ON s.id BETWEEN b.first_snapshot AND b.last_snapshot",
+        "file_version_block b",
+    ];
+    for (blocks, count) in references.iter().zip(&mut counts) {
+        let mut stmt = tx.prepare_cached(&format!(
+            "SELECT count(*), coalesce(sum(length(d.data)), 0) FROM {blocks} JOIN block_data d ON d.id = b.data_id"
+        ))?;
+        let (n, bytes): (u64, u64) = stmt.query_row(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        *count = n;
+        referenced_bytes += bytes;
+    }
+    let mut stmt = tx.prepare_cached("SELECT count(*), coalesce(sum(length(data)), 0) FROM block_data")?;
+    let (stored_blocks, stored_bytes) = stmt.query_row(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let [blocks, snapshot_blocks, version_blocks] = counts;
+    Ok(BlockStats {
+        blocks,
+        snapshot_blocks,
+        version_blocks,
+        stored_blocks,
+        referenced_bytes,
+        stored_bytes,
+    })
+}
+
+/// Find the first allocated block numbered `bno` or higher.
+pub fn next_data(tx: &mut rusqlite::Transaction, ino: u64, bno: u64) -> Result<Option<u64>> {
+    let mut stmt = tx.prepare_cached("SELECT min(bno) FROM block WHERE ino = ? AND bno >= ?")?;
+    let next = stmt.query_row(params![ino, bno], |row| row.get(0))?;
+    Ok(next)
+}
+
+/// Find the first missing block numbered `bno` or higher.
+pub fn next_hole(tx: &mut rusqlite::Transaction, ino: u64, bno: u64) -> Result<u64> {
+    let mut stmt = tx.prepare_cached("SELECT 1 FROM block WHERE ino = ? AND bno = ?")?;
+    if !stmt.exists(params![ino, bno])? {
+        return Ok(bno);
+    }
+    let mut stmt = tx.prepare_cached(
+        "SELECT b.bno + 1 FROM block b
+        WHERE b.ino = ? AND b.bno >= ?
+        AND NOT EXISTS (SELECT 1 FROM block n WHERE n.ino = b.ino AND n.bno = b.bno + 1)
+        ORDER BY b.bno LIMIT 1",
+    )?;
+    let hole = stmt.query_row(params![ino, bno], |row| row.get(0))?;
+    Ok(hole)
+}
+
+/// Remove the blocks numbered from `start_bno` up to, but not including, `end_bno`.
+pub fn remove_block_range(tx: &mut rusqlite::Transaction, ino: u64, start_bno: u64, end_bno: u64) -> Result<()> {
+    let mut stmt = tx.prepare_cached("DELETE FROM block WHERE ino = ? AND bno >= ? AND bno < ?")?;
+    stmt.execute(params![ino, start_bno, end_bno])?;
+    Ok(())
+}
+
 pub fn remove_blocks_from(tx: &mut rusqlite::Transaction, ino: u64, bno: u64) -> Result<()> {
     let mut stmt = tx.prepare_cached("DELETE FROM block WHERE ino = ? AND bno >= ?")?;
     stmt.execute(params![ino, bno])?;
@@ -108,6 +253,23 @@ impl TryFrom<Option<u8>> for Compression {
     }
 }
 
+/// How the data of the blocks is stored.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Storage {
+    pub compression: Compression,
+    /// Blocks with the same content share the same data.
+    pub dedup: bool,
+}
+
+impl From<Compression> for Storage {
+    fn from(compression: Compression) -> Self {
+        Storage {
+            compression,
+            dedup: false,
+        }
+    }
+}
+
 pub struct CompressedBlock<'d> {
     // Inode number.
     pub ino: u64,
@@ -226,19 +388,42 @@ impl Block {
 
     pub fn write_at(&mut self, inode_offset: u64, data: &[u8]) -> (u64, i64) {
         let start_len = self.data.len();
-        let rel_offset = inode_offset - self.start_offset();
-        self.data.resize(rel_offset as usize, 0);
-        let written = self.consume(data);
+        let rel_offset = (inode_offset - self.start_offset()) as usize;
+        let written = if rel_offset >= start_len {
+            // Writing past the end of the data, the gap is filled with zeros.
+            self.data.resize(rel_offset, 0);
+            self.consume(data)
+        } else {
+            // Overwrite the existing data in place and append what is left.
+            let overlap = cmp::min(data.len(), start_len - rel_offset);
+            self.data[rel_offset..rel_offset + overlap].copy_from_slice(&data[..overlap]);
+            overlap as u64 + self.consume(&data[overlap..])
+        };
         let diff = self.data.len() as i64 - start_len as i64;
         (written, diff)
     }
 
-    pub fn copy_into(&self, dest: &mut Vec<u8>, offset: u64) -> usize {
-        let rel_offset = offset.saturating_sub(self.start_offset()) as usize;
-        let remaining = dest.capacity() - dest.len();
-        let max_write = cmp::min(remaining, self.data.len() - rel_offset);
-        dest.extend_from_slice(&self.data[rel_offset..][..max_write]);
-        max_write
+    /// Copy the data of the block overlapping the range `offset..offset + dest.len()` of the inode into `dest`.
+    /// The parts of `dest` not covered by the block are left untouched. Returns the number of bytes copied.
+    pub fn copy_into(&self, dest: &mut [u8], offset: u64) -> usize {
+        let start = cmp::max(offset, self.start_offset());
+        let end = cmp::min(offset + dest.len() as u64, self.start_offset() + self.data.len() as u64);
+        if start >= end {
+            return 0;
+        }
+        let src = &self.data[(start - self.start_offset()) as usize..(end - self.start_offset()) as usize];
+        dest[(start - offset) as usize..(end - offset) as usize].copy_from_slice(src);
+        src.len()
+    }
+
+    /// Zero the data of the block overlapping the range `start..end` of the inode.
+    pub fn zero_range(&mut self, start: u64, end: u64) {
+        let len = self.data.len() as u64;
+        let rel_start = cmp::min(start.saturating_sub(self.start_offset()), len) as usize;
+        let rel_end = cmp::min(end.saturating_sub(self.start_offset()), len) as usize;
+        if rel_start < rel_end {
+            self.data[rel_start..rel_end].fill(0);
+        }
     }
 
     pub fn truncate(&mut self, inode_offset: u64) {
@@ -297,6 +482,18 @@ mod tests {
         let mut b = Block::empty(0, 1);
         assert_eq!(b.write_at(BLOCK_SIZE + 5, &[1; 5]), (5, 10));
         assert_eq!(b.data, vec![0, 0, 0, 0, 0, 1, 1, 1, 1, 1]);
+
+        // Overwriting the middle keeps the tail.
+        assert_eq!(b.write_at(BLOCK_SIZE + 3, &[2; 3]), (3, 0));
+        assert_eq!(b.data, vec![0, 0, 0, 2, 2, 2, 1, 1, 1, 1]);
+
+        // Overwriting the end extends the data.
+        assert_eq!(b.write_at(BLOCK_SIZE + 8, &[3; 4]), (4, 2));
+        assert_eq!(b.data, vec![0, 0, 0, 2, 2, 2, 1, 1, 3, 3, 3, 3]);
+
+        // Writes stop at the end of the block.
+        let mut b = Block::empty(0, 0);
+        assert_eq!(b.write_at(BLOCK_SIZE - 2, &[1; 5]), (2, BLOCK_SIZE as i64));
     }
 
     #[test]
@@ -304,15 +501,34 @@ mod tests {
         let mut b = Block::empty(0, 0);
         b.data = (1u8..=10).collect();
 
-        let mut buf = Vec::with_capacity(5);
+        let mut buf = vec![0; 5];
         assert_eq!(b.copy_into(&mut buf, 0), 5);
 
-        let mut buf = Vec::with_capacity(15);
+        let mut buf = vec![0; 15];
         assert_eq!(b.copy_into(&mut buf, 0), 10);
 
-        let mut buf = Vec::with_capacity(5);
+        let mut buf = vec![0; 5];
         assert_eq!(b.copy_into(&mut buf, 5), 5);
         assert_eq!(buf, &[6, 7, 8, 9, 10]);
+
+        // Block 1 starts in the middle of the buffer.
+        let mut b = Block::empty(0, 1);
+        b.data = vec![7; 10];
+        let mut buf = vec![0; 8];
+        assert_eq!(b.copy_into(&mut buf, BLOCK_SIZE - 4), 4);
+        assert_eq!(buf, &[0, 0, 0, 0, 7, 7, 7, 7]);
+    }
+
+    #[test]
+    fn test_block_zero_range() {
+        let mut b = Block::empty(0, 1);
+        b.data = vec![1; 10];
+        b.zero_range(BLOCK_SIZE + 2, BLOCK_SIZE + 4);
+        assert_eq!(b.data, vec![1, 1, 0, 0, 1, 1, 1, 1, 1, 1]);
+        b.zero_range(0, BLOCK_SIZE + 1);
+        assert_eq!(b.data[0], 0);
+        b.zero_range(BLOCK_SIZE + 8, 10 * BLOCK_SIZE);
+        assert_eq!(b.data, vec![0, 1, 0, 0, 1, 1, 1, 1, 0, 0]);
     }
 
     #[test]
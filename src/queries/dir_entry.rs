@@ -1,4 +1,8 @@
-use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+use std::{
+    ffi::{OsStr, OsString},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Component, Path, PathBuf},
+};
 
 use crate::{
     errors::{Error, Result},
@@ -6,13 +10,37 @@ use crate::{
 };
 use rusqlite::params;
 
+/// Longest name allowed in a directory, in bytes.
+pub const NAME_MAX: usize = 255;
+
+fn check_name(name: &OsStr) -> Result<()> {
+    if name.len() > NAME_MAX {
+        return Err(Error::NameTooLong);
+    }
+    Ok(())
+}
+
 pub fn lookup(tx: &mut rusqlite::Transaction, parent_ino: u64, name: &OsStr) -> Result<u64> {
     let mut stmt = tx.prepare_cached("SELECT ino FROM dir_entry WHERE parent_ino = ? AND name = ?")?;
     let ino = stmt.query_row(params![parent_ino, name.as_encoded_bytes()], |row| row.get(0))?;
     Ok(ino)
 }
 
+/// Find the inode of a path relative to the root directory. Symbolic links are not followed.
+pub fn lookup_path(tx: &mut rusqlite::Transaction, path: &Path) -> Result<u64> {
+    let mut ino = 1;
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(name) => ino = lookup(tx, ino, name)?,
+            Component::ParentDir | Component::Prefix(_) => return Err(Error::InvalidArgument),
+        }
+    }
+    Ok(ino)
+}
+
 pub fn create(tx: &mut rusqlite::Transaction, parent_ino: u64, name: &OsStr, ino: u64) -> Result<()> {
+    check_name(name)?;
     let mut stmt = tx.prepare_cached(include_str!("sql/create_dir_entry.sql"))?;
     stmt.insert(params![parent_ino, name.as_encoded_bytes(), ino])?;
     Ok(())
@@ -34,12 +62,43 @@ pub fn rename(
     new_parent: u64,
     new_name: &OsStr,
 ) -> Result<()> {
+    check_name(new_name)?;
     let mut stmt =
         tx.prepare_cached("UPDATE dir_entry SET parent_ino = ?, name = ? WHERE parent_ino = ? AND name = ?")?;
     stmt.execute(params![new_parent, new_name.as_bytes(), parent, name.as_bytes()])?;
     Ok(())
 }
 
+/// Point an existing entry to another inode.
+pub fn set_ino(tx: &mut rusqlite::Transaction, parent_ino: u64, name: &OsStr, ino: u64) -> Result<()> {
+    let mut stmt = tx.prepare_cached("UPDATE dir_entry SET ino = ? WHERE parent_ino = ? AND name = ?")?;
+    let affected = stmt.execute(params![ino, parent_ino, name.as_bytes()])?;
+    if affected == 0 {
+        return Err(Error::NotFound);
+    }
+    Ok(())
+}
+
+/// Parent of a directory. Directories have a single entry, the root directory has none.
+pub fn parent_of(tx: &mut rusqlite::Transaction, ino: u64) -> Result<u64> {
+    let mut stmt = tx.prepare_cached("SELECT parent_ino FROM dir_entry WHERE ino = ? LIMIT 1")?;
+    let parent = stmt.query_row(params![ino], |row| row.get(0))?;
+    Ok(parent)
+}
+
+/// Path of a directory from the root directory, the reverse of `lookup_path`.
+pub fn dir_path(tx: &mut rusqlite::Transaction, ino: u64) -> Result<PathBuf> {
+    let mut names = Vec::new();
+    let mut current = ino;
+    while current != 1 {
+        let mut stmt = tx.prepare_cached("SELECT parent_ino, name FROM dir_entry WHERE ino = ? LIMIT 1")?;
+        let (parent, name): (u64, Vec<u8>) = stmt.query_row(params![current], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        names.push(OsString::from_vec(name));
+        current = parent;
+    }
+    Ok(Path::new("/").join(names.iter().rev().collect::<PathBuf>()))
+}
+
 pub fn is_dir_empty(tx: &mut rusqlite::Transaction, ino: u64) -> Result<bool> {
     let mut stmt = tx.prepare_cached("SELECT NOT EXISTS(SELECT 1 FROM dir_entry WHERE parent_ino = ?)")?;
     let empty = stmt.query_row(params![ino], |row| row.get(0))?;
@@ -75,3 +134,14 @@ pub struct ListDirEntry<'n> {
     pub name: &'n OsStr,
     pub kind: fuser::FileType,
 }
+
+impl<'n> ListDirEntry<'n> {
+    pub fn new(offset: i64, ino: u64, name: &'n OsStr, kind: fuser::FileType) -> Self {
+        ListDirEntry {
+            offset,
+            ino,
+            name,
+            kind,
+        }
+    }
+}
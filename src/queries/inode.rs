@@ -1,10 +1,14 @@
 use crate::{
     errors::{Error, Result},
+    queries,
     time::TimeSpec,
     types::FileType,
 };
 use rusqlite::params;
 
+/// Unit of `st_blocks`, independent of the block size of the filesystem.
+pub const POSIX_BLOCK_SIZE: u64 = 512;
+
 pub fn lookup(tx: &mut rusqlite::Transaction, ino: u64) -> Result<fuser::FileAttr> {
     let mut stmt = tx.prepare_cached(include_str!("sql/lookup_inode.sql"))?;
     let attr = stmt.query_row(params![ino], |row| {
@@ -76,6 +80,35 @@ pub fn set_attr(
     }
 }
 
+/// Set one of the timestamps of the inode, `name` is one of `atime`, `mtime`, `ctime` or `crtime`.
+pub fn set_time(tx: &mut rusqlite::Transaction, ino: u64, name: &str, time: TimeSpec) -> Result<()> {
+    let mut stmt = tx.prepare_cached(&format!(
+        "UPDATE inode SET `{name}_secs` = ?, `{name}_nanos` = ? WHERE ino = ?"
+    ))?;
+    let affected = stmt.execute(params![time.secs, time.nanos, ino])?;
+    match affected {
+        0 => Err(Error::NotFound),
+        _ => Ok(()),
+    }
+}
+
+/// Add `delta` to the link count of the inode.
+pub fn add_nlink(tx: &mut rusqlite::Transaction, ino: u64, delta: i32) -> Result<()> {
+    let mut stmt = tx.prepare_cached("UPDATE inode SET nlink = nlink + ? WHERE ino = ?")?;
+    let affected = stmt.execute(params![delta, ino])?;
+    match affected {
+        0 => Err(Error::NotFound),
+        _ => Ok(()),
+    }
+}
+
+/// Recompute the number of 512 byte blocks used by the inode from the compressed size of its data blocks.
+pub fn update_blocks(tx: &mut rusqlite::Transaction, ino: u64) -> Result<u64> {
+    let blocks = queries::block::stored_bytes(tx, ino)?.div_ceil(POSIX_BLOCK_SIZE);
+    set_attr(tx, ino, "blocks", blocks)?;
+    Ok(blocks)
+}
+
 pub fn remove(tx: &mut rusqlite::Transaction, ino: u64) -> Result<()> {
     let mut stmt = tx.prepare_cached("DELETE FROM inode WHERE ino = ?")?;
     let affected = stmt.execute(params![ino])?;
@@ -85,6 +118,20 @@ pub fn remove(tx: &mut rusqlite::Transaction, ino: u64) -> Result<()> {
     }
 }
 
+/// Remove the inodes without links, left behind when the filesystem was not unmounted cleanly while unlinked files
+/// were still open.
+pub fn remove_orphans(tx: &mut rusqlite::Transaction) -> Result<usize> {
+    let mut stmt = tx.prepare_cached("DELETE FROM inode WHERE nlink = 0")?;
+    let removed = stmt.execute(params![])?;
+    Ok(removed)
+}
+
+pub fn count(tx: &mut rusqlite::Transaction) -> Result<u64> {
+    let mut stmt = tx.prepare_cached("SELECT count(*) FROM inode")?;
+    let count = stmt.query_row(params![], |row| row.get(0))?;
+    Ok(count)
+}
+
 #[derive(Default)]
 struct RowCounter {
     c: usize,
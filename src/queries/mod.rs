@@ -1,3 +1,8 @@
 pub mod block;
 pub mod dir_entry;
 pub mod inode;
+pub mod setting;
+pub mod snapshot;
+pub mod symlink;
+pub mod version;
+pub mod xattr;
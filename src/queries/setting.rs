@@ -0,0 +1,42 @@
+use crate::errors::{Error, Result};
+use rusqlite::params;
+
+pub const VERSIONS_KEEP: &str = "versions_keep";
+pub const VERSIONS_MAX_AGE: &str = "versions_max_age";
+
+/// Settings stored in the database with their description. All of them are numbers and default to 0. The versions of
+/// the files are only kept once one of their limits is set.
+pub const SETTINGS: [(&str, &str); 2] = [
+    (
+        VERSIONS_KEEP,
+        "Number of previous versions kept for each file, 0 for no limit",
+    ),
+    (
+        VERSIONS_MAX_AGE,
+        "Age in seconds after which the versions of the files are deleted, 0 for no limit",
+    ),
+];
+
+pub fn get(tx: &mut rusqlite::Transaction, name: &str) -> Result<u64> {
+    let mut stmt = tx.prepare_cached("SELECT value FROM setting WHERE name = ?")?;
+    let mut rows = stmt.query(params![name])?;
+    match rows.next()? {
+        Some(row) => {
+            let value: String = row.get(0)?;
+            value.parse().map_err(|_| Error::InvalidArgument)
+        }
+        None => Ok(0),
+    }
+}
+
+pub fn set(tx: &mut rusqlite::Transaction, name: &str, value: &str) -> Result<()> {
+    if !SETTINGS.iter().any(|(known, _)| *known == name) {
+        return Err(Error::NotFound);
+    }
+    let value: u64 = value.parse().map_err(|_| Error::InvalidArgument)?;
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO setting (name, value) VALUES (?, ?) ON CONFLICT (name) DO UPDATE SET value = excluded.value",
+    )?;
+    stmt.execute(params![name, value.to_string()])?;
+    Ok(())
+}
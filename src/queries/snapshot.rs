@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+
+use crate::{errors::Result, time::TimeSpec};
+use rusqlite::params;
+
+#[derive(Debug, PartialEq)]
+pub struct Snapshot {
+    pub id: i64,
+    pub name: String,
+    pub created: TimeSpec,
+}
+
+/// Columns of the inodes, all of them must match for a snapshot to share the row of the previous one.
+const INODE_COLUMNS: &str = "ino, size, blocks, atime_secs, atime_nanos, mtime_secs, mtime_nanos, ctime_secs, \
+    ctime_nanos, crtime_secs, crtime_nanos, kind, perm, nlink, uid, gid, rdev, blksize, flags";
+
+/// Tables holding the rows of the snapshots, each row is part of the snapshots from `first_snapshot` to
+/// `last_snapshot`.
+const TABLES: [&str; 5] = [
+    "snapshot_inode",
+    "snapshot_dir_entry",
+    "snapshot_block",
+    "snapshot_symlink",
+    "snapshot_xattr",
+];
+
+/// Save the current state of the filesystem as a new snapshot. The rows of the previous snapshot that did not change
+/// are shared with it, the others are copied. Inodes without links are left out, they are only kept until their last
+/// handle is closed.
+pub fn create(tx: &mut rusqlite::Transaction, name: &str, created: TimeSpec) -> Result<Snapshot> {
+    let previous: Option<i64> = tx
+        .prepare_cached("SELECT max(id) FROM snapshot")?
+        .query_row(params![], |row| row.get(0))?;
+    let mut stmt = tx.prepare_cached("INSERT INTO snapshot (name, created_secs, created_nanos) VALUES (?, ?, ?)")?;
+    let id = stmt.insert(params![name, created.secs, created.nanos])?;
+
+    let shared = [
+        format!(
+            "UPDATE snapshot_inode SET last_snapshot = ?1 WHERE last_snapshot = ?2
+            AND ({INODE_COLUMNS}) IN (SELECT {INODE_COLUMNS} FROM inode WHERE nlink > 0)"
+        ),
+        "UPDATE snapshot_dir_entry SET last_snapshot = ?1 WHERE last_snapshot = ?2
+        AND (parent_ino, name, ino) IN (SELECT parent_ino, name, ino FROM dir_entry)"
+            .to_owned(),
+        "UPDATE snapshot_block SET last_snapshot = ?1 WHERE last_snapshot = ?2
+        AND (ino, bno, data_id) IN (
+            SELECT b.ino, b.bno, b.data_id FROM block b JOIN inode i ON i.ino = b.ino WHERE i.nlink > 0
+        )"
+        .to_owned(),
+        "UPDATE snapshot_symlink SET last_snapshot = ?1 WHERE last_snapshot = ?2
+        AND (ino, target) IN (SELECT ino, target FROM symlink)"
+            .to_owned(),
+        "UPDATE snapshot_xattr SET last_snapshot = ?1 WHERE last_snapshot = ?2
+        AND (ino, name, value) IN (
+            SELECT x.ino, x.name, x.value FROM xattr x JOIN inode i ON i.ino = x.ino WHERE i.nlink > 0
+        )"
+        .to_owned(),
+    ];
+    for sql in shared {
+        tx.prepare_cached(&sql)?.execute(params![id, previous])?;
+    }
+
+    // The rows that changed since the previous snapshot are copied. Entries and attributes are listed in insertion
+    // order, the rowid of the copies keeps it.
+    let copied = [
+        format!(
+            "INSERT INTO snapshot_inode (first_snapshot, last_snapshot, {INODE_COLUMNS})
+            SELECT ?1, ?1, {INODE_COLUMNS} FROM inode i WHERE nlink > 0
+            AND NOT EXISTS (SELECT 1 FROM snapshot_inode s WHERE s.ino = i.ino AND s.last_snapshot = ?1)"
+        ),
+        "INSERT INTO snapshot_dir_entry (first_snapshot, last_snapshot, parent_ino, name, ino)
+        SELECT ?1, ?1, parent_ino, name, ino FROM dir_entry e
+        WHERE NOT EXISTS (
+            SELECT 1 FROM snapshot_dir_entry s
+            WHERE s.parent_ino = e.parent_ino AND s.name = e.name AND s.last_snapshot = ?1
+        )
+        ORDER BY rowid"
+            .to_owned(),
+        "INSERT INTO snapshot_block (first_snapshot, last_snapshot, ino, bno, data_id)
+        SELECT ?1, ?1, b.ino, b.bno, b.data_id FROM block b JOIN inode i ON i.ino = b.ino WHERE i.nlink > 0
+        AND NOT EXISTS (
+            SELECT 1 FROM snapshot_block s WHERE s.ino = b.ino AND s.bno = b.bno AND s.last_snapshot = ?1
+        )"
+        .to_owned(),
+        "INSERT INTO snapshot_symlink (first_snapshot, last_snapshot, ino, target)
+        SELECT ?1, ?1, ino, target FROM symlink l
+        WHERE NOT EXISTS (SELECT 1 FROM snapshot_symlink s WHERE s.ino = l.ino AND s.last_snapshot = ?1)"
+            .to_owned(),
+        "INSERT INTO snapshot_xattr (first_snapshot, last_snapshot, ino, name, value)
+        SELECT ?1, ?1, x.ino, x.name, x.value FROM xattr x JOIN inode i ON i.ino = x.ino WHERE i.nlink > 0
+        AND NOT EXISTS (
+            SELECT 1 FROM snapshot_xattr s WHERE s.ino = x.ino AND s.name = x.name AND s.last_snapshot = ?1
+        )
+        ORDER BY x.rowid"
+            .to_owned(),
+    ];
+    for sql in copied {
+        tx.prepare_cached(&sql)?.execute(params![id])?;
+    }
+
+    Ok(Snapshot {
+        id,
+        name: name.to_owned(),
+        created,
+    })
+}
+
+pub fn lookup(tx: &mut rusqlite::Transaction, name: &str) -> Result<Snapshot> {
+    let mut stmt = tx.prepare_cached("SELECT id, name, created_secs, created_nanos FROM snapshot WHERE name = ?")?;
+    let snapshot = stmt.query_row(params![name], |row| {
+        Ok(Snapshot {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            created: TimeSpec::new(row.get(2)?, row.get(3)?),
+        })
+    })?;
+    Ok(snapshot)
+}
+
+pub fn list(tx: &mut rusqlite::Transaction) -> Result<Vec<Snapshot>> {
+    let mut stmt = tx.prepare_cached("SELECT id, name, created_secs, created_nanos FROM snapshot ORDER BY id")?;
+    let snapshots = stmt
+        .query_map(params![], |row| {
+            Ok(Snapshot {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                created: TimeSpec::new(row.get(2)?, row.get(3)?),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(snapshots)
+}
+
+/// Delete the snapshot, the data of its blocks is deleted unless the files or other snapshots still reference it.
+pub fn remove(tx: &mut rusqlite::Transaction, name: &str) -> Result<()> {
+    let id = lookup(tx, name)?.id;
+    let mut stmt = tx.prepare_cached("DELETE FROM snapshot WHERE id = ?")?;
+    stmt.execute(params![id])?;
+
+    // The rows only part of the snapshot are deleted, the rows shared with other snapshots now end at the remaining
+    // snapshots next to it.
+    for table in TABLES {
+        tx.prepare_cached(&format!(
+            "DELETE FROM {table} WHERE first_snapshot = ?1 AND last_snapshot = ?1"
+        ))?
+        .execute(params![id])?;
+        tx.prepare_cached(&format!(
+            "UPDATE {table} SET first_snapshot = (SELECT min(id) FROM snapshot WHERE id > ?1) WHERE first_snapshot = ?1"
+        ))?
+        .execute(params![id])?;
+        tx.prepare_cached(&format!(
+            "UPDATE {table} SET last_snapshot = (SELECT max(id) FROM snapshot WHERE id < ?1) WHERE last_snapshot = ?1"
+        ))?
+        .execute(params![id])?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// Version of the file at a path, the change time moves with any change of its content or attributes.
+#[derive(PartialEq)]
+struct PathVersion {
+    ino: u64,
+    size: u64,
+    ctime: TimeSpec,
+}
+
+/// List the paths that differ between two snapshots, `None` being the current state of the filesystem. The paths
+/// are sorted.
+pub fn diff(tx: &mut rusqlite::Transaction, from: Option<i64>, to: Option<i64>) -> Result<Vec<(Change, Vec<u8>)>> {
+    let mut old = paths(tx, from)?;
+    let new = paths(tx, to)?;
+
+    let mut changes = BTreeMap::new();
+    for (path, version) in new {
+        match old.remove(&path) {
+            None => {
+                changes.insert(path, Change::Added);
+            }
+            Some(old_version) if old_version != version => {
+                changes.insert(path, Change::Modified);
+            }
+            Some(_) => {}
+        }
+    }
+    changes.extend(old.into_keys().map(|path| (path, Change::Removed)));
+    Ok(changes.into_iter().map(|(path, change)| (change, path)).collect())
+}
+
+fn paths(tx: &mut rusqlite::Transaction, snapshot: Option<i64>) -> Result<BTreeMap<Vec<u8>, PathVersion>> {
+    let (dir_entry, inode) = match snapshot {
+        Some(_) => (
+            "(SELECT parent_ino, name, ino FROM snapshot_dir_entry WHERE ?1 BETWEEN first_snapshot AND last_snapshot)",
+            "(SELECT * FROM snapshot_inode WHERE ?1 BETWEEN first_snapshot AND last_snapshot)",
+        ),
+        None => ("dir_entry", "inode"),
+    };
+    let mut stmt = tx.prepare(&format!(
+        "WITH RECURSIVE tree (ino, path) AS (
+            SELECT ino, CAST('/' || name AS BLOB) FROM {dir_entry} WHERE parent_ino = 1
+            UNION ALL
+            SELECT e.ino, CAST(tree.path || '/' || e.name AS BLOB) FROM {dir_entry} e JOIN tree ON e.parent_ino = tree.ino
+        )
+        SELECT tree.path, i.ino, i.size, i.ctime_secs, i.ctime_nanos FROM tree JOIN {inode} i ON i.ino = tree.ino"
+    ))?;
+    let mut rows = match snapshot {
+        Some(id) => stmt.query(params![id])?,
+        None => stmt.query(params![])?,
+    };
+
+    let mut paths = BTreeMap::new();
+    while let Some(row) = rows.next()? {
+        let version = PathVersion {
+            ino: row.get(1)?,
+            size: row.get(2)?,
+            ctime: TimeSpec::new(row.get(3)?, row.get(4)?),
+        };
+        paths.insert(row.get(0)?, version);
+    }
+    Ok(paths)
+}
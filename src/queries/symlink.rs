@@ -0,0 +1,16 @@
+use std::ffi::OsStr;
+
+use crate::errors::Result;
+use rusqlite::params;
+
+pub fn create(tx: &mut rusqlite::Transaction, ino: u64, target: &OsStr) -> Result<()> {
+    let mut stmt = tx.prepare_cached("INSERT INTO symlink (ino, target) VALUES (?, ?)")?;
+    stmt.execute(params![ino, target.as_encoded_bytes()])?;
+    Ok(())
+}
+
+pub fn lookup(tx: &mut rusqlite::Transaction, ino: u64) -> Result<Vec<u8>> {
+    let mut stmt = tx.prepare_cached("SELECT target FROM symlink WHERE ino = ?")?;
+    let target = stmt.query_row(params![ino], |row| row.get(0))?;
+    Ok(target)
+}
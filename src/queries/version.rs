@@ -0,0 +1,272 @@
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStringExt,
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    errors::{Error, Result},
+    queries::{self, block::BLOCK_SIZE, dir_entry, setting},
+    time::TimeSpec,
+};
+use rusqlite::params;
+
+/// Previous content of a regular file.
+#[derive(Debug, PartialEq)]
+pub struct Version {
+    pub id: i64,
+    /// When the content was replaced.
+    pub created: TimeSpec,
+    pub size: u64,
+    pub mtime: TimeSpec,
+    /// The file was deleted or replaced, the version is listed under the path it had.
+    pub deleted: bool,
+}
+
+/// How long the versions of the files are kept, no version is recorded if neither limit is set.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retention {
+    /// Number of versions kept for each file.
+    pub keep: u64,
+    /// Age in seconds after which versions are deleted.
+    pub max_age: u64,
+}
+
+impl Retention {
+    pub fn load(tx: &mut rusqlite::Transaction) -> Result<Self> {
+        Ok(Retention {
+            keep: setting::get(tx, setting::VERSIONS_KEEP)?,
+            max_age: setting::get(tx, setting::VERSIONS_MAX_AGE)?,
+        })
+    }
+
+    fn enabled(self) -> bool {
+        self.keep > 0 || self.max_age > 0
+    }
+}
+
+/// Keep the current content of the file as a version before it is overwritten or truncated, then delete the versions
+/// of the file outside of the retention. Returns the id of the version, if one was recorded.
+pub fn record(tx: &mut rusqlite::Transaction, ino: u64, now: TimeSpec) -> Result<Option<i64>> {
+    let retention = Retention::load(tx)?;
+    if !retention.enabled() {
+        return Ok(None);
+    }
+    let id = insert(tx, ino, now)?;
+    prune(tx, Some(ino), now, retention)?;
+    Ok(id)
+}
+
+/// Keep the content of a regular file before its last link, `name` in the directory `parent`, is removed. The
+/// versions of the file then outlive the inode and are listed under the path of that link.
+pub fn record_removal(
+    tx: &mut rusqlite::Transaction,
+    ino: u64,
+    parent: u64,
+    name: &OsStr,
+    now: TimeSpec,
+) -> Result<()> {
+    let retention = Retention::load(tx)?;
+    if retention.enabled() {
+        insert(tx, ino, now)?;
+    }
+    if !tx
+        .prepare_cached("SELECT 1 FROM file_version WHERE ino = ?")?
+        .exists(params![ino])?
+    {
+        return Ok(());
+    }
+    // The versions are now counted with the versions of the files deleted before at the same path.
+    let path = path_key(&dir_entry::dir_path(tx, parent)?.join(name));
+    tx.prepare_cached("UPDATE file_version SET path = ? WHERE ino = ?")?
+        .execute(params![path, ino])?;
+    prune_matching(tx, None, Some(&path), now, retention)?;
+    Ok(())
+}
+
+fn insert(tx: &mut rusqlite::Transaction, ino: u64, now: TimeSpec) -> Result<Option<i64>> {
+    let attr = queries::inode::lookup(tx, ino)?;
+    // There is nothing to keep of an empty file. The content of a deleted file was kept with its last link.
+    if attr.kind != fuser::FileType::RegularFile || attr.size == 0 || attr.nlink == 0 {
+        return Ok(None);
+    }
+    let mtime = TimeSpec::from(attr.mtime);
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO file_version (ino, created_secs, created_nanos, size, mtime_secs, mtime_nanos, perm, uid, gid)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )?;
+    let id = stmt.insert(params![
+        ino,
+        now.secs,
+        now.nanos,
+        attr.size,
+        mtime.secs,
+        mtime.nanos,
+        attr.perm,
+        attr.uid,
+        attr.gid
+    ])?;
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO file_version_block (version_id, bno, data_id) SELECT ?, bno, data_id FROM block WHERE ino = ?",
+    )?;
+    stmt.execute(params![id, ino])?;
+    Ok(Some(id))
+}
+
+/// Delete the versions outside of the retention, of the inode or of all the files. The versions of the deleted files
+/// are counted per path.
+pub fn prune(tx: &mut rusqlite::Transaction, ino: Option<u64>, now: TimeSpec, retention: Retention) -> Result<usize> {
+    prune_matching(tx, ino, None, now, retention)
+}
+
+/// Delete the versions outside of the retention of the inode, of the deleted files at the path, or of all the files
+/// if neither is given.
+fn prune_matching(
+    tx: &mut rusqlite::Transaction,
+    ino: Option<u64>,
+    path: Option<&[u8]>,
+    now: TimeSpec,
+    retention: Retention,
+) -> Result<usize> {
+    let mut removed = 0;
+    if retention.max_age > 0 {
+        let cutoff = now.secs.saturating_sub_unsigned(retention.max_age);
+        let mut stmt = tx.prepare_cached(
+            "DELETE FROM file_version
+            WHERE created_secs < ?1 AND ((?2 IS NULL AND ?3 IS NULL) OR ino = ?2 OR path = ?3)",
+        )?;
+        removed += stmt.execute(params![cutoff, ino, path])?;
+    }
+    if retention.keep > 0 {
+        let mut stmt = tx.prepare_cached(
+            "DELETE FROM file_version WHERE id IN (
+                SELECT id FROM (
+                    SELECT id, row_number() OVER (PARTITION BY coalesce(path, ino) ORDER BY id DESC) AS n
+                    FROM file_version WHERE (?1 IS NULL AND ?2 IS NULL) OR ino = ?1 OR path = ?2
+                )
+                WHERE n > ?3
+            )",
+        )?;
+        removed += stmt.execute(params![ino, path, retention.keep])?;
+    }
+    Ok(removed)
+}
+
+/// List the versions of the file at the path and of the deleted files that had the path, from the oldest.
+pub fn list(tx: &mut rusqlite::Transaction, path: &Path) -> Result<Vec<Version>> {
+    let ino = lookup_file(tx, path)?;
+    let mut stmt = tx.prepare_cached(
+        "SELECT id, created_secs, created_nanos, size, mtime_secs, mtime_nanos, path IS NOT NULL FROM file_version
+        WHERE ino = ?1 OR path = ?2 ORDER BY id",
+    )?;
+    let versions = stmt
+        .query_map(params![ino, path_key(path)], |row| {
+            Ok(Version {
+                id: row.get(0)?,
+                created: TimeSpec::new(row.get(1)?, row.get(2)?),
+                size: row.get(3)?,
+                mtime: TimeSpec::new(row.get(4)?, row.get(5)?),
+                deleted: row.get(6)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(versions)
+}
+
+/// Replace the content of the file at the path with one of the versions listed for the path. A deleted file is
+/// created again, with the mode and owner it had. The replaced content is kept as a new version.
+pub fn restore(tx: &mut rusqlite::Transaction, path: &Path, id: i64, now: TimeSpec) -> Result<()> {
+    let ino = lookup_file(tx, path)?;
+    if let Some(ino) = ino {
+        if queries::inode::lookup(tx, ino)?.kind != fuser::FileType::RegularFile {
+            return Err(Error::InvalidArgument);
+        }
+    }
+    let (size, perm, uid, gid): (u64, u16, u32, u32) = tx
+        .prepare_cached("SELECT size, perm, uid, gid FROM file_version WHERE id = ?1 AND (ino = ?2 OR path = ?3)")?
+        .query_row(params![id, ino, path_key(path)], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?;
+    let ino = match ino {
+        Some(ino) => ino,
+        None => create_file(tx, path, perm, uid, gid, now)?,
+    };
+
+    // The restored version must still exist when its blocks are copied, the retention is applied last.
+    let retention = Retention::load(tx)?;
+    if retention.enabled() {
+        insert(tx, ino, now)?;
+    }
+    queries::block::remove_blocks_from(tx, ino, 0)?;
+    tx.prepare_cached(
+        "INSERT INTO block (ino, bno, data_id) SELECT ?, bno, data_id FROM file_version_block WHERE version_id = ?",
+    )?
+    .execute(params![ino, id])?;
+    queries::inode::set_attr(tx, ino, "size", size)?;
+    queries::inode::update_blocks(tx, ino)?;
+    queries::inode::set_time(tx, ino, "mtime", now)?;
+    queries::inode::set_time(tx, ino, "ctime", now)?;
+    prune(tx, Some(ino), now, retention)?;
+    Ok(())
+}
+
+/// Absolute path without redundant separators, under which the versions of deleted files are stored.
+fn path_key(path: &Path) -> Vec<u8> {
+    let path = Path::new("/").join(
+        path.components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect::<PathBuf>(),
+    );
+    path.into_os_string().into_vec()
+}
+
+/// Inode of the file at the path, if there is one.
+fn lookup_file(tx: &mut rusqlite::Transaction, path: &Path) -> Result<Option<u64>> {
+    match dir_entry::lookup_path(tx, path) {
+        Ok(ino) => Ok(Some(ino)),
+        Err(Error::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Create an empty regular file at the path, in an existing directory.
+fn create_file(
+    tx: &mut rusqlite::Transaction,
+    path: &Path,
+    perm: u16,
+    uid: u32,
+    gid: u32,
+    now: TimeSpec,
+) -> Result<u64> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(Error::InvalidArgument);
+    };
+    let parent = dir_entry::lookup_path(tx, parent)?;
+    if queries::inode::lookup(tx, parent)?.kind != fuser::FileType::Directory {
+        return Err(Error::NotADirectory);
+    }
+    let time = SystemTime::from(now);
+    let mut attr = fuser::FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: time,
+        mtime: time,
+        ctime: time,
+        crtime: time,
+        kind: fuser::FileType::RegularFile,
+        perm,
+        nlink: 1,
+        uid,
+        gid,
+        rdev: 0,
+        blksize: BLOCK_SIZE as u32,
+        flags: 0,
+    };
+    queries::inode::create(tx, &mut attr)?;
+    dir_entry::create(tx, parent, name, attr.ino)?;
+    queries::inode::set_time(tx, parent, "mtime", now)?;
+    queries::inode::set_time(tx, parent, "ctime", now)?;
+    Ok(attr.ino)
+}
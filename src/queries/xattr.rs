@@ -0,0 +1,41 @@
+use std::ffi::OsStr;
+
+use crate::errors::{Error, Result};
+use rusqlite::params;
+
+pub fn get(tx: &mut rusqlite::Transaction, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+    let mut stmt = tx.prepare_cached("SELECT value FROM xattr WHERE ino = ? AND name = ?")?;
+    let value = stmt.query_row(params![ino, name.as_encoded_bytes()], |row| row.get(0))?;
+    Ok(value)
+}
+
+pub fn exists(tx: &mut rusqlite::Transaction, ino: u64, name: &OsStr) -> Result<bool> {
+    let mut stmt = tx.prepare_cached("SELECT EXISTS(SELECT 1 FROM xattr WHERE ino = ? AND name = ?)")?;
+    let exists = stmt.query_row(params![ino, name.as_encoded_bytes()], |row| row.get(0))?;
+    Ok(exists)
+}
+
+pub fn set(tx: &mut rusqlite::Transaction, ino: u64, name: &OsStr, value: &[u8]) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO xattr (ino, name, value) VALUES (?, ?, ?) ON CONFLICT (ino, name) DO UPDATE SET value = excluded.value",
+    )?;
+    stmt.execute(params![ino, name.as_encoded_bytes(), value])?;
+    Ok(())
+}
+
+pub fn list(tx: &mut rusqlite::Transaction, ino: u64) -> Result<Vec<Vec<u8>>> {
+    let mut stmt = tx.prepare_cached("SELECT name FROM xattr WHERE ino = ? ORDER BY rowid")?;
+    let names = stmt
+        .query_map(params![ino], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<Vec<u8>>>>()?;
+    Ok(names)
+}
+
+pub fn remove(tx: &mut rusqlite::Transaction, ino: u64, name: &OsStr) -> Result<()> {
+    let mut stmt = tx.prepare_cached("DELETE FROM xattr WHERE ino = ? AND name = ?")?;
+    let affected = stmt.execute(params![ino, name.as_encoded_bytes()])?;
+    if affected == 0 {
+        return Err(Error::NotFound);
+    }
+    Ok(())
+}
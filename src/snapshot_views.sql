@@ -0,0 +1,19 @@
+-- Temporary views shadowing the tables of the filesystem with the rows of a snapshot. Views have no rowid, the rowid
+-- of the snapshot rows keeps the order of the entries and attributes.
+CREATE TEMP VIEW inode AS
+SELECT
+    ino, size, blocks, atime_secs, atime_nanos, mtime_secs, mtime_nanos, ctime_secs, ctime_nanos, crtime_secs,
+    crtime_nanos, kind, perm, nlink, uid, gid, rdev, blksize, flags
+FROM main.snapshot_inode WHERE {snapshot_id} BETWEEN first_snapshot AND last_snapshot;
+
+CREATE TEMP VIEW dir_entry AS
+SELECT rowid, parent_ino, name, ino FROM main.snapshot_dir_entry WHERE {snapshot_id} BETWEEN first_snapshot AND last_snapshot;
+
+CREATE TEMP VIEW block AS
+SELECT rowid, ino, bno, data_id FROM main.snapshot_block WHERE {snapshot_id} BETWEEN first_snapshot AND last_snapshot;
+
+CREATE TEMP VIEW symlink AS
+SELECT ino, target FROM main.snapshot_symlink WHERE {snapshot_id} BETWEEN first_snapshot AND last_snapshot;
+
+CREATE TEMP VIEW xattr AS
+SELECT rowid, ino, name, value FROM main.snapshot_xattr WHERE {snapshot_id} BETWEEN first_snapshot AND last_snapshot;
@@ -1,34 +1,61 @@
-use std::time::{self, Duration, SystemTime};
+use std::{
+    fmt,
+    time::{self, Duration, SystemTime},
+};
 
 use fuser::TimeOrNow;
 
-#[derive(Clone, Copy, Debug)]
+/// Time relative to the UNIX epoch. The seconds are signed so that times before the epoch can be represented, the
+/// nanoseconds are always positive: 1969-12-31T23:59:59.5 is -1 seconds and 500000000 nanoseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TimeSpec {
-    pub secs: u64,
+    pub secs: i64,
     pub nanos: u32,
 }
 
 impl TimeSpec {
-    pub fn new(secs: u64, nanos: u32) -> Self {
+    pub fn new(secs: i64, nanos: u32) -> Self {
         Self { secs, nanos }
     }
+
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
 }
 
 impl From<SystemTime> for TimeSpec {
     fn from(value: SystemTime) -> Self {
-        let d = value
-            .duration_since(time::UNIX_EPOCH)
-            .expect("Time is before UNIX epoch");
-        TimeSpec {
-            secs: d.as_secs(),
-            nanos: d.subsec_nanos(),
+        match value.duration_since(time::UNIX_EPOCH) {
+            Ok(d) => TimeSpec {
+                secs: i64::try_from(d.as_secs()).unwrap_or(i64::MAX),
+                nanos: d.subsec_nanos(),
+            },
+            Err(e) => {
+                let d = e.duration();
+                let secs = 0i64.checked_sub_unsigned(d.as_secs()).unwrap_or(i64::MIN);
+                match d.subsec_nanos() {
+                    0 => TimeSpec { secs, nanos: 0 },
+                    nanos => TimeSpec {
+                        secs: secs.saturating_sub(1),
+                        nanos: 1_000_000_000 - nanos,
+                    },
+                }
+            }
         }
     }
 }
 
 impl From<TimeSpec> for SystemTime {
     fn from(val: TimeSpec) -> Self {
-        time::UNIX_EPOCH + Duration::new(val.secs, val.nanos)
+        let secs = Duration::from_secs(val.secs.unsigned_abs());
+        let time = if val.secs >= 0 {
+            time::UNIX_EPOCH.checked_add(secs)
+        } else {
+            time::UNIX_EPOCH.checked_sub(secs)
+        };
+        // A time the platform cannot represent must not bring down the filesystem, it is reported as the epoch.
+        time.and_then(|t| t.checked_add(Duration::from_nanos(val.nanos.into())))
+            .unwrap_or(time::UNIX_EPOCH)
     }
 }
 
@@ -36,7 +63,7 @@ impl From<TimeOrNow> for TimeSpec {
     fn from(value: TimeOrNow) -> Self {
         match value {
             TimeOrNow::SpecificTime(t) => t.into(),
-            TimeOrNow::Now => SystemTime::now().into(),
+            TimeOrNow::Now => TimeSpec::now(),
         }
     }
 }
@@ -46,3 +73,88 @@ impl From<TimeSpec> for TimeOrNow {
         TimeOrNow::SpecificTime(value.into())
     }
 }
+
+/// Formats the time as a UTC date and time, e.g. `2024-05-01 13:45:00 UTC`.
+impl fmt::Display for TimeSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = self.secs.div_euclid(86_400);
+        let secs = self.secs.rem_euclid(86_400);
+        // Civil date of the day number, see https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = i128::from(days) + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i128::from(month <= 2);
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::TimeSpec;
+
+    #[test]
+    fn test_timespec_conversions() {
+        let cases = [
+            (UNIX_EPOCH, TimeSpec::new(0, 0)),
+            (
+                UNIX_EPOCH + Duration::new(1_700_000_000, 42),
+                TimeSpec::new(1_700_000_000, 42),
+            ),
+            (UNIX_EPOCH - Duration::from_secs(1), TimeSpec::new(-1, 0)),
+            (UNIX_EPOCH - Duration::from_millis(500), TimeSpec::new(-1, 500_000_000)),
+            (
+                UNIX_EPOCH - Duration::new(315_619_200, 1),
+                TimeSpec::new(-315_619_201, 999_999_999),
+            ),
+            // Year 2500.
+            (
+                UNIX_EPOCH + Duration::from_secs(16_725_225_600),
+                TimeSpec::new(16_725_225_600, 0),
+            ),
+        ];
+        for (time, ts) in cases {
+            assert_eq!(TimeSpec::from(time), ts);
+            assert_eq!(SystemTime::from(ts), time);
+        }
+    }
+
+    #[test]
+    fn test_timespec_extremes() {
+        // The conversions do not panic on the limits of the stored values.
+        for ts in [TimeSpec::new(i64::MIN, 0), TimeSpec::new(i64::MAX, 999_999_999)] {
+            assert_eq!(TimeSpec::from(SystemTime::from(ts)), ts);
+        }
+        // Out of range nanoseconds carry into the seconds.
+        assert_eq!(
+            TimeSpec::from(SystemTime::from(TimeSpec::new(0, u32::MAX))),
+            TimeSpec::new(4, 294_967_295)
+        );
+    }
+
+    #[test]
+    fn test_timespec_display() {
+        assert_eq!(TimeSpec::new(0, 0).to_string(), "1970-01-01 00:00:00 UTC");
+        assert_eq!(TimeSpec::new(951_782_400, 0).to_string(), "2000-02-29 00:00:00 UTC");
+        assert_eq!(TimeSpec::new(1_700_000_000, 5).to_string(), "2023-11-14 22:13:20 UTC");
+        assert_eq!(TimeSpec::new(-1, 500_000_000).to_string(), "1969-12-31 23:59:59 UTC");
+        // Must not overflow.
+        TimeSpec::new(i64::MIN, 0).to_string();
+        TimeSpec::new(i64::MAX, 0).to_string();
+    }
+}